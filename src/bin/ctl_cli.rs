@@ -0,0 +1,62 @@
+use std::process::ExitCode;
+use std::time::Duration;
+
+use clap::Parser;
+use wfb_rs::common::control::{self, ControlCommand};
+
+/// Control client of wfb_rs, changes the settings of a running transmitter or queries its statistics
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    after_help = "Commands:\n  set-mcs <index>\n  set-bandwidth <10|20|40|80|160>\n  set-stbc <0-3>\n  \
+        set-ldpc <on|off>\n  set-short-gi <on|off>\n  set-fec <block size> <redundant pkgs>\n  \
+        set-txpower <0-64>\n  get-stats              (transmitter and receiver)"
+)]
+struct Args {
+    /// Control port of wfb_rs_tx or wfb_rs_rx (host:port)
+    #[arg(short = 'a', long, default_value = "127.0.0.1:9000")]
+    address: String,
+
+    /// Time to wait for the reply in ms
+    #[arg(short = 't', long, default_value = "1000", value_parser = parse_duration)]
+    timeout: Duration,
+
+    /// Command and its arguments, e.g. set-fec 8000 10
+    #[arg(required = true, num_args = 1..)]
+    command: Vec<String>,
+}
+
+fn parse_duration(arg: &str) -> Result<std::time::Duration, std::num::ParseIntError> {
+    let milliseconds = arg.parse()?;
+    Ok(std::time::Duration::from_millis(milliseconds))
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    // checked here as well, so typos don't need a running transmitter to show up
+    let command = match ControlCommand::parse(&args.command.join(" ")) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("Invalid command: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match control::send_command(&args.address, command, args.timeout) {
+        Ok(answer) => {
+            if answer.is_empty() {
+                println!("ok");
+            } else {
+                println!("{}", answer);
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{}: {}", command, e);
+            ExitCode::FAILURE
+        }
+    }
+}
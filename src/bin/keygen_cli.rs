@@ -0,0 +1,39 @@
+use clap::Parser;
+use wfb_rs::common::keypair;
+
+/// Key generator of wfb_rs, writes a matching drone and ground station keypair
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Output file of the drone (transmitter) key
+    #[arg(short = 'd', long, default_value = "drone.key")]
+    drone_key: String,
+
+    /// Output file of the ground station (receiver) key
+    #[arg(short = 'g', long, default_value = "gs.key")]
+    gs_key: String,
+
+    /// Key ID stored in the key files, to tell keys apart when rotating
+    #[arg(short = 'i', long, default_value_t = 0)]
+    key_id: u32,
+
+    /// Write the plain 64 byte wfb-ng layout instead of the versioned wfb_rs format
+    #[arg(long, default_value_t = false)]
+    wfb_ng: bool,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let (drone, ground_station) = keypair::generate_keypairs(args.key_id).unwrap();
+
+    drone.save(&args.drone_key, args.wfb_ng).unwrap();
+    ground_station.save(&args.gs_key, args.wfb_ng).unwrap();
+
+    println!(
+        "Drone key {} and ground station key {} generated (key id {})",
+        args.drone_key,
+        args.gs_key,
+        args.key_id,
+    );
+}
@@ -1,7 +1,9 @@
 use clap::Parser;
+use std::thread;
 use std::time::Duration;
+use wfb_rs::common::{bandwidth::Bandwidth, channel::{self, Ht40}, fec::FecMode, radio::FcsMode};
 #[cfg(feature = "receiver")]
-use wfb_rs::{common::utils, Receiver};
+use wfb_rs::{common::{hopping::Hopping, utils::WifiSetup}, Receiver, RxConfig, RxDevice, RxOutput, RxRunConfig};
 
 /// Receiving side of wfb_rs
 #[derive(Parser, Debug)]
@@ -23,18 +25,91 @@ struct Args {
     #[arg(short = 'p', long, default_value_t = 0)]
     radio_port: u16,
 
+    /// Another stream of the same cards: radio_port:host:port[:raptorq|:rs], forwarded to host:port,
+    /// without a fec mode it takes the one of the main stream. Can be repeated.
+    #[arg(long, value_parser = parse_stream)]
+    stream: Vec<Stream>,
+
     /// Link ID
     #[arg(short = 'i', long, default_value_t = 7669206)]
     link_id: u32,
 
+    /// Send fec statistics to this address (host:port), for the adaptive redundancy of the transmitter
+    #[arg(short = 'F', long)]
+    feedback_address: Option<String>,
+
+    /// Answer control queries (get-stats) on this localhost udp port, e.g. 9001
+    #[arg(short = 'C', long)]
+    control_port: Option<u16>,
+
     /// Log Interval
     #[arg(short='l', long, default_value = "1000", value_parser = parse_duration)]
     log_interval: Duration,
 
-    /// Wifi Card setup (channel 149, monitor mode)
-    #[arg(short='s', long, default_value_t = false)]
+    /// Send json statistics every log interval to this udp address (host:port) or unix socket (unix:/path)
+    #[arg(long)]
+    stats_address: Option<String>,
+
+    /// Serve Prometheus metrics at http://<address>/metrics (e.g. 0.0.0.0:9101)
+    #[arg(long)]
+    metrics_address: Option<String>,
+
+    /// FEC mode, rs is Reed-Solomon compatible with wfb-ng and requires a key file
+    #[arg(long, value_enum, default_value_t = FecMode::Raptorq)]
+    fec_mode: FecMode,
+
+    /// Forward the intact packets of fec blocks that can't be decoded
+    #[arg(long, default_value_t = false)]
+    forward_partial: bool,
+
+    /// Frames that end with the 4 byte FCS: always, or only those the radiotap header flags
+    #[arg(long, value_enum, default_value_t = FcsMode::Always)]
+    fcs: FcsMode,
+
+    /// Interleaving depth of the transmitter
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=16))]
+    interleave_depth: u8,
+
+    /// Ground station key file (see wfb_rs_keygen), enables decryption of the radio payload
+    #[arg(short = 'K', long)]
+    key_file: Option<String>,
+
+    /// Bandwidth of the transmitter, the wifi setup tunes to a channel this wide
+    #[arg(short = 'b', long, default_value = "20", value_parser = parse_bandwidth)]
+    bandwidth: Bandwidth,
+
+    /// Wifi Card setup (monitor mode on --channel)
+    #[arg(short = 's', long, default_value_t = false)]
     wifi_setup: bool,
 
+    /// Channel of the wifi setup, the channel width follows the bandwidth
+    #[arg(long, default_value_t = 149, value_parser = parse_channel)]
+    channel: u32,
+
+    /// Frequency of the wifi setup in MHz, instead of --channel
+    #[arg(long, conflicts_with = "channel")]
+    frequency: Option<u32>,
+
+    /// Side of the second half of a 40 MHz channel (default: the one of the channel plan)
+    #[arg(long, value_enum)]
+    ht40: Option<Ht40>,
+
+    /// Region of the wifi setup, an ISO 3166 country code like DE or 00 for the world
+    #[arg(long, value_parser = parse_region)]
+    region: Option<String>,
+
+    /// Kernel module the wifi setup loads first, empty to skip
+    #[arg(long, default_value = "8812eu")]
+    driver: String,
+
+    /// Channels to hop between, e.g. 149,157,165, the same list on both sides (needs --wifi-setup and --key-file)
+    #[arg(long, value_delimiter = ',', value_parser = parse_channel, requires = "wifi_setup")]
+    hop_channels: Vec<u32>,
+
+    /// Dwell time of the frequency hopping on each channel in ms
+    #[arg(long, default_value = "200", value_parser = parse_duration)]
+    hop_dwell: Duration,
+
     /// Wifi Device
     #[arg(required = true, num_args = 1..)]
     wifi_devices: Vec<String>
@@ -45,30 +120,94 @@ fn parse_duration(arg: &str) -> Result<std::time::Duration, std::num::ParseIntEr
     Ok(std::time::Duration::from_millis(milliseconds))
 }
 
+fn parse_bandwidth(arg: &str) -> Result<Bandwidth, String> {
+    arg.parse()
+}
+
+fn parse_channel(arg: &str) -> Result<u32, String> {
+    let channel = arg.parse().map_err(|_| format!("Invalid channel {}", arg))?;
+    channel::channel_frequency(channel).ok_or(format!("Unknown channel {}", channel))?;
+    Ok(channel)
+}
+
+fn parse_region(arg: &str) -> Result<String, String> {
+    if arg.len() != 2 || !arg.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(format!("Invalid region {}, expected a country code like DE", arg));
+    }
+    Ok(arg.to_ascii_uppercase())
+}
+
+#[derive(Debug, Clone)]
+struct Stream {
+    radio_port: u8,
+    client_address: String,
+    fec_mode: Option<FecMode>,
+}
+
+fn parse_stream(arg: &str) -> Result<Stream, String> {
+    let usage = "Expected radio_port:host:port[:raptorq|:rs]";
+    let parts: Vec<&str> = arg.split(':').collect();
+    let (fec_mode, address) = match parts.as_slice() {
+        [.., "raptorq"] => (Some(FecMode::Raptorq), &parts[1..parts.len() - 1]),
+        [.., "rs"] => (Some(FecMode::Rs), &parts[1..parts.len() - 1]),
+        _ => (None, &parts[1..]),
+    };
+    let [host, port] = address else {
+        return Err(usage.to_string());
+    };
+    let radio_port = parts[0].parse().map_err(|_| format!("Invalid radio port {}", parts[0]))?;
+    Ok(Stream { radio_port, client_address: format!("{}:{}", host, port), fec_mode })
+}
+
 #[cfg(feature = "receiver")]
 fn main() {
     let args = Args::parse();
 
     println!("{:?}", args);
 
-    if args.wifi_setup {
-        for wifi in &args.wifi_devices {
-            utils::set_monitor_mode(wifi.as_str()).unwrap();
-        }
+    let wifi_setup = args.wifi_setup.then(|| WifiSetup {
+        frequency: args.frequency.or(channel::channel_frequency(args.channel)).unwrap(),
+        ht40: args.ht40,
+        region: args.region.clone(),
+        driver: (!args.driver.is_empty()).then(|| args.driver.clone()),
+        hopping: (!args.hop_channels.is_empty()).then(|| Hopping {
+            frequencies: args.hop_channels.iter().filter_map(|&channel| channel::channel_frequency(channel)).collect(),
+            dwell: args.hop_dwell,
+        }),
+    });
+
+    let rx = Receiver::new(args.wifi_devices.into_iter().map(RxDevice::Wifi).collect(), RxConfig {
+        magic: args.magic,
+        radio_port: args.radio_port as u8,
+        link_id: args.link_id,
+        wifi_setup,
+        bandwidth: args.bandwidth,
+        fec_mode: args.fec_mode,
+        forward_partial: args.forward_partial,
+        fcs: args.fcs,
+        interleave_depth: args.interleave_depth as usize,
+        key_file: args.key_file,
+    }).unwrap();
+
+    for stream in args.stream {
+        let stream_rx = rx.add_stream(stream.radio_port, stream.fec_mode.unwrap_or(args.fec_mode)).unwrap();
+        let stats_address = args.stats_address.clone();
+        thread::spawn(move || {
+            stream_rx.run(RxOutput::Udp(stream.client_address), RxRunConfig {
+                stats_address,
+                log_interval: args.log_interval,
+                ..Default::default()
+            }).unwrap();
+        });
     }
 
-    let rx = Receiver::new(
-        args.magic,
-        args.radio_port,
-        args.link_id,
-        args.wifi_devices,
-    ).unwrap();
-
-    rx.run(
-        args.client_address,
-        args.client_port,
-        args.log_interval
-    ).unwrap();
+    rx.run(RxOutput::Udp(format!("{}:{}", args.client_address, args.client_port)), RxRunConfig {
+        feedback_address: args.feedback_address,
+        control_port: args.control_port,
+        stats_address: args.stats_address,
+        metrics_address: args.metrics_address,
+        log_interval: args.log_interval,
+    }).unwrap();
 }
 
 #[cfg(not(feature = "receiver"))]
@@ -0,0 +1,254 @@
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use clap::Parser;
+use wfb_rs::common::{bandwidth::Bandwidth, channel::{self, Ht40}, fec::FecMode, radio::FcsMode};
+#[cfg(feature = "receiver")]
+use wfb_rs::{common::{tun::Tun, utils::{self, WifiSetup}}, Receiver, RxConfig, RxDevice, RxOutput, RxRunConfig, Transmitter, TxConfig, TxDevice, TxInput, TxRunConfig};
+
+/// Bidirectional ip tunnel of wfb_rs: sends the packets of a tun interface on one radio port and
+/// writes the packets received on another one back into it. The other end swaps the radio ports.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    // Magic number to identify the device
+    #[arg(short = 'm', long, default_value_t = 0x57627273)]
+    magic: u32,
+
+    /// Link ID
+    #[arg(short = 'i', long, default_value_t = 7669206)]
+    link_id: u32,
+
+    /// Radio port of the outgoing packets
+    #[arg(long, default_value_t = 32)]
+    tx_radio_port: u8,
+
+    /// Radio port of the incoming packets
+    #[arg(long, default_value_t = 33)]
+    rx_radio_port: u8,
+
+    /// Name of the tun interface
+    #[arg(long, default_value = "wfb-tun")]
+    tun_name: String,
+
+    /// Address of the tun interface, e.g. 10.5.0.1/24 (default: leave it unconfigured)
+    #[arg(long)]
+    tun_address: Option<String>,
+
+    /// MTU of the tun interface
+    #[arg(long, default_value_t = 1400)]
+    mtu: u16,
+
+    /// FEC mode, rs is Reed-Solomon compatible with wfb-ng and requires key files
+    #[arg(long, value_enum, default_value_t = FecMode::Raptorq)]
+    fec_mode: FecMode,
+
+    /// Reed-Solomon data fragments per block
+    #[arg(long, default_value_t = 1)]
+    fec_k: u8,
+
+    /// Reed-Solomon fragments per block including parity
+    #[arg(long, default_value_t = 2)]
+    fec_n: u8,
+
+    // (max) Size of each package send over wifi
+    #[arg(short = 'W', long, default_value_t = 800)]
+    wifi_packet_size: u16,
+
+    // (min) Size of each fec block
+    #[arg(short = 'B', long, default_value_t = 1_500)]
+    block_size: u32,
+
+    // Number of redundant packages send per block
+    #[arg(short = 'r', long, default_value_t = 2)]
+    redundant_pkgs: u32,
+
+    /// Send a fec block after this many ms even if it is not full, keeps interactive traffic responsive
+    #[arg(long, default_value = "10", value_parser = parse_duration)]
+    max_block_age: Duration,
+
+    /// Forward the intact packets of fec blocks that can't be decoded
+    #[arg(long, default_value_t = false)]
+    forward_partial: bool,
+
+    /// Frames that end with the 4 byte FCS: always, or only those the radiotap header flags
+    #[arg(long, value_enum, default_value_t = FcsMode::Always)]
+    fcs: FcsMode,
+
+    /// Bandwidth
+    #[arg(short='b', long, default_value = "20", value_parser = parse_bandwidth)]
+    bandwidth: Bandwidth,
+
+    /// Short GI
+    #[arg(short = 'G', long, action=clap::ArgAction::SetFalse, default_value_t = true)]
+    short_gi: bool,
+
+    /// STBC
+    #[arg(short = 'S', long, default_value_t = 1)]
+    stbc: u8,
+
+    /// LDPC
+    #[arg(short = 'L', long, default_value_t = true)]
+    ldpc: bool,
+
+    /// MCS Index
+    #[arg(short = 'M', long, default_value_t = 1)]
+    mcs_index: u8,
+
+    /// VHT Mode
+    #[arg(long, default_value_t = false)]
+    vht_mode: bool,
+
+    /// vht nss
+    #[arg(short = 'N', long, default_value_t = 1)]
+    vht_nss: u8,
+
+    /// Drone key of the outgoing direction (see wfb_rs_keygen), enables encryption
+    #[arg(long, requires = "rx_key")]
+    tx_key: Option<String>,
+
+    /// Ground station key of the incoming direction, generated separately from the outgoing one
+    #[arg(long, requires = "tx_key")]
+    rx_key: Option<String>,
+
+    /// Listen for control commands of the transmitter on this localhost udp port
+    #[arg(short = 'C', long)]
+    control_port: Option<u16>,
+
+    /// Log Interval
+    #[arg(short='l', long, default_value = "1000", value_parser = parse_duration)]
+    log_interval: Duration,
+
+    /// Wifi Card setup (monitor mode on --channel)
+    #[arg(short = 's', long, default_value_t = false)]
+    wifi_setup: bool,
+
+    /// Channel of the wifi setup, the channel width follows the bandwidth
+    #[arg(long, default_value_t = 149, value_parser = parse_channel)]
+    channel: u32,
+
+    /// Frequency of the wifi setup in MHz, instead of --channel
+    #[arg(long, conflicts_with = "channel")]
+    frequency: Option<u32>,
+
+    /// Side of the second half of a 40 MHz channel (default: the one of the channel plan)
+    #[arg(long, value_enum)]
+    ht40: Option<Ht40>,
+
+    /// Region of the wifi setup, an ISO 3166 country code like DE or 00 for the world
+    #[arg(long, value_parser = parse_region)]
+    region: Option<String>,
+
+    /// Kernel module the wifi setup loads first, empty to skip
+    #[arg(long, default_value = "8812eu")]
+    driver: String,
+
+    /// Wifi Devices, the first one also transmits
+    #[arg(required = true, num_args = 1..)]
+    wifi_devices: Vec<String>,
+}
+
+fn parse_duration(arg: &str) -> Result<std::time::Duration, std::num::ParseIntError> {
+    let milliseconds = arg.parse()?;
+    Ok(std::time::Duration::from_millis(milliseconds))
+}
+
+fn parse_channel(arg: &str) -> Result<u32, String> {
+    let channel = arg.parse().map_err(|_| format!("Invalid channel {}", arg))?;
+    channel::channel_frequency(channel).ok_or(format!("Unknown channel {}", channel))?;
+    Ok(channel)
+}
+
+fn parse_region(arg: &str) -> Result<String, String> {
+    if arg.len() != 2 || !arg.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(format!("Invalid region {}, expected a country code like DE", arg));
+    }
+    Ok(arg.to_ascii_uppercase())
+}
+
+fn parse_bandwidth(arg: &str) -> Result<Bandwidth, String> {
+    arg.parse()
+}
+
+#[cfg(feature = "receiver")]
+fn main() {
+    let args = Args::parse();
+
+    println!("{:?}", args);
+
+    let wifi_setup = args.wifi_setup.then(|| WifiSetup {
+        frequency: args.frequency.or(channel::channel_frequency(args.channel)).unwrap(),
+        ht40: args.ht40,
+        region: args.region.clone(),
+        driver: (!args.driver.is_empty()).then(|| args.driver.clone()),
+        hopping: None,
+    });
+    // the transmitter sets up the first card, the receiving ones are tuned to the same channel
+    if let Some(wifi_setup) = &wifi_setup {
+        for wifi in &args.wifi_devices[1..] {
+            let channel = utils::setup_wifi(wifi, wifi_setup, args.bandwidth).unwrap();
+            println!("{} is on {}", wifi, channel);
+        }
+    }
+
+    let tun = Tun::create(&args.tun_name).unwrap();
+    tun.configure(args.tun_address.as_deref(), args.mtu).unwrap();
+    println!("Tunnel interface {} is up", tun.name());
+    let tun = Arc::new(tun);
+
+    let tx = Transmitter::new(TxDevice::Wifi(args.wifi_devices[0].clone()), TxConfig {
+        magic: args.magic,
+        radio_port: args.tx_radio_port,
+        link_id: args.link_id,
+        bandwidth: args.bandwidth,
+        short_gi: args.short_gi,
+        stbc: args.stbc,
+        ldpc: args.ldpc,
+        mcs_index: args.mcs_index,
+        vht_mode: args.vht_mode,
+        vht_nss: args.vht_nss,
+        wifi_setup,
+        fec_mode: args.fec_mode,
+        min_block_size: args.block_size,
+        wifi_packet_size: args.wifi_packet_size,
+        redundant_pkgs: args.redundant_pkgs,
+        fec_k: args.fec_k,
+        fec_n: args.fec_n,
+        max_block_age: Some(args.max_block_age),
+        key_file: args.tx_key,
+        ..Default::default()
+    }).unwrap();
+
+    let rx = Receiver::new(args.wifi_devices.into_iter().map(RxDevice::Wifi).collect(), RxConfig {
+        magic: args.magic,
+        radio_port: args.rx_radio_port,
+        link_id: args.link_id,
+        bandwidth: args.bandwidth,
+        fec_mode: args.fec_mode,
+        forward_partial: args.forward_partial,
+        fcs: args.fcs,
+        key_file: args.rx_key,
+        ..Default::default()
+    }).unwrap();
+
+    let rx_tun = tun.clone();
+    let log_interval = args.log_interval;
+    thread::spawn(move || {
+        rx.run(RxOutput::Tun(rx_tun), RxRunConfig { log_interval, ..Default::default() }).unwrap();
+    });
+
+    // room for the largest packet the interface hands out
+    let buffer_size = args.mtu as usize + 1;
+    tx.run(TxInput::Tun(tun), TxRunConfig {
+        control_port: args.control_port,
+        buffer_size,
+        log_interval: args.log_interval,
+        ..Default::default()
+    }).unwrap();
+}
+
+#[cfg(not(feature = "receiver"))]
+fn main() {
+    println!("Tunnel was not built, recompile with --features=receiver")
+}
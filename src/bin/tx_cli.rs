@@ -1,7 +1,9 @@
-use std::time::Duration;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use clap::Parser;
-use wfb_rs::{common::{bandwidth::Bandwidth, utils}, Transmitter};
+use wfb_rs::common::{bandwidth::Bandwidth, channel::{self, Ht40}, fec::FecMode, hopping::Hopping, session::SessionFec, utils::{self, WifiSetup}};
+use wfb_rs::{Transmitter, TxConfig, TxDevice, TxInput, TxRunConfig};
 
 /// Receiving side of wfb_rs
 #[derive(Parser, Debug)]
@@ -10,6 +12,22 @@ struct Args {
     /// Explicitly disable fec
     #[arg(short = 'f', long, default_value_t = false)]
     fec_disabled: bool,
+
+    /// FEC mode, rs is Reed-Solomon compatible with wfb-ng and requires a key file
+    #[arg(long, value_enum, default_value_t = FecMode::Raptorq)]
+    fec_mode: FecMode,
+
+    /// Send the unversioned fec header of older releases (blocks up to 64 KiB, no partial forwarding)
+    #[arg(long, default_value_t = false)]
+    legacy_fec_header: bool,
+
+    /// Reed-Solomon data fragments per block
+    #[arg(long, default_value_t = 8)]
+    fec_k: u8,
+
+    /// Reed-Solomon fragments per block including parity
+    #[arg(long, default_value_t = 12)]
+    fec_n: u8,
     
     // Magic number to identify the device
     #[arg(short = 'm', long, default_value_t = 0x57627273)]
@@ -23,6 +41,11 @@ struct Args {
     #[arg(short = 'u', long, default_value_t = 5600)]
     source_port: u16,
 
+    /// Another stream on the same card: radio_port:udp_port[:none|:raptorq:block_size:redundant_pkgs|:rs:k:n],
+    /// without fec settings it takes those of the main stream. Can be repeated.
+    #[arg(long, value_parser = parse_stream)]
+    stream: Vec<Stream>,
+
     /// Receiving Buffer Size
     #[arg(short = 'R', long, default_value_t = 1_500)]
     buffer_size: usize,
@@ -33,7 +56,44 @@ struct Args {
 
     // (min) Size of each fec block
     #[arg(short = 'B', long, default_value_t = 10_000)]
-    block_size: u16,
+    block_size: u32,
+
+    /// Adapt the redundant packages to the feedback of the receiver, received on this port
+    #[arg(short = 'F', long)]
+    feedback_port: Option<u16>,
+
+    /// Address the feedback port listens on. The reports are not authenticated, only use addresses
+    /// reached through an authenticated wfb link (e.g. a tunnel with key files)
+    #[arg(long, default_value = "127.0.0.1")]
+    feedback_bind: String,
+
+    /// Lower bound of the adaptive redundant packages
+    #[arg(long, default_value_t = 2)]
+    min_redundant_pkgs: u32,
+
+    /// Upper bound of the adaptive redundant packages
+    #[arg(long, default_value_t = 60)]
+    max_redundant_pkgs: u32,
+
+    /// Pick the MCS index and bandwidth from the link reports on the feedback port
+    #[arg(long, default_value_t = false, requires = "feedback_port")]
+    auto_mcs: bool,
+
+    /// Lowest MCS index of the automatic rate selection
+    #[arg(long, default_value_t = 0)]
+    min_mcs: u8,
+
+    /// Highest MCS index of the automatic rate selection, the bandwidth option is the widest channel it uses
+    #[arg(long, default_value_t = 7)]
+    max_mcs: u8,
+
+    /// Interleave the packets of this many fec blocks against burst loss, the receiver needs the same depth
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=16))]
+    interleave_depth: u8,
+
+    /// Send a fec block after this many ms even if it is not full (default: wait for a full block)
+    #[arg(long, value_parser = parse_duration)]
+    max_block_age: Option<Duration>,
 
     // Number of redundant packages send per block
     #[arg(short = 'r', long, default_value_t = 15)]
@@ -67,26 +127,74 @@ struct Args {
     #[arg(short='l', long, default_value = "1000", value_parser = parse_duration)]
     log_interval: Duration,
 
+    /// Send json statistics every log interval to this udp address (host:port) or unix socket (unix:/path)
+    #[arg(long)]
+    stats_address: Option<String>,
+
+    /// Serve Prometheus metrics at http://<address>/metrics (e.g. 0.0.0.0:9101)
+    #[arg(long)]
+    metrics_address: Option<String>,
+
     /// Link ID
     #[arg(short = 'i', long, default_value_t = 7669206)]
     link_id: u32,
 
-    /// Epoch
-    #[arg(long, default_value_t = 0)]
-    epoch: u64,
+    /// Session epoch, must increase between restarts (default: current unix time)
+    #[arg(long)]
+    epoch: Option<u64>,
+
+    /// Drone key file (see wfb_rs_keygen), enables encryption of the radio payload
+    #[arg(short = 'K', long)]
+    key_file: Option<String>,
+
+    /// Interval of the session announcements in ms
+    #[arg(long, default_value = "1000", value_parser = parse_duration)]
+    session_interval: Duration,
+
+    /// Rotate the session key after this many ms, at least 1000 (default: never)
+    #[arg(long, value_parser = parse_duration)]
+    key_rotation: Option<Duration>,
 
     /// VHT Mode
     #[arg(long, default_value_t = false)]
     vht_mode: bool,
 
-    /// Control Port
-    #[arg(short = 'C', long, default_value_t = 9000)]
-    control_port: u16,
+    /// Listen for control commands (set-mcs, set-fec, ...) on this localhost udp port, e.g. 9000
+    #[arg(short = 'C', long)]
+    control_port: Option<u16>,
 
-    /// Wifi Card setup (channel 149, monitor mode)
+    /// Wifi Card setup (monitor mode on --channel)
     #[arg(short = 's', long, default_value_t = false)]
     wifi_setup: bool,
 
+    /// Channel of the wifi setup, the channel width follows the bandwidth
+    #[arg(long, default_value_t = 149, value_parser = parse_channel)]
+    channel: u32,
+
+    /// Frequency of the wifi setup in MHz, instead of --channel
+    #[arg(long, conflicts_with = "channel")]
+    frequency: Option<u32>,
+
+    /// Side of the second half of a 40 MHz channel (default: the one of the channel plan)
+    #[arg(long, value_enum)]
+    ht40: Option<Ht40>,
+
+    /// Region of the wifi setup, an ISO 3166 country code like DE or 00 for the world
+    #[arg(long, value_parser = parse_region)]
+    region: Option<String>,
+
+    /// Kernel module the wifi setup loads first, empty to skip
+    #[arg(long, default_value = "8812eu")]
+    driver: String,
+
+    /// Channels to hop between, e.g. 149,157,165, the same list on both sides (needs --wifi-setup and --key-file)
+    #[arg(long, value_delimiter = ',', value_parser = parse_channel, requires = "wifi_setup")]
+    hop_channels: Vec<u32>,
+
+    /// Dwell time of the frequency hopping on each channel in ms
+    #[arg(long, default_value = "200", value_parser = parse_duration)]
+    hop_dwell: Duration,
+
     /// Tx Power Index (0-64)
     #[arg(short = 't', long)]
     txpower: Option<u8>,
@@ -101,15 +209,58 @@ fn parse_duration(arg: &str) -> Result<std::time::Duration, std::num::ParseIntEr
     Ok(std::time::Duration::from_millis(milliseconds))
 }
 
+fn parse_channel(arg: &str) -> Result<u32, String> {
+    let channel = arg.parse().map_err(|_| format!("Invalid channel {}", arg))?;
+    channel::channel_frequency(channel).ok_or(format!("Unknown channel {}", channel))?;
+    Ok(channel)
+}
+
+fn parse_region(arg: &str) -> Result<String, String> {
+    if arg.len() != 2 || !arg.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(format!("Invalid region {}, expected a country code like DE", arg));
+    }
+    Ok(arg.to_ascii_uppercase())
+}
+
 fn parse_bandwidth(arg: &str) -> Result<Bandwidth, String> {
-    match arg {
-        "10" => Ok(Bandwidth::Bw10),
-        "20" => Ok(Bandwidth::Bw20),
-        "40" => Ok(Bandwidth::Bw40),
-        "80" => Ok(Bandwidth::Bw80),
-        "160" => Ok(Bandwidth::Bw160),
-        _ => Err("Invalid Bandwidth!".to_string()),
+    arg.parse()
+}
+
+#[derive(Debug, Clone)]
+struct Stream {
+    radio_port: u8,
+    source_port: u16,
+    fec: Option<StreamFec>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum StreamFec {
+    None,
+    Raptorq { block_size: u32, redundant_pkgs: u32 },
+    Rs { k: u8, n: u8 },
+}
+
+fn parse_stream(arg: &str) -> Result<Stream, String> {
+    fn number<T: std::str::FromStr>(part: &str) -> Result<T, String> {
+        part.parse().map_err(|_| format!("Invalid number {}", part))
     }
+
+    let usage = "Expected radio_port:udp_port[:none|:raptorq:block_size:redundant_pkgs|:rs:k:n]";
+    let parts: Vec<&str> = arg.split(':').collect();
+    let [radio_port, source_port, fec @ ..] = parts.as_slice() else {
+        return Err(usage.to_string());
+    };
+    let fec = match fec {
+        [] => None,
+        ["none"] => Some(StreamFec::None),
+        ["raptorq", block_size, redundant_pkgs] => Some(StreamFec::Raptorq {
+            block_size: number(block_size)?,
+            redundant_pkgs: number(redundant_pkgs)?,
+        }),
+        ["rs", k, n] => Some(StreamFec::Rs { k: number(k)?, n: number(n)? }),
+        _ => return Err(usage.to_string()),
+    };
+    Ok(Stream { radio_port: number(radio_port)?, source_port: number(source_port)?, fec })
 }
 
 fn main() {
@@ -117,34 +268,93 @@ fn main() {
 
     println!("{:?}", args);
 
-    if args.wifi_setup {
-        utils::set_monitor_mode(args.wifi_device.as_str()).unwrap();
-    }
+    // the transmitter sets up the card, so the channel is as wide as its bandwidth
+    let wifi_setup = args.wifi_setup.then(|| WifiSetup {
+        frequency: args.frequency.or(channel::channel_frequency(args.channel)).unwrap(),
+        ht40: args.ht40,
+        region: args.region.clone(),
+        driver: (!args.driver.is_empty()).then(|| args.driver.clone()),
+        hopping: (!args.hop_channels.is_empty()).then(|| Hopping {
+            frequencies: args.hop_channels.iter().filter_map(|&channel| channel::channel_frequency(channel)).collect(),
+            dwell: args.hop_dwell,
+        }),
+    });
+
+    let epoch = args.epoch.unwrap_or_else(|| {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    });
+
+    let tx = Transmitter::new(TxDevice::Wifi(args.wifi_device.clone()), TxConfig {
+        magic: args.magic,
+        radio_port: args.radio_port,
+        link_id: args.link_id,
+        bandwidth: args.bandwidth,
+        short_gi: args.short_gi,
+        stbc: args.stbc,
+        ldpc: args.ldpc,
+        mcs_index: args.mcs_index,
+        vht_mode: args.vht_mode,
+        vht_nss: args.vht_nss,
+        wifi_setup,
+        fec_disabled: args.fec_disabled,
+        fec_mode: args.fec_mode,
+        legacy_fec_header: args.legacy_fec_header,
+        min_block_size: args.block_size,
+        wifi_packet_size: args.wifi_packet_size,
+        redundant_pkgs: args.redundant_pkgs,
+        adaptive_redundancy: args.feedback_port.map(|_| (args.min_redundant_pkgs, args.max_redundant_pkgs)),
+        rate_control: args.auto_mcs.then_some((args.min_mcs, args.max_mcs)),
+        interleave_depth: args.interleave_depth as usize,
+        fec_k: args.fec_k,
+        fec_n: args.fec_n,
+        max_block_age: args.max_block_age,
+        key_file: args.key_file,
+        epoch,
+        session_interval: args.session_interval,
+        key_rotation: args.key_rotation,
+    }).unwrap();
+
+    // after the setup, monitor mode may reset it
     if let Some(tx_power) = args.txpower {
         utils::set_tx_power(args.wifi_device.as_str(), tx_power).unwrap();
     }
 
-    let tx = Transmitter::new(
-        args.magic,
-        args.radio_port,
-        args.link_id,
-        args.bandwidth,
-        args.short_gi,
-        args.stbc,
-        args.ldpc,
-        args.mcs_index,
-        args.vht_mode,
-        args.vht_nss,
-        args.wifi_device,
-        args.fec_disabled,
-        args.block_size,
-        args.wifi_packet_size,
-        args.redundant_pkgs
-    ).unwrap();
-
-    tx.run(
-        args.source_port,
-        args.buffer_size,
-        args.log_interval,
-    ).unwrap();
+    // the main stream's fec settings for streams without their own
+    let main_fec = if args.fec_mode == FecMode::Rs {
+        SessionFec::ReedSolomon { k: args.fec_k, n: args.fec_n }
+    } else if args.fec_disabled {
+        SessionFec::Disabled
+    } else {
+        SessionFec::RaptorQ { block_size: args.block_size, packet_size: args.wifi_packet_size, redundant_pkgs: args.redundant_pkgs }
+    };
+    for stream in &args.stream {
+        let fec = match stream.fec {
+            None => main_fec,
+            Some(StreamFec::None) => SessionFec::Disabled,
+            Some(StreamFec::Raptorq { block_size, redundant_pkgs }) => {
+                SessionFec::RaptorQ { block_size, packet_size: args.wifi_packet_size, redundant_pkgs }
+            }
+            Some(StreamFec::Rs { k, n }) => SessionFec::ReedSolomon { k, n },
+        };
+        let stream_tx = tx.add_stream(stream.radio_port, fec).unwrap();
+        let source_port = stream.source_port;
+        let stats_address = args.stats_address.clone();
+        thread::spawn(move || {
+            stream_tx.run(TxInput::Udp(source_port), TxRunConfig {
+                stats_address,
+                buffer_size: args.buffer_size,
+                log_interval: args.log_interval,
+                ..Default::default()
+            }).unwrap();
+        });
+    }
+
+    tx.run(TxInput::Udp(args.source_port), TxRunConfig {
+        feedback_address: args.feedback_port.map(|port| format!("{}:{}", args.feedback_bind, port)),
+        control_port: args.control_port,
+        stats_address: args.stats_address,
+        metrics_address: args.metrics_address,
+        buffer_size: args.buffer_size,
+        log_interval: args.log_interval,
+    }).unwrap();
 }
@@ -1,5 +1,28 @@
 pub mod bandwidth;
+pub mod channel;
 pub mod hw_headers;
+pub mod crypto;
 pub mod magic_header;
+pub mod session;
 pub mod fec;
+pub mod feedback;
+pub mod hopping;
+pub mod control;
+pub mod stats;
+pub mod metrics;
+pub mod keypair;
+pub mod netlink;
+pub mod radio;
+pub mod sim_channel;
+pub mod tun;
 pub mod utils;
+pub mod wfb_ng;
+
+// Known answers of the unit tests are written in hex
+#[cfg(test)]
+pub(crate) fn from_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
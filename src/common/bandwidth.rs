@@ -1,6 +1,9 @@
+use std::fmt;
+use std::str::FromStr;
+
 use clap::ValueEnum;
 
-#[derive(Debug, Clone, Copy, ValueEnum)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
 pub enum Bandwidth {
     Bw10,
     Bw20,
@@ -9,14 +12,36 @@ pub enum Bandwidth {
     Bw160,
 }
 
-impl ToString for Bandwidth {
-    fn to_string(&self) -> String {
+impl Bandwidth {
+    pub fn mhz(&self) -> u16 {
         match self {
-            Bandwidth::Bw10 => "10".to_string(),
-            Bandwidth::Bw20 => "20".to_string(),
-            Bandwidth::Bw40 => "40".to_string(),
-            Bandwidth::Bw80 => "80".to_string(),
-            Bandwidth::Bw160 => "160".to_string(),
+            Bandwidth::Bw10 => 10,
+            Bandwidth::Bw20 => 20,
+            Bandwidth::Bw40 => 40,
+            Bandwidth::Bw80 => 80,
+            Bandwidth::Bw160 => 160,
+        }
+    }
+}
+
+impl fmt::Display for Bandwidth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.mhz())
+    }
+}
+
+// Parses the channel width in MHz, as given on the command line
+impl FromStr for Bandwidth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "10" => Ok(Bandwidth::Bw10),
+            "20" => Ok(Bandwidth::Bw20),
+            "40" => Ok(Bandwidth::Bw40),
+            "80" => Ok(Bandwidth::Bw80),
+            "160" => Ok(Bandwidth::Bw160),
+            _ => Err("Invalid Bandwidth!".to_string()),
         }
     }
 }
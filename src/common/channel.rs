@@ -0,0 +1,109 @@
+// Wifi channel the card is tuned to. Its width is the bandwidth of the radiotap header, wider channels
+// are placed like the standard 5 GHz channel plan places them.
+use std::fmt;
+
+use clap::ValueEnum;
+
+use super::bandwidth::Bandwidth;
+
+// center channel numbers of the 5 GHz channels wider than 20 MHz
+const CENTERS_40: [u32; 14] = [38, 46, 54, 62, 102, 110, 118, 126, 134, 142, 151, 159, 167, 175];
+const CENTERS_80: [u32; 7] = [42, 58, 106, 122, 138, 155, 171];
+const CENTERS_160: [u32; 3] = [50, 114, 163];
+
+// Side of the second 20 MHz half of a 40 MHz channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Ht40 {
+    #[value(alias = "+")]
+    Plus,   // above the primary channel
+    #[value(alias = "-")]
+    Minus,  // below the primary channel
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Channel {
+    pub frequency: u32,         // MHz, of the primary 20 MHz channel
+    pub bandwidth: Bandwidth,
+    pub center_frequency: u32,  // MHz, of the whole channel
+}
+
+impl Channel {
+    // ht40 picks the side of a 40 MHz channel, without it the channel plan decides
+    pub fn new(frequency: u32, bandwidth: Bandwidth, ht40: Option<Ht40>) -> Result<Self, String> {
+        if ht40.is_some() && bandwidth != Bandwidth::Bw40 {
+            return Err(format!("HT40+/- needs a 40 MHz channel, not {} MHz", bandwidth));
+        }
+
+        let center_frequency = match bandwidth {
+            Bandwidth::Bw10 | Bandwidth::Bw20 => frequency,
+            Bandwidth::Bw40 => match ht40 {
+                Some(Ht40::Plus) => frequency + 10,
+                Some(Ht40::Minus) => frequency.saturating_sub(10),
+                // 2.4 GHz channels overlap, the second half goes where there is room for it
+                None if is_2ghz(frequency) => match channel_number(frequency) {
+                    Some(channel) if channel <= 7 => frequency + 10,
+                    Some(_) => frequency - 10,
+                    None => return Err(format!("{} MHz is no 2.4 GHz channel", frequency)),
+                },
+                None => Self::plan_center(frequency, bandwidth, &CENTERS_40)?,
+            },
+            Bandwidth::Bw80 => Self::plan_center(frequency, bandwidth, &CENTERS_80)?,
+            Bandwidth::Bw160 => Self::plan_center(frequency, bandwidth, &CENTERS_160)?,
+        };
+
+        Ok(Self { frequency, bandwidth, center_frequency })
+    }
+
+    // Center of the 5 GHz channel of the given width that contains the primary channel
+    fn plan_center(frequency: u32, bandwidth: Bandwidth, centers: &[u32]) -> Result<u32, String> {
+        let no_channel = || format!("{} MHz is not part of any {} MHz channel", frequency, bandwidth);
+        if is_2ghz(frequency) {
+            return Err(no_channel());
+        }
+        let channel = channel_number(frequency).ok_or_else(no_channel)?;
+        // the 20 MHz channels of a wider one are 4 channel numbers apart around its center
+        let reach = (bandwidth.mhz() as u32 - 20) / 10;
+        centers
+            .iter()
+            .find(|&&center| channel.abs_diff(center) <= reach && channel.abs_diff(center) % 4 == 2)
+            .map(|&center| 5000 + center * 5)
+            .ok_or_else(no_channel)
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match channel_number(self.frequency) {
+            Some(channel) => write!(f, "channel {} ({} MHz)", channel, self.frequency)?,
+            None => write!(f, "{} MHz", self.frequency)?,
+        }
+        write!(f, ", {} MHz wide", self.bandwidth)?;
+        if self.center_frequency != self.frequency {
+            write!(f, " around {} MHz", self.center_frequency)?;
+        }
+        Ok(())
+    }
+}
+
+// Frequency in MHz of a 2.4 or 5 GHz channel number
+pub fn channel_frequency(channel: u32) -> Option<u32> {
+    match channel {
+        1..=13 => Some(2407 + channel * 5),
+        14 => Some(2484),
+        32..=177 => Some(5000 + channel * 5),
+        _ => None,
+    }
+}
+
+pub fn channel_number(frequency: u32) -> Option<u32> {
+    match frequency {
+        2412..=2472 if (frequency - 2407).is_multiple_of(5) => Some((frequency - 2407) / 5),
+        2484 => Some(14),
+        5160..=5885 if frequency.is_multiple_of(5) => Some((frequency - 5000) / 5),
+        _ => None,
+    }
+}
+
+fn is_2ghz(frequency: u32) -> bool {
+    frequency < 3000
+}
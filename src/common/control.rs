@@ -0,0 +1,222 @@
+// Runtime control of a running transmitter or receiver: one text command per udp datagram on the
+// control port, answered with a single line starting with "ok" or "error", e.g.
+// `echo set-mcs 3 | nc -u -w1 127.0.0.1 9000` or `wfb_rs_ctl set-mcs 3`
+use std::fmt;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use super::bandwidth::Bandwidth;
+
+// Transmitter settings that can change while it runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    Mcs(u8),
+    Bandwidth(Bandwidth),
+    Stbc(u8),
+    Ldpc(bool),
+    ShortGi(bool),
+    Fec { block_size: u32, redundant_pkgs: u32 },   // raptorq only
+    TxPower(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlCommand {
+    Set(Setting),
+    GetStats,   // answered with the json record of the totals since the start
+}
+
+impl ControlCommand {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&name, args)) = words.split_first() else {
+            return Err("empty command".to_string());
+        };
+
+        let setting = match (name, args) {
+            ("get-stats", []) => return Ok(ControlCommand::GetStats),
+            ("set-mcs", [mcs]) => Setting::Mcs(parse_number(mcs)?),
+            ("set-bandwidth", [bandwidth]) => Setting::Bandwidth(bandwidth.parse()?),
+            ("set-stbc", [stbc]) => Setting::Stbc(parse_number(stbc)?),
+            ("set-ldpc", [ldpc]) => Setting::Ldpc(parse_switch(ldpc)?),
+            ("set-short-gi", [short_gi]) => Setting::ShortGi(parse_switch(short_gi)?),
+            ("set-fec", [block_size, redundant_pkgs]) => Setting::Fec {
+                block_size: parse_number(block_size)?,
+                redundant_pkgs: parse_number(redundant_pkgs)?,
+            },
+            ("set-txpower", [tx_power]) => Setting::TxPower(parse_number(tx_power)?),
+            ("get-stats" | "set-mcs" | "set-bandwidth" | "set-stbc" | "set-ldpc" | "set-short-gi" | "set-fec" | "set-txpower", _) => {
+                return Err(format!("wrong number of arguments for {}", name));
+            }
+            _ => return Err(format!("unknown command {}", name)),
+        };
+        Ok(ControlCommand::Set(setting))
+    }
+}
+
+// The text form parse reads
+impl fmt::Display for ControlCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let switch = |on: bool| if on { "on" } else { "off" };
+        match self {
+            ControlCommand::GetStats => write!(f, "get-stats"),
+            ControlCommand::Set(Setting::Mcs(mcs)) => write!(f, "set-mcs {}", mcs),
+            ControlCommand::Set(Setting::Bandwidth(bandwidth)) => write!(f, "set-bandwidth {}", bandwidth),
+            ControlCommand::Set(Setting::Stbc(stbc)) => write!(f, "set-stbc {}", stbc),
+            ControlCommand::Set(Setting::Ldpc(ldpc)) => write!(f, "set-ldpc {}", switch(*ldpc)),
+            ControlCommand::Set(Setting::ShortGi(short_gi)) => write!(f, "set-short-gi {}", switch(*short_gi)),
+            ControlCommand::Set(Setting::Fec { block_size, redundant_pkgs }) => {
+                write!(f, "set-fec {} {}", block_size, redundant_pkgs)
+            }
+            ControlCommand::Set(Setting::TxPower(tx_power)) => write!(f, "set-txpower {}", tx_power),
+        }
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(arg: &str) -> Result<T, String> {
+    arg.parse().map_err(|_| format!("invalid number {}", arg))
+}
+
+fn parse_switch(arg: &str) -> Result<bool, String> {
+    match arg {
+        "on" | "1" | "true" => Ok(true),
+        "off" | "0" | "false" => Ok(false),
+        _ => Err(format!("expected on or off, got {}", arg)),
+    }
+}
+
+// A parsed command waiting to be applied, the reply goes back to the sender
+pub struct ControlRequest {
+    pub command: ControlCommand,
+    socket: Arc<UdpSocket>,
+    sender: SocketAddr,
+}
+
+impl ControlRequest {
+    // Ok carries the answer to a query, empty for settings
+    pub fn reply(&self, result: Result<String, String>) {
+        send_reply(&self.socket, self.sender, result);
+    }
+}
+
+fn send_reply(socket: &UdpSocket, sender: SocketAddr, result: Result<String, String>) {
+    let reply = match result {
+        Ok(answer) if answer.is_empty() => "ok\n".to_string(),
+        Ok(answer) => format!("ok {}\n", answer),
+        Err(e) => format!("error: {}\n", e),
+    };
+    // the sender may be gone already, that's fine
+    let _ = socket.send_to(reply.as_bytes(), sender);
+}
+
+// Receives commands in the background, malformed ones are answered right away. Only listens on
+// localhost, the commands are not authenticated.
+pub fn listen(control_port: u16) -> Result<Receiver<ControlRequest>, Box<dyn std::error::Error>> {
+    let socket = Arc::new(UdpSocket::bind(format!("127.0.0.1:{}", control_port))?);
+    let (request_s, request_r) = channel();
+
+    thread::spawn(move || {
+        let mut buffer = [0u8; 256];
+        loop {
+            let (received, sender) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("Error receiving control command: {}", e);
+                    continue;
+                }
+            };
+            let line = String::from_utf8_lossy(&buffer[..received]);
+            let command = match ControlCommand::parse(&line) {
+                Ok(command) => command,
+                Err(e) => {
+                    send_reply(&socket, sender, Err(e));
+                    continue;
+                }
+            };
+            if request_s.send(ControlRequest { command, socket: socket.clone(), sender }).is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(request_r)
+}
+
+// Sends a command to the control port at `address` and waits for the reply, returns the answer
+// after "ok" or the reason of an error
+pub fn send_command(address: &str, command: ControlCommand, timeout: Duration) -> Result<String, Box<dyn std::error::Error>> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(address)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.send(command.to_string().as_bytes())?;
+
+    let mut buffer = [0u8; 65536];
+    let received = socket.recv(&mut buffer).map_err(|e| match e.kind() {
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => format!("no reply from {}", address),
+        _ => format!("no reply from {}: {}", address, e),
+    })?;
+
+    let reply = String::from_utf8_lossy(&buffer[..received]);
+    let reply = reply.trim_end();
+    if let Some(e) = reply.strip_prefix("error: ") {
+        return Err(e.into());
+    }
+    match reply.strip_prefix("ok") {
+        Some(answer) => Ok(answer.trim_start().to_string()),
+        None => Err(format!("unexpected reply {}", reply).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        let parse = |line| ControlCommand::parse(line).unwrap();
+        assert_eq!(parse("get-stats"), ControlCommand::GetStats);
+        assert_eq!(parse("set-mcs 3\n"), ControlCommand::Set(Setting::Mcs(3)));
+        assert_eq!(parse(" set-bandwidth  40 "), ControlCommand::Set(Setting::Bandwidth(Bandwidth::Bw40)));
+        assert_eq!(parse("set-fec 20000 8"), ControlCommand::Set(Setting::Fec { block_size: 20000, redundant_pkgs: 8 }));
+        assert_eq!(parse("set-ldpc off"), ControlCommand::Set(Setting::Ldpc(false)));
+        assert_eq!(parse("set-short-gi 1"), ControlCommand::Set(Setting::ShortGi(true)));
+        assert_eq!(parse("set-stbc 2"), ControlCommand::Set(Setting::Stbc(2)));
+        assert_eq!(parse("set-txpower 40"), ControlCommand::Set(Setting::TxPower(40)));
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let error = |line| ControlCommand::parse(line).unwrap_err();
+        assert_eq!(error(""), "empty command");
+        assert_eq!(error(" \n"), "empty command");
+        assert_eq!(error("set-rate 3"), "unknown command set-rate");
+        assert_eq!(error("set-mcs"), "wrong number of arguments for set-mcs");
+        assert_eq!(error("set-fec 20000"), "wrong number of arguments for set-fec");
+        assert_eq!(error("get-stats now"), "wrong number of arguments for get-stats");
+        assert_eq!(error("set-mcs three"), "invalid number three");
+        assert_eq!(error("set-mcs 256"), "invalid number 256");
+        assert_eq!(error("set-mcs -1"), "invalid number -1");
+        assert_eq!(error("set-fec 20000 -8"), "invalid number -8");
+        assert_eq!(error("set-ldpc maybe"), "expected on or off, got maybe");
+        assert!(ControlCommand::parse("set-bandwidth 30").is_err());
+    }
+
+    #[test]
+    fn display_is_parsed_back() {
+        let commands = [
+            ControlCommand::GetStats,
+            ControlCommand::Set(Setting::Mcs(7)),
+            ControlCommand::Set(Setting::Bandwidth(Bandwidth::Bw80)),
+            ControlCommand::Set(Setting::Stbc(1)),
+            ControlCommand::Set(Setting::Ldpc(true)),
+            ControlCommand::Set(Setting::ShortGi(false)),
+            ControlCommand::Set(Setting::Fec { block_size: 1, redundant_pkgs: 0 }),
+            ControlCommand::Set(Setting::TxPower(63)),
+        ];
+        for command in commands {
+            assert_eq!(ControlCommand::parse(&command.to_string()), Ok(command));
+        }
+    }
+}
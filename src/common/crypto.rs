@@ -0,0 +1,170 @@
+use std::mem::size_of;
+
+use chacha20::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use chacha20::ChaCha20Legacy;
+use poly1305::universal_hash::KeyInit;
+use poly1305::Poly1305;
+#[cfg(feature = "receiver")]
+use subtle::ConstantTimeEq;
+
+pub const KEY_SIZE: usize = 32;
+pub const TAG_SIZE: usize = 16;
+const CRYPTO_HEADER_SIZE: usize = size_of::<CryptoHeader>();
+
+pub type Key = [u8; KEY_SIZE];
+
+// Header in front of every encrypted wfb packet, it is also authenticated as associated data
+#[derive(Debug, Clone, Copy)]
+pub struct CryptoHeader {
+    pub epoch: u64,     // 8 bytes - session epoch, selects the session key
+    pub nonce: u64,     // 8 bytes - per packet nonce, never reused within an epoch
+}
+
+impl CryptoHeader {
+    pub fn new(epoch: u64, nonce: u64) -> Self {
+        Self {
+            epoch,
+            nonce,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; CRYPTO_HEADER_SIZE] {
+        let mut bytes = [0u8; CRYPTO_HEADER_SIZE];
+        bytes[0..8].copy_from_slice(&self.epoch.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.nonce.to_le_bytes());
+        bytes
+    }
+
+    #[cfg(feature = "receiver")]
+    pub fn from_bytes(bytes: &[u8]) -> Option<(Self, &[u8])> {
+        if bytes.len() < CRYPTO_HEADER_SIZE {
+            return None;
+        }
+
+        let epoch = u64::from_le_bytes(bytes[0..8].try_into().unwrap());
+        let nonce = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+
+        Some((Self {
+            epoch,
+            nonce
+        }, &bytes[CRYPTO_HEADER_SIZE..]))
+    }
+}
+
+// ChaCha20-Poly1305 with a 64 bit nonce, the same construction as libsodium's crypto_aead_chacha20poly1305
+fn poly1305_tag(key: &Key, nonce: &[u8; 8], ad: &[u8], ciphertext: &[u8]) -> [u8; TAG_SIZE] {
+    let mut block0 = [0u8; 64];
+    ChaCha20Legacy::new(key.into(), nonce.into()).apply_keystream(&mut block0);
+
+    let mut mac_data = Vec::with_capacity(ad.len() + ciphertext.len() + 16);
+    mac_data.extend_from_slice(ad);
+    mac_data.extend_from_slice(&(ad.len() as u64).to_le_bytes());
+    mac_data.extend_from_slice(ciphertext);
+    mac_data.extend_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+
+    Poly1305::new(block0[..32].into()).compute_unpadded(&mac_data).into()
+}
+
+fn apply_keystream(key: &Key, nonce: &[u8; 8], data: &mut [u8]) {
+    let mut cipher = ChaCha20Legacy::new(key.into(), nonce.into());
+    // block 0 is reserved for the poly1305 key
+    cipher.seek(64u64);
+    cipher.apply_keystream(data);
+}
+
+// Returns the ciphertext with the authentication tag appended
+pub fn encrypt(key: &Key, nonce: &[u8; 8], ad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(plaintext.len() + TAG_SIZE);
+    out.extend_from_slice(plaintext);
+    apply_keystream(key, nonce, &mut out);
+    let tag = poly1305_tag(key, nonce, ad, &out);
+    out.extend_from_slice(&tag);
+    out
+}
+
+// Returns None if the packet was not authenticated by the key
+#[cfg(feature = "receiver")]
+pub fn decrypt(key: &Key, nonce: &[u8; 8], ad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+    if ciphertext.len() < TAG_SIZE {
+        return None;
+    }
+    let (ciphertext, tag) = ciphertext.split_at(ciphertext.len() - TAG_SIZE);
+
+    let expected_tag = poly1305_tag(key, nonce, ad, ciphertext);
+    if !bool::from(expected_tag.ct_eq(tag)) {
+        return None;
+    }
+
+    let mut out = ciphertext.to_vec();
+    apply_keystream(key, nonce, &mut out);
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::from_hex;
+
+    // test vector of draft-agl-tls-chacha20poly1305, which libsodium's crypto_aead_chacha20poly1305 follows
+    const KEY: &str = "4290bcb154173531f314af57f3be3b5006da371ece272afa1b5dbdd1100a1007";
+    const NONCE: &str = "cd7cf67be39c794a";
+    const AD: &str = "87e229d4500845a079c0";
+    const PLAINTEXT: &str = "86d09974840bded2a5ca";
+    const CIPHERTEXT: &str = "e3e446f7ede9a19b62a4677dabf4e3d24b876bb284753896e1d6";
+
+    fn vector() -> (Key, [u8; 8], Vec<u8>, Vec<u8>, Vec<u8>) {
+        (
+            from_hex(KEY).try_into().unwrap(),
+            from_hex(NONCE).try_into().unwrap(),
+            from_hex(AD),
+            from_hex(PLAINTEXT),
+            from_hex(CIPHERTEXT),
+        )
+    }
+
+    #[test]
+    fn encrypt_matches_libsodium() {
+        let (key, nonce, ad, plaintext, ciphertext) = vector();
+        assert_eq!(encrypt(&key, &nonce, &ad, &plaintext), ciphertext);
+    }
+
+    #[test]
+    fn crypto_header_is_little_endian() {
+        let header = CryptoHeader::new(0x0102_0304_0506_0708, 0x1112_1314_1516_1718).to_bytes();
+        assert_eq!(header[..], from_hex("08070605040302011817161514131211"));
+    }
+
+    #[cfg(feature = "receiver")]
+    #[test]
+    fn crypto_header_round_trip() {
+        let header = CryptoHeader::new(7, 42).to_bytes();
+        let packet = [&header[..], b"payload"].concat();
+        let (parsed, rest) = CryptoHeader::from_bytes(&packet).unwrap();
+        assert_eq!((parsed.epoch, parsed.nonce, rest), (7, 42, &b"payload"[..]));
+        assert!(CryptoHeader::from_bytes(&header[..15]).is_none());
+    }
+
+    #[cfg(feature = "receiver")]
+    #[test]
+    fn decrypt_matches_libsodium() {
+        let (key, nonce, ad, plaintext, ciphertext) = vector();
+        assert_eq!(decrypt(&key, &nonce, &ad, &ciphertext), Some(plaintext));
+    }
+
+    #[cfg(feature = "receiver")]
+    #[test]
+    fn decrypt_rejects_modified_packets() {
+        let (key, nonce, ad, _, ciphertext) = vector();
+
+        let mut other_nonce = nonce;
+        other_nonce[7] ^= 1;
+        assert_eq!(decrypt(&key, &other_nonce, &ad, &ciphertext), None);
+        assert_eq!(decrypt(&key, &nonce, &ad[1..], &ciphertext), None);
+        for i in 0..ciphertext.len() {
+            let mut modified = ciphertext.clone();
+            modified[i] ^= 0x80;
+            assert_eq!(decrypt(&key, &nonce, &ad, &modified), None);
+        }
+        assert_eq!(decrypt(&key, &nonce, &ad, &ciphertext[..TAG_SIZE - 1]), None);
+    }
+}
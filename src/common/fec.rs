@@ -1,49 +1,155 @@
+pub mod reed_solomon;
+
 use std::mem::size_of;
 
+use clap::ValueEnum;
 use raptorq::ObjectTransmissionInformation;
 
+// raptorq is our own format, rs is Reed-Solomon in the wire format of wfb-ng
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FecMode {
+    Raptorq,
+    Rs,
+}
+
 // FEC Header constants and structures
-const FEC_HEADER_SIZE: usize = size_of::<FecHeader>();
+// version 0 is the unversioned header of the first releases: 16 bit block size and the 8 bit block id of raptorq
+pub const FEC_VERSION_LEGACY: u8 = 0;
+pub const FEC_VERSION: u8 = 1;
+const FEC_HEADER_SIZE_LEGACY: usize = size_of::<u16>() * 2;
+const FEC_HEADER_SIZE: usize = size_of::<u8>() + size_of::<u64>() + size_of::<u32>() + size_of::<u16>();
 
-pub fn get_raptorq_oti(block_size: u16, wifi_packet_size: u16) -> (ObjectTransmissionInformation, u64) {
+// larger blocks would exceed the decoding memory of raptorq, forged ones could exhaust ours
+pub const MAX_BLOCK_SIZE: u32 = 8 * 1024 * 1024;
+
+// fec header, packet table and symbol of one fec packet. The receiver captures 4096 bytes, which also
+// hold the radiotap, 802.11 and crypto headers.
+pub const MAX_FEC_PACKET_SIZE: usize = 3840;
+
+pub fn get_raptorq_oti(block_size: u32, wifi_packet_size: u16) -> (ObjectTransmissionInformation, u64) {
     let config = ObjectTransmissionInformation::with_defaults(block_size as u64, wifi_packet_size);
     let padding = config.symbol_size() as u64 - config.transfer_length() % config.symbol_size() as u64;
     (config, padding)
 }
 
-#[derive(Debug, Clone, Copy)]
+// Blocks the receiver accepts and raptorq encodes as a single source block, the packet size limits
+// the symbols of a source block
+pub fn check_block_size(block_size: u32, wifi_packet_size: u16) -> Result<(), String> {
+    if block_size > MAX_BLOCK_SIZE {
+        return Err(format!("Blocks are limited to {} bytes, got {}", MAX_BLOCK_SIZE, block_size));
+    }
+    if get_raptorq_oti(block_size, wifi_packet_size).0.source_blocks() != 1 {
+        return Err(format!("Blocks of {} bytes are too large for packets of {} bytes", block_size, wifi_packet_size));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct FecHeader {
-    pub block_size: u16,    // 2 bytes - the total size of the current fec block in bytes
+    pub version: u8,        // 1 byte - FEC_VERSION, not sent in the legacy header
+    pub block_seq: u64,     // 8 bytes - sequence number of the fec block, legacy: the raptorq block id
+    pub block_size: u32,    // 4 bytes - the total size of the current fec block in bytes, legacy: 2 bytes
     pub packet_size: u16,    // 2 bytes - the size of the wifi packet in bytes
 }
 
 impl FecHeader {
-    pub fn new(block_size: u16, packet_size: u16) -> Self {
+    pub fn new(version: u8, block_seq: u64, block_size: u32, packet_size: u16) -> Self {
         Self {
+            version,
+            block_seq,
             block_size,
             packet_size,
         }
     }
 
-    pub fn to_bytes(&self) -> [u8; FEC_HEADER_SIZE] {
-        let mut bytes = [0u8; FEC_HEADER_SIZE];
-        bytes[0..2].copy_from_slice(&self.block_size.to_le_bytes());
-        bytes[2..4].copy_from_slice(&self.packet_size.to_le_bytes());
+    pub fn to_bytes(&self) -> Vec<u8> {
+        if self.version == FEC_VERSION_LEGACY {
+            let mut bytes = vec![0u8; FEC_HEADER_SIZE_LEGACY];
+            bytes[0..2].copy_from_slice(&(self.block_size as u16).to_le_bytes());
+            bytes[2..4].copy_from_slice(&self.packet_size.to_le_bytes());
+            return bytes;
+        }
+
+        let mut bytes = vec![0u8; FEC_HEADER_SIZE];
+        bytes[0] = self.version;
+        bytes[1..9].copy_from_slice(&self.block_seq.to_le_bytes());
+        bytes[9..13].copy_from_slice(&self.block_size.to_le_bytes());
+        bytes[13..15].copy_from_slice(&self.packet_size.to_le_bytes());
         bytes
     }
 
+    // Returns None for unknown versions
     #[cfg(feature = "receiver")]
     pub fn from_bytes(bytes: &[u8]) -> Option<(Self, &[u8])> {
-        if bytes.len() < FEC_HEADER_SIZE {
+        if bytes.len() < FEC_HEADER_SIZE || bytes[0] != FEC_VERSION {
             return None;
         }
 
-        let block_size = u16::from_le_bytes(bytes[0..2].try_into().unwrap());
-        let packet_size = u16::from_le_bytes(bytes[2..4].try_into().unwrap());
+        let block_seq = u64::from_le_bytes(bytes[1..9].try_into().unwrap());
+        let block_size = u32::from_le_bytes(bytes[9..13].try_into().unwrap());
+        let packet_size = u16::from_le_bytes(bytes[13..15].try_into().unwrap());
 
         Some((Self {
+            version: bytes[0],
+            block_seq,
             block_size,
             packet_size
         }, &bytes[FEC_HEADER_SIZE..]))
     }
+
+    // The block id of the legacy header is the first byte of the raptorq payload id that follows
+    #[cfg(feature = "receiver")]
+    pub fn from_legacy_bytes(bytes: &[u8]) -> Option<(Self, &[u8])> {
+        if bytes.len() <= FEC_HEADER_SIZE_LEGACY {
+            return None;
+        }
+
+        let block_size = u16::from_le_bytes(bytes[0..2].try_into().unwrap());
+        let packet_size = u16::from_le_bytes(bytes[2..4].try_into().unwrap());
+
+        Some((Self {
+            version: FEC_VERSION_LEGACY,
+            block_seq: bytes[FEC_HEADER_SIZE_LEGACY] as u64,
+            block_size: block_size as u32,
+            packet_size
+        }, &bytes[FEC_HEADER_SIZE_LEGACY..]))
+    }
+}
+
+// Udp packets that start in the source symbol of a fec packet, so they can be forwarded
+// without decoding the block. Repair packets carry an empty table, legacy packets none at all.
+// Tables are cut to fit MAX_FEC_PACKET_SIZE, the packets left out only arrive with the decoded block.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PacketTable {
+    pub packets: Vec<(u32, u32)>,   // 2 + 8 * n bytes - count, then offset in the block and length of each packet
+}
+
+impl PacketTable {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = (self.packets.len() as u16).to_le_bytes().to_vec();
+        for (offset, len) in &self.packets {
+            bytes.extend_from_slice(&offset.to_le_bytes());
+            bytes.extend_from_slice(&len.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[cfg(feature = "receiver")]
+    pub fn from_bytes(bytes: &[u8]) -> Option<(Self, &[u8])> {
+        if bytes.len() < size_of::<u16>() {
+            return None;
+        }
+        let count = u16::from_le_bytes(bytes[0..2].try_into().unwrap()) as usize;
+        let end = size_of::<u16>() + count * 2 * size_of::<u32>();
+        if bytes.len() < end {
+            return None;
+        }
+
+        let packets = bytes[size_of::<u16>()..end]
+            .chunks(2 * size_of::<u32>())
+            .map(|b| (u32::from_le_bytes(b[0..4].try_into().unwrap()), u32::from_le_bytes(b[4..8].try_into().unwrap())))
+            .collect();
+
+        Some((Self { packets }, &bytes[end..]))
+    }
 }
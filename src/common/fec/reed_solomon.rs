@@ -0,0 +1,259 @@
+// Systematic Reed-Solomon erasure code over GF(2^8), built exactly like zfec's fec_new(k, n)
+// (which wfb-ng uses through zfex), so the parity fragments are bit-identical to wfb-ng.
+
+// x^8 + x^4 + x^3 + x^2 + 1, zfec's "101110001"
+const GF_POLY: u16 = 0x11d;
+
+const fn gf_tables() -> ([u8; 510], [u8; 256]) {
+    let mut exp = [0u8; 510];
+    let mut log = [0u8; 256];
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        exp[i + 255] = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= GF_POLY;
+        }
+        i += 1;
+    }
+    (exp, log)
+}
+
+const GF_EXP: [u8; 510] = gf_tables().0;
+const GF_LOG: [u8; 256] = gf_tables().1;
+
+fn gf_mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    GF_EXP[GF_LOG[a as usize] as usize + GF_LOG[b as usize] as usize]
+}
+
+fn gf_inv(a: u8) -> u8 {
+    GF_EXP[255 - GF_LOG[a as usize] as usize]
+}
+
+// dst += c * src
+fn gf_addmul(dst: &mut [u8], src: &[u8], c: u8) {
+    if c == 0 {
+        return;
+    }
+    for (d, s) in dst.iter_mut().zip(src) {
+        *d ^= gf_mul(c, *s);
+    }
+}
+
+// Gauss-Jordan inversion of a k*k matrix, None if it is singular
+fn invert_matrix(matrix: &[u8], k: usize) -> Option<Vec<u8>> {
+    let mut m = matrix.to_vec();
+    let mut inv = vec![0u8; k * k];
+    for i in 0..k {
+        inv[i * k + i] = 1;
+    }
+
+    for col in 0..k {
+        let pivot = (col..k).find(|&row| m[row * k + col] != 0)?;
+        if pivot != col {
+            for c in 0..k {
+                m.swap(pivot * k + c, col * k + c);
+                inv.swap(pivot * k + c, col * k + c);
+            }
+        }
+
+        let scale = gf_inv(m[col * k + col]);
+        for c in 0..k {
+            m[col * k + c] = gf_mul(m[col * k + c], scale);
+            inv[col * k + c] = gf_mul(inv[col * k + c], scale);
+        }
+
+        for row in (0..k).filter(|&row| row != col) {
+            let factor = m[row * k + col];
+            if factor == 0 {
+                continue;
+            }
+            for c in 0..k {
+                m[row * k + c] ^= gf_mul(factor, m[col * k + c]);
+                inv[row * k + c] ^= gf_mul(factor, inv[col * k + c]);
+            }
+        }
+    }
+    Some(inv)
+}
+
+pub struct ReedSolomon {
+    k: usize,
+    n: usize,
+    enc_matrix: Vec<u8>, // n rows of k coefficients, the top k rows are the identity
+}
+
+impl ReedSolomon {
+    pub fn new(k: u8, n: u8) -> Result<Self, Box<dyn std::error::Error>> {
+        let (k, n) = (k as usize, n as usize);
+        if k < 1 || k > n {
+            return Err(format!("Invalid Reed-Solomon parameters k={} n={}", k, n).into());
+        }
+
+        // vandermonde matrix with the evaluation points 0, 1, a, a^2, ...
+        let mut vandermonde = vec![0u8; n * k];
+        vandermonde[0] = 1;
+        for row in 1..n {
+            for col in 0..k {
+                vandermonde[row * k + col] = GF_EXP[((row - 1) * col) % 255];
+            }
+        }
+
+        // make it systematic: multiply with the inverse of its top k*k part
+        let top_inv = invert_matrix(&vandermonde[..k * k], k).ok_or("Singular Reed-Solomon matrix")?;
+        let mut enc_matrix = vec![0u8; n * k];
+        for row in 0..n {
+            for col in 0..k {
+                enc_matrix[row * k + col] = (0..k)
+                    .map(|i| gf_mul(vandermonde[row * k + i], top_inv[i * k + col]))
+                    .fold(0, |acc, v| acc ^ v);
+            }
+        }
+
+        Ok(Self { k, n, enc_matrix })
+    }
+
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    pub fn n(&self) -> usize {
+        self.n
+    }
+
+    // Computes the n-k parity fragments of size `size`, shorter data fragments count as zero padded
+    pub fn encode(&self, data: &[&[u8]], size: usize) -> Vec<Vec<u8>> {
+        (self.k..self.n)
+            .map(|row| {
+                let mut parity = vec![0u8; size];
+                for (j, fragment) in data.iter().enumerate() {
+                    let len = fragment.len().min(size);
+                    gf_addmul(&mut parity[..len], &fragment[..len], self.enc_matrix[row * self.k + j]);
+                }
+                parity
+            })
+            .collect()
+    }
+
+    // Restores the missing data fragments from any k received fragments, indexed by fragment index.
+    // Fragments are zero padded to `size`. Returns None if less than k fragments are present.
+    #[cfg(feature = "receiver")]
+    pub fn reconstruct(&self, fragments: &mut [Option<Vec<u8>>], size: usize) -> Option<()> {
+        if fragments.len() != self.n {
+            return None;
+        }
+        let missing: Vec<usize> = (0..self.k).filter(|&i| fragments[i].is_none()).collect();
+        if missing.is_empty() {
+            return Some(());
+        }
+
+        let used: Vec<usize> = (0..self.n).filter(|&i| fragments[i].is_some()).take(self.k).collect();
+        if used.len() < self.k {
+            return None;
+        }
+
+        let mut sub_matrix = Vec::with_capacity(self.k * self.k);
+        for &index in &used {
+            sub_matrix.extend_from_slice(&self.enc_matrix[index * self.k..(index + 1) * self.k]);
+        }
+        let decode_matrix = invert_matrix(&sub_matrix, self.k)?;
+
+        for &index in &missing {
+            let mut restored = vec![0u8; size];
+            for (j, &used_index) in used.iter().enumerate() {
+                let fragment = fragments[used_index].as_ref().unwrap();
+                let len = fragment.len().min(size);
+                gf_addmul(&mut restored[..len], &fragment[..len], decode_matrix[index * self.k + j]);
+            }
+            fragments[index] = Some(restored);
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::from_hex;
+
+    // Known answers of zfec's fec_new(8, 12) and fec_encode, the defaults of wfb-ng
+    const PARITY_ROWS: [&str; 4] = ["8918d07d92a4f5fe", "36f8d0ce2519fb16", "5fcda3405048f69e", "ed912490dc9057d2"];
+    const FRAGMENTS: [&str; 8] = [
+        "000003000102",
+        "0000050708090a0b",
+        "0000070e0f1011121314",
+        "00000915161718191a1b1c1d",
+        "00000b1c1d1e1f20212223242526",
+        "00000d232425262728292a2b2c2d2e2f",
+        "00000f2a2b2c2d2e2f303132333435363738",
+        "0000113132333435363738393a3b3c3d3e3f4041",
+    ];
+    const PARITY: [&str; 4] = [
+        "0000e11461c7c7c81d7a07fb00003860a155718f",
+        "00004b6b28baeadea45bcae0d863dbc485c3e9ff",
+        "0000de57e887b4bc35dd536f805ea27350d554ca",
+        "0000ddfa8596da4f5723e2e73bb8368d658dbe6c",
+    ];
+
+    #[test]
+    fn matrix_matches_zfec() {
+        let rs = ReedSolomon::new(8, 12).unwrap();
+        for row in 0..8 {
+            let identity: Vec<u8> = (0..8).map(|col| (row == col) as u8).collect();
+            assert_eq!(rs.enc_matrix[row * 8..(row + 1) * 8], identity);
+        }
+        for (i, expected) in PARITY_ROWS.iter().enumerate() {
+            assert_eq!(rs.enc_matrix[(8 + i) * 8..(9 + i) * 8], from_hex(expected));
+        }
+    }
+
+    #[test]
+    fn parity_matches_zfec() {
+        let rs = ReedSolomon::new(8, 12).unwrap();
+        let fragments: Vec<Vec<u8>> = FRAGMENTS.iter().map(|f| from_hex(f)).collect();
+        let data: Vec<&[u8]> = fragments.iter().map(|f| &f[..]).collect();
+        let parity: Vec<Vec<u8>> = PARITY.iter().map(|p| from_hex(p)).collect();
+        assert_eq!(rs.encode(&data, 20), parity);
+    }
+
+    #[test]
+    fn rejects_invalid_parameters() {
+        assert!(ReedSolomon::new(0, 4).is_err());
+        assert!(ReedSolomon::new(5, 4).is_err());
+        assert!(ReedSolomon::new(1, 1).is_ok());
+    }
+
+    #[cfg(feature = "receiver")]
+    #[test]
+    fn reconstructs_from_any_k_fragments() {
+        let rs = ReedSolomon::new(8, 12).unwrap();
+        let all: Vec<Vec<u8>> = FRAGMENTS.iter().chain(&PARITY).map(|f| {
+            let mut fragment = from_hex(f);
+            fragment.resize(20, 0);
+            fragment
+        }).collect();
+
+        for lost in [[0, 1, 2, 3], [4, 9, 10, 11], [7, 0, 8, 5]] {
+            let mut fragments: Vec<Option<Vec<u8>>> = all.iter().cloned().map(Some).collect();
+            for i in lost {
+                fragments[i] = None;
+            }
+            rs.reconstruct(&mut fragments, 20).unwrap();
+            for i in 0..8 {
+                assert_eq!(fragments[i].as_ref(), Some(&all[i]));
+            }
+        }
+
+        let mut fragments: Vec<Option<Vec<u8>>> = all.iter().cloned().map(Some).collect();
+        for fragment in &mut fragments[..5] {
+            *fragment = None;
+        }
+        assert!(rs.reconstruct(&mut fragments, 20).is_none());
+    }
+}
@@ -0,0 +1,55 @@
+// Link report of the receiver, sent back to the transmitter over a return channel (plain udp, e.g. a
+// wfb link in the opposite direction) so it can adapt the number of repair packets and its rate
+use std::mem::size_of;
+use std::time::Duration;
+
+// How often the receiver reports, whether or not anything was decoded
+pub const FEEDBACK_INTERVAL: Duration = Duration::from_millis(250);
+
+// marks feedback datagrams ("WFBF")
+const FEEDBACK_MAGIC: u32 = 0x5746_4246;
+const FEEDBACK_SIZE: usize = size_of::<u32>() * 6;
+// reports of older receivers end before the rssi
+const FEEDBACK_SIZE_NO_RSSI: usize = size_of::<u32>() * 5;
+const RSSI_UNKNOWN: i8 = i8::MIN;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FecFeedback {
+    pub channel_id: u32,        // 4 bytes - (link_id << 8) | radio_port of the reported stream
+    pub decoded_blocks: u32,    // 4 bytes - blocks decoded since the last report
+    pub lost_blocks: u32,       // 4 bytes - blocks given up since the last report
+    pub max_repair_used: u32,   // 4 bytes - most repair packets a single block needed to decode
+    pub rssi: Option<i8>,       // 1 byte + 3 padding - average signal of the best antenna per frame in dBm
+}
+
+impl FecFeedback {
+    #[cfg(feature = "receiver")]
+    pub fn to_bytes(&self) -> [u8; FEEDBACK_SIZE] {
+        let mut bytes = [0u8; FEEDBACK_SIZE];
+        bytes[0..4].copy_from_slice(&FEEDBACK_MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.channel_id.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.decoded_blocks.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.lost_blocks.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.max_repair_used.to_le_bytes());
+        bytes[20] = self.rssi.unwrap_or(RSSI_UNKNOWN) as u8;
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < FEEDBACK_SIZE_NO_RSSI || u32::from_le_bytes(bytes[0..4].try_into().unwrap()) != FEEDBACK_MAGIC {
+            return None;
+        }
+
+        Some(Self {
+            channel_id: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            decoded_blocks: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            lost_blocks: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+            max_repair_used: u32::from_le_bytes(bytes[16..20].try_into().unwrap()),
+            rssi: if bytes.len() >= FEEDBACK_SIZE {
+                Some(bytes[20] as i8).filter(|&rssi| rssi != RSSI_UNKNOWN)
+            } else {
+                None
+            },
+        })
+    }
+}
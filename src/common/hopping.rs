@@ -0,0 +1,366 @@
+// Frequency hopping: air and ground follow the same keyed pseudo-random sequence of channels and
+// move on to the next one every dwell time. The transmitter keeps the receivers in step with sync
+// packets, a receiver that lost track scans the channels until it hears one again.
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use chacha20::cipher::consts::U10;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::{hchacha, ChaCha20Legacy};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+
+use super::bandwidth::Bandwidth;
+use super::channel::{Channel, Ht40};
+use super::crypto::{Key, KEY_SIZE};
+use super::keypair::Keypair;
+use super::utils;
+
+// Retuning takes a few ms, shorter dwell times would mostly be spent on it
+pub const MIN_DWELL: Duration = Duration::from_millis(20);
+// Sync packets per dwell time, the first one right after the hop
+const SYNCS_PER_DWELL: u32 = 4;
+// A receiver that heard no sync packet for this many dwell times starts scanning
+#[cfg(feature = "receiver")]
+const LOST_SYNC_DWELLS: u32 = 3;
+// A scanning receiver waits this many rounds of the schedule on every channel
+#[cfg(feature = "receiver")]
+const SCAN_ROUNDS: u32 = 2;
+const SYNC_DATA_SIZE: usize = 16;
+// Domain of the hopping key, no other key is derived from the shared secret with it
+const HOP_KEY_LABEL: [u8; 16] = *b"wfb_rs hop key\0\0";
+
+// Hopping settings of the wifi setup
+#[derive(Debug, Clone)]
+pub struct Hopping {
+    pub frequencies: Vec<u32>,  // MHz, primary channels to hop between
+    pub dwell: Duration,
+}
+
+// Keyed sequence of channels, the same on both ends
+pub struct HopSchedule {
+    channels: Vec<Channel>,
+    dwell: Duration,
+    key: Key,
+}
+
+impl HopSchedule {
+    // The channels are as wide as the bandwidth. The keypair keys the sequence and authenticates the
+    // sync packets, without it anyone could predict the channels and move the receivers.
+    pub fn new(
+        hopping: &Hopping,
+        bandwidth: Bandwidth,
+        ht40: Option<Ht40>,
+        keypair: &Keypair,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if hopping.frequencies.len() < 2 {
+            return Err("Frequency hopping needs at least two channels".into());
+        }
+        if hopping.dwell < MIN_DWELL {
+            return Err(format!("The dwell time has to be at least {} ms", MIN_DWELL.as_millis()).into());
+        }
+
+        let channels = hopping.frequencies
+            .iter()
+            .map(|&frequency| Channel::new(frequency, bandwidth, ht40))
+            .collect::<Result<_, _>>()?;
+        let key = Self::derive_key(keypair)?;
+
+        Ok(Self { channels, dwell: hopping.dwell, key })
+    }
+
+    // Both ends of a keypair share its x25519 secret, HChaCha20 keyed with it turns the label into the hopping key
+    fn derive_key(keypair: &Keypair) -> Result<Key, Box<dyn std::error::Error>> {
+        let shared_secret = MontgomeryPoint(keypair.peer_public_key).mul_clamped(keypair.secret_key).to_bytes();
+        // a low order public key would make it known to everyone
+        if shared_secret == [0u8; KEY_SIZE] {
+            return Err("Invalid peer public key".into());
+        }
+        Ok(hchacha::<U10>(&shared_secret.into(), &HOP_KEY_LABEL.into()).into())
+    }
+
+    // Channel of the given hop, every hop draws anew
+    pub fn channel(&self, hop: u64) -> Channel {
+        let mut draw = [0u8; 4];
+        ChaCha20Legacy::new((&self.key).into(), (&hop.to_le_bytes()).into()).apply_keystream(&mut draw);
+        self.channels[(u32::from_le_bytes(draw) % self.channels.len() as u32) as usize]
+    }
+
+    pub fn dwell(&self) -> Duration {
+        self.dwell
+    }
+}
+
+// Where the transmitter is in the sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncData {
+    pub hop: u64,               // 8 bytes - hop the transmitter is on
+    pub next_hop: Duration,     // 4 bytes - time until it moves on, in µs
+    pub dwell: Duration,        // 4 bytes - dwell time of its schedule, in ms
+}
+
+impl SyncData {
+    pub fn to_bytes(&self) -> [u8; SYNC_DATA_SIZE] {
+        let mut bytes = [0u8; SYNC_DATA_SIZE];
+        bytes[0..8].copy_from_slice(&self.hop.to_le_bytes());
+        bytes[8..12].copy_from_slice(&(self.next_hop.as_micros() as u32).to_le_bytes());
+        bytes[12..16].copy_from_slice(&(self.dwell.as_millis() as u32).to_le_bytes());
+        bytes
+    }
+
+    #[cfg(feature = "receiver")]
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < SYNC_DATA_SIZE {
+            return None;
+        }
+
+        Some(Self {
+            hop: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            next_hop: Duration::from_micros(u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as u64),
+            dwell: Duration::from_millis(u32::from_le_bytes(bytes[12..16].try_into().unwrap()) as u64),
+        })
+    }
+}
+
+// Hopping of the transmitter. It follows the wall clock, so the hop numbers keep increasing across
+// restarts and receivers don't take a restarted transmitter for an old sync packet.
+pub struct Hopper {
+    schedule: HopSchedule,
+    interface: String,
+    hop: u64,
+    next_hop: Instant,
+    next_sync: Instant,
+    channel: Channel,
+}
+
+impl Hopper {
+    pub fn start(schedule: HopSchedule, interface: String) -> Result<Self, Box<dyn std::error::Error>> {
+        let (hop, next_hop) = Self::clock_hop(schedule.dwell);
+        let channel = schedule.channel(hop);
+        utils::set_channel(&interface, &channel)?;
+
+        Ok(Self { schedule, interface, hop, next_hop, next_sync: Instant::now(), channel })
+    }
+
+    // Hop of the wall clock and when it ends
+    fn clock_hop(dwell: Duration) -> (u64, Instant) {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros();
+        let dwell = dwell.as_micros();
+        let remaining = dwell - since_epoch % dwell;
+        ((since_epoch / dwell) as u64, Instant::now() + Duration::from_micros(remaining as u64))
+    }
+
+    pub fn channel(&self) -> Channel {
+        self.channel
+    }
+
+    // When the next hop or sync packet is due
+    pub fn next_event(&self) -> Instant {
+        self.next_hop.min(self.next_sync)
+    }
+
+    // Hops if the dwell time is over, returns the content of a sync packet if one is due
+    pub fn poll(&mut self) -> Option<SyncData> {
+        let now = Instant::now();
+        if now >= self.next_hop {
+            // hops that were missed while busy are skipped
+            (self.hop, self.next_hop) = Self::clock_hop(self.schedule.dwell);
+            retune(&[&self.interface], self.schedule.channel(self.hop), &mut self.channel);
+            self.next_sync = now;
+        }
+
+        if now < self.next_sync {
+            return None;
+        }
+        self.next_sync = now + self.schedule.dwell / SYNCS_PER_DWELL;
+        Some(SyncData {
+            hop: self.hop,
+            next_hop: self.next_hop.saturating_duration_since(now),
+            dwell: self.schedule.dwell,
+        })
+    }
+}
+
+// Hopping of the receiver, it follows the sync packets of the transmitter
+#[cfg(feature = "receiver")]
+pub struct HopFollower {
+    schedule: HopSchedule,
+    interfaces: Vec<String>,
+    hop: u64,
+    next_hop: Instant,
+    last_sync: Option<Instant>,
+    scanning: bool,
+    scan_index: usize,
+    channel: Channel,
+    foreign_schedule: bool,
+}
+
+#[cfg(feature = "receiver")]
+impl HopFollower {
+    // Scans until the first sync packet arrives
+    pub fn new(schedule: HopSchedule, interfaces: Vec<String>) -> Result<Self, Box<dyn std::error::Error>> {
+        let channel = schedule.channels[0];
+        for interface in &interfaces {
+            utils::set_channel(interface, &channel)?;
+        }
+
+        Ok(Self {
+            next_hop: Instant::now() + Self::scan_dwell(&schedule),
+            schedule,
+            interfaces,
+            hop: 0,
+            last_sync: None,
+            scanning: true,
+            scan_index: 0,
+            channel,
+            foreign_schedule: false,
+        })
+    }
+
+    // The transmitter visits a channel about once per round of the schedule
+    fn scan_dwell(schedule: &HopSchedule) -> Duration {
+        schedule.dwell * schedule.channels.len() as u32 * SCAN_ROUNDS
+    }
+
+    pub fn channel(&self) -> Channel {
+        self.channel
+    }
+
+    pub fn next_hop(&self) -> Instant {
+        self.next_hop
+    }
+
+    fn synced(&self) -> bool {
+        self.last_sync.is_some_and(|t| t.elapsed() < self.schedule.dwell * LOST_SYNC_DWELLS)
+    }
+
+    pub fn process_sync(&mut self, sync: &SyncData) {
+        if sync.dwell != self.schedule.dwell {
+            if !self.foreign_schedule {
+                eprintln!("Sync packet with a dwell time of {} ms, ours is {} ms", sync.dwell.as_millis(), self.schedule.dwell.as_millis());
+                self.foreign_schedule = true;
+            }
+            return;
+        }
+        // older than the hop we are on, delayed or replayed
+        if self.synced() && sync.hop < self.hop {
+            return;
+        }
+
+        let now = Instant::now();
+        self.hop = sync.hop;
+        self.next_hop = now + sync.next_hop;
+        self.last_sync = Some(now);
+        let interfaces: Vec<&str> = self.interfaces.iter().map(String::as_str).collect();
+        retune(&interfaces, self.schedule.channel(self.hop), &mut self.channel);
+        if self.scanning {
+            println!("Hopping in sync with the transmitter, on {}", self.channel);
+            self.scanning = false;
+        }
+    }
+
+    // Hops along when the dwell time is over, scans the next channel while there is no sync
+    pub fn poll(&mut self) {
+        let now = Instant::now();
+        if now < self.next_hop {
+            return;
+        }
+
+        let next_channel = if self.synced() {
+            // hops that were missed while busy are skipped
+            let dwell = self.schedule.dwell;
+            let hops = ((now - self.next_hop).as_micros() / dwell.as_micros()) as u32 + 1;
+            self.hop += hops as u64;
+            self.next_hop += dwell * hops;
+            self.schedule.channel(self.hop)
+        } else {
+            if !self.scanning {
+                println!("Lost the hopping sync, scanning");
+                self.scanning = true;
+            }
+            self.scan_index = (self.scan_index + 1) % self.schedule.channels.len();
+            self.next_hop = now + Self::scan_dwell(&self.schedule);
+            self.schedule.channels[self.scan_index]
+        };
+        let interfaces: Vec<&str> = self.interfaces.iter().map(String::as_str).collect();
+        retune(&interfaces, next_channel, &mut self.channel);
+    }
+}
+
+// Errors are only logged, the next hop tries again
+fn retune(interfaces: &[&str], channel: Channel, current: &mut Channel) {
+    if channel == *current {
+        return;
+    }
+    for interface in interfaces {
+        if let Err(e) = utils::set_channel(interface, &channel) {
+            eprintln!("Error hopping to {}: {}", channel, e);
+        }
+    }
+    *current = channel;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::from_hex;
+    use crate::common::keypair::generate_keypairs;
+
+    fn hopping() -> Hopping {
+        Hopping { frequencies: vec![5745, 5785, 5825], dwell: Duration::from_millis(100) }
+    }
+
+    fn keypair(secret: &str, peer_public: &str) -> Keypair {
+        Keypair {
+            key_id: 0,
+            role: None,
+            secret_key: from_hex(secret).try_into().unwrap(),
+            peer_public_key: from_hex(peer_public).try_into().unwrap(),
+        }
+    }
+
+    // HChaCha20 of the label, keyed with the x25519 secret of the keys of libsodium's crypto_box test
+    #[test]
+    fn hop_key_known_answer() {
+        let drone = keypair(
+            "68f208412d8dd5db9d0c6d18512e86f0ec75665ab841372d57b042b27ef89d4c",
+            "e8980c86e032f1eb2975052e8d65bddd15c3b59641174ec9678a53789d92c754",
+        );
+        let ground_station = keypair(
+            "b581fb5ae182a16f603f39270d4e3b95bc008310b727a11dd4e784a0044d461b",
+            "ac3a70ba35df3c3fae427a7c72021d68f2c1e044040b75f17313c0c8b5d4241d",
+        );
+        let expected = from_hex("1a5937350831e1486e6833696fcb462bdf6d19f7a926594332079508e3cda928");
+        assert_eq!(HopSchedule::derive_key(&drone).unwrap().to_vec(), expected);
+        assert_eq!(HopSchedule::derive_key(&ground_station).unwrap().to_vec(), expected);
+    }
+
+    #[test]
+    fn rejects_low_order_public_keys() {
+        let (mut drone, _) = generate_keypairs(1).unwrap();
+        drone.peer_public_key = [0; KEY_SIZE];
+        assert!(HopSchedule::derive_key(&drone).is_err());
+    }
+
+    #[test]
+    fn both_ends_follow_the_same_channels() {
+        let (drone, ground_station) = generate_keypairs(1).unwrap();
+        let tx = HopSchedule::new(&hopping(), Bandwidth::Bw20, None, &drone).unwrap();
+        let rx = HopSchedule::new(&hopping(), Bandwidth::Bw20, None, &ground_station).unwrap();
+        let channels: Vec<u32> = (0..100).map(|hop| tx.channel(hop).frequency).collect();
+        assert_eq!(channels, (0..100).map(|hop| rx.channel(hop).frequency).collect::<Vec<_>>());
+        assert!(hopping().frequencies.iter().all(|frequency| channels.contains(frequency)));
+
+        // another keypair draws another sequence
+        let (other, _) = generate_keypairs(2).unwrap();
+        let other = HopSchedule::new(&hopping(), Bandwidth::Bw20, None, &other).unwrap();
+        assert_ne!(channels, (0..100).map(|hop| other.channel(hop).frequency).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let (drone, _) = generate_keypairs(1).unwrap();
+        let one_channel = Hopping { frequencies: vec![5745], ..hopping() };
+        assert!(HopSchedule::new(&one_channel, Bandwidth::Bw20, None, &drone).is_err());
+        let short_dwell = Hopping { dwell: MIN_DWELL - Duration::from_millis(1), ..hopping() };
+        assert!(HopSchedule::new(&short_dwell, Bandwidth::Bw20, None, &drone).is_err());
+    }
+}
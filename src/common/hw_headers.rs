@@ -47,6 +47,35 @@ pub fn get_ieee80211_header(frame_type: u8, channel_id: u32, ieee_seq: u16) -> [
     ieee_header
 }
 
+// Rejects the settings get_radiotap_headers can't encode
+pub fn check_radio_settings(
+    stbc: u8,
+    bandwidth: Bandwidth,
+    mcs_index: u8,
+    vht_mode: bool,
+    vht_nss: u8,
+) -> Result<(), String> {
+    if !vht_mode {
+        if matches!(bandwidth, Bandwidth::Bw80 | Bandwidth::Bw160) {
+            return Err(format!("{} MHz needs vht mode", bandwidth));
+        }
+        if stbc > 3 {
+            return Err(format!("Invalid HT STBC value {}", stbc));
+        }
+        if mcs_index > 31 {
+            return Err(format!("Invalid HT MCS index {}", mcs_index));
+        }
+    } else {
+        if mcs_index > 9 {
+            return Err(format!("Invalid VHT MCS index {}", mcs_index));
+        }
+        if !(1..=8).contains(&vht_nss) {
+            return Err(format!("Invalid VHT NSS {}", vht_nss));
+        }
+    }
+    Ok(())
+}
+
 pub fn get_radiotap_headers(
     stbc: u8,
     ldpc: bool,
@@ -0,0 +1,243 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+
+use curve25519_dalek::montgomery::MontgomeryPoint;
+
+use super::crypto::{Key, KEY_SIZE};
+
+// On-disk keypair format, all integers little endian:
+//
+//  offset  size  field
+//       0     8  magic "WFBRSKEY"
+//       8     1  format version (KEYPAIR_VERSION)
+//       9     1  role, 0 = drone (transmitter), 1 = ground station (receiver)
+//      10     2  reserved, zero
+//      12     4  key id, chosen at generation time to tell keys apart when rotating a fleet
+//      16    32  own x25519 secret key
+//      48    32  x25519 public key of the peer
+//
+// Files of 64 bytes are read as wfb-ng's drone.key/gs.key (own secret key followed by the
+// peer public key), so keys generated by wfb_keygen can be used directly.
+const KEYPAIR_MAGIC: &[u8; 8] = b"WFBRSKEY";
+pub const KEYPAIR_VERSION: u8 = 1;
+const KEYPAIR_SIZE: usize = 80;
+const WFB_NG_KEYPAIR_SIZE: usize = 2 * KEY_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyRole {
+    Drone,
+    GroundStation,
+}
+
+impl KeyRole {
+    fn to_byte(self) -> u8 {
+        match self {
+            KeyRole::Drone => 0,
+            KeyRole::GroundStation => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(KeyRole::Drone),
+            1 => Some(KeyRole::GroundStation),
+            _ => None,
+        }
+    }
+}
+
+pub struct Keypair {
+    pub key_id: u32,
+    pub role: Option<KeyRole>, // None for wfb-ng key files
+    pub secret_key: Key,
+    pub peer_public_key: Key,
+}
+
+impl Keypair {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(KEYPAIR_SIZE);
+        bytes.extend_from_slice(KEYPAIR_MAGIC);
+        bytes.push(KEYPAIR_VERSION);
+        bytes.push(self.role.unwrap_or(KeyRole::Drone).to_byte());
+        bytes.extend_from_slice(&[0, 0]);
+        bytes.extend_from_slice(&self.key_id.to_le_bytes());
+        bytes.extend_from_slice(&self.secret_key);
+        bytes.extend_from_slice(&self.peer_public_key);
+        bytes
+    }
+
+    // wfb-ng layout, loses key id and role
+    pub fn to_wfb_ng_bytes(&self) -> Vec<u8> {
+        [&self.secret_key[..], &self.peer_public_key[..]].concat()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        if bytes.len() == WFB_NG_KEYPAIR_SIZE {
+            return Ok(Self {
+                key_id: 0,
+                role: None,
+                secret_key: bytes[0..32].try_into().unwrap(),
+                peer_public_key: bytes[32..64].try_into().unwrap(),
+            });
+        }
+
+        if bytes.len() < 9 || &bytes[0..8] != KEYPAIR_MAGIC {
+            return Err("Not a wfb_rs or wfb-ng key file".into());
+        }
+        if bytes[8] != KEYPAIR_VERSION {
+            return Err(format!("Unsupported key file version {}", bytes[8]).into());
+        }
+        if bytes.len() != KEYPAIR_SIZE {
+            return Err("Key file is truncated".into());
+        }
+
+        let role = KeyRole::from_byte(bytes[9]).ok_or("Invalid key role")?;
+
+        Ok(Self {
+            key_id: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+            role: Some(role),
+            secret_key: bytes[16..48].try_into().unwrap(),
+            peer_public_key: bytes[48..80].try_into().unwrap(),
+        })
+    }
+
+    // Loads a keypair and checks that it was generated for this side of the link
+    pub fn load(path: &str, role: KeyRole) -> Result<Self, Box<dyn std::error::Error>> {
+        let bytes = fs::read(path).map_err(|e| format!("Could not read key file {}: {}", path, e))?;
+        let keypair = Self::from_bytes(&bytes).map_err(|e| format!("{}: {}", path, e))?;
+
+        if keypair.role.is_some_and(|r| r != role) {
+            return Err(format!("{} is not a {:?} key", path, role).into());
+        }
+        Ok(keypair)
+    }
+
+    pub fn save(&self, path: &str, wfb_ng_format: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let bytes = if wfb_ng_format { self.to_wfb_ng_bytes() } else { self.to_bytes() };
+        // the file contains a secret key, so only the owner may read it
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .and_then(|mut file| file.write_all(&bytes))
+            .map_err(|e| format!("Could not write key file {}: {}", path, e))?;
+        Ok(())
+    }
+}
+
+// Generates a matching pair of drone and ground station keys
+pub fn generate_keypairs(key_id: u32) -> Result<(Keypair, Keypair), Box<dyn std::error::Error>> {
+    let mut drone_secret_key = [0u8; KEY_SIZE];
+    let mut gs_secret_key = [0u8; KEY_SIZE];
+    getrandom::getrandom(&mut drone_secret_key)?;
+    getrandom::getrandom(&mut gs_secret_key)?;
+
+    let drone_public_key = MontgomeryPoint::mul_base_clamped(drone_secret_key).to_bytes();
+    let gs_public_key = MontgomeryPoint::mul_base_clamped(gs_secret_key).to_bytes();
+
+    let drone = Keypair {
+        key_id,
+        role: Some(KeyRole::Drone),
+        secret_key: drone_secret_key,
+        peer_public_key: gs_public_key,
+    };
+    let ground_station = Keypair {
+        key_id,
+        role: Some(KeyRole::GroundStation),
+        secret_key: gs_secret_key,
+        peer_public_key: drone_public_key,
+    };
+    Ok((drone, ground_station))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keypair() -> Keypair {
+        Keypair {
+            key_id: 0x0403_0201,
+            role: Some(KeyRole::GroundStation),
+            secret_key: [0x11; KEY_SIZE],
+            peer_public_key: [0x22; KEY_SIZE],
+        }
+    }
+
+    #[test]
+    fn layout() {
+        let bytes = keypair().to_bytes();
+        assert_eq!(bytes.len(), KEYPAIR_SIZE);
+        assert_eq!(&bytes[0..8], b"WFBRSKEY");
+        assert_eq!(bytes[8..16], [KEYPAIR_VERSION, 1, 0, 0, 0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(bytes[16..48], [0x11; KEY_SIZE]);
+        assert_eq!(bytes[48..80], [0x22; KEY_SIZE]);
+    }
+
+    #[test]
+    fn round_trip() {
+        let parsed = Keypair::from_bytes(&keypair().to_bytes()).unwrap();
+        assert_eq!(parsed.key_id, 0x0403_0201);
+        assert_eq!(parsed.role, Some(KeyRole::GroundStation));
+        assert_eq!(parsed.secret_key, [0x11; KEY_SIZE]);
+        assert_eq!(parsed.peer_public_key, [0x22; KEY_SIZE]);
+    }
+
+    #[test]
+    fn wfb_ng_layout() {
+        let bytes = keypair().to_wfb_ng_bytes();
+        assert_eq!(bytes, [[0x11; KEY_SIZE], [0x22; KEY_SIZE]].concat());
+
+        let parsed = Keypair::from_bytes(&bytes).unwrap();
+        assert_eq!((parsed.key_id, parsed.role), (0, None));
+        assert_eq!(parsed.secret_key, [0x11; KEY_SIZE]);
+        assert_eq!(parsed.peer_public_key, [0x22; KEY_SIZE]);
+    }
+
+    #[test]
+    fn rejects_wrong_length() {
+        let bytes = keypair().to_bytes();
+        for len in [0, 8, 9, 63, 65, KEYPAIR_SIZE - 1] {
+            assert!(Keypair::from_bytes(&bytes[..len]).is_err(), "{} bytes", len);
+        }
+        assert!(Keypair::from_bytes(&[&bytes[..], &[0]].concat()).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_magic_version_and_role() {
+        let mut bytes = keypair().to_bytes();
+        bytes[0] = b'X';
+        assert!(Keypair::from_bytes(&bytes).is_err());
+
+        let mut bytes = keypair().to_bytes();
+        bytes[8] = KEYPAIR_VERSION + 1;
+        assert!(Keypair::from_bytes(&bytes).err().unwrap().to_string().contains("version"));
+
+        let mut bytes = keypair().to_bytes();
+        bytes[9] = 2;
+        assert!(Keypair::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn load_checks_the_role() {
+        let path = std::env::temp_dir().join(format!("wfb_rs_keypair_test_{}.key", std::process::id()));
+        let path = path.to_str().unwrap();
+        keypair().save(path, false).unwrap();
+        let drone = Keypair::load(path, KeyRole::Drone);
+        let ground_station = Keypair::load(path, KeyRole::GroundStation);
+        fs::remove_file(path).unwrap();
+
+        assert!(drone.is_err());
+        assert_eq!(ground_station.unwrap().key_id, 0x0403_0201);
+    }
+
+    #[test]
+    fn generated_keys_match() {
+        let (drone, ground_station) = generate_keypairs(9).unwrap();
+        assert_eq!((drone.role, ground_station.role), (Some(KeyRole::Drone), Some(KeyRole::GroundStation)));
+        assert_eq!(drone.peer_public_key, MontgomeryPoint::mul_base_clamped(ground_station.secret_key).to_bytes());
+        assert_eq!(ground_station.peer_public_key, MontgomeryPoint::mul_base_clamped(drone.secret_key).to_bytes());
+    }
+}
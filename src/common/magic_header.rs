@@ -1,5 +1,21 @@
 const MAGIC_HEADER_SIZE: usize = size_of::<MagicHeader>();
 
+// session packets are marked by the magic xor'ed with this mask ("SESS")
+const SESSION_MAGIC_MASK: u32 = 0x5345_5353;
+// fec packets with a versioned header ("FECV"), the inverted magic marks the legacy header
+const FEC_MAGIC_MASK: u32 = 0x4645_4356;
+// sync packets of the frequency hopping ("HOPS")
+const SYNC_MAGIC_MASK: u32 = 0x484f_5053;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Data,
+    Fec,
+    LegacyFec,
+    Session,
+    Sync,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct MagicHeader {
     pub magic: u32,         // 4 bytes - magic number to identify wfb packets
@@ -13,11 +29,29 @@ impl MagicHeader {
     }
 
     pub fn new_fec(magic: u32) -> Self {
+        Self {
+            magic: magic ^ FEC_MAGIC_MASK,
+        }
+    }
+
+    pub fn new_legacy_fec(magic: u32) -> Self {
         Self {
             magic: !magic,
         }
     }
 
+    pub fn new_session(magic: u32) -> Self {
+        Self {
+            magic: magic ^ SESSION_MAGIC_MASK,
+        }
+    }
+
+    pub fn new_sync(magic: u32) -> Self {
+        Self {
+            magic: magic ^ SYNC_MAGIC_MASK,
+        }
+    }
+
     pub fn to_bytes(&self) -> [u8; MAGIC_HEADER_SIZE] {
         let mut bytes = [0u8; MAGIC_HEADER_SIZE];
         bytes[0..4].copy_from_slice(&self.magic.to_le_bytes());
@@ -25,7 +59,7 @@ impl MagicHeader {
     }
 
     #[cfg(feature = "receiver")]
-    pub fn from_bytes<'a>(&self, bytes: &'a[u8]) -> Option<(bool, &'a[u8])> {
+    pub fn from_bytes<'a>(&self, bytes: &'a[u8]) -> Option<(PacketType, &'a[u8])> {
         if bytes.len() < MAGIC_HEADER_SIZE {
             return None;
         }
@@ -33,11 +67,23 @@ impl MagicHeader {
         let dec_magic = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
 
         if dec_magic == self.magic {
-            Some((false, &bytes[MAGIC_HEADER_SIZE..]))
+            Some((PacketType::Data, &bytes[MAGIC_HEADER_SIZE..]))
+        }
+
+        else if dec_magic == self.magic ^ FEC_MAGIC_MASK {
+            Some((PacketType::Fec, &bytes[MAGIC_HEADER_SIZE..]))
         }
 
         else if dec_magic == !self.magic {
-            Some((true, &bytes[MAGIC_HEADER_SIZE..]))
+            Some((PacketType::LegacyFec, &bytes[MAGIC_HEADER_SIZE..]))
+        }
+
+        else if dec_magic == self.magic ^ SESSION_MAGIC_MASK {
+            Some((PacketType::Session, &bytes[MAGIC_HEADER_SIZE..]))
+        }
+
+        else if dec_magic == self.magic ^ SYNC_MAGIC_MASK {
+            Some((PacketType::Sync, &bytes[MAGIC_HEADER_SIZE..]))
         }
 
         else {
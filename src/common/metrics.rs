@@ -0,0 +1,92 @@
+// Prometheus endpoint: a minimal http listener that answers GET /metrics with the text exposition
+// format, enough for a scraper without pulling in an http server
+use std::fmt::Display;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Serves the output of `render` in the background
+pub fn serve(address: &str, render: impl Fn() -> String + Send + 'static) -> Result<(), Box<dyn std::error::Error>> {
+    let listener = TcpListener::bind(address)?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.map_err(|e| e.into()).and_then(|stream| respond(stream, &render));
+            if let Err(e) = result {
+                eprintln!("Error serving metrics: {}", e);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream, render: &impl Fn() -> String) -> Result<(), Box<dyn std::error::Error>> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // skip the headers, the request has no body
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render()),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+// Builds the text exposition format, one metric family after the other
+#[derive(Default)]
+pub struct MetricsWriter {
+    out: String,
+}
+
+impl MetricsWriter {
+    pub fn counter(&mut self, name: &str, help: &str, value: impl Display) {
+        self.family(name, "counter", help, [("", value)]);
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: impl Display) {
+        self.family(name, "gauge", help, [("", value)]);
+    }
+
+    // Samples are the labels without braces (e.g. `card="0"`) and the value
+    pub fn family<L: AsRef<str>, V: Display>(
+        &mut self,
+        name: &str,
+        kind: &str,
+        help: &str,
+        samples: impl IntoIterator<Item = (L, V)>,
+    ) {
+        self.out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+        for (labels, value) in samples {
+            let labels = labels.as_ref();
+            if labels.is_empty() {
+                self.out.push_str(&format!("{} {}\n", name, value));
+            } else {
+                self.out.push_str(&format!("{}{{{}}} {}\n", name, labels, value));
+            }
+        }
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
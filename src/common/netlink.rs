@@ -0,0 +1,378 @@
+// Native network setup over netlink: rtnetlink for the link state, mtu and addresses, nl80211 (generic
+// netlink) for monitor mode, channel and tx power. Replaces ip and iw, minimal OpenIPC images lack iw.
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::mem::{size_of, zeroed};
+use std::net::IpAddr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::Duration;
+
+use super::bandwidth::Bandwidth;
+use super::channel::Channel;
+
+// a lost request shouldn't hang the setup
+const RECV_TIMEOUT: Duration = Duration::from_secs(1);
+// the nl80211 family description lists all of its commands
+const RECV_BUFFER_SIZE: usize = 64 * 1024;
+const NLMSG_HEADER_SIZE: usize = 16;
+const NLA_HEADER_SIZE: usize = 4;
+const GENL_HEADER_SIZE: usize = 4;
+
+// linux/nl80211.h
+const NL80211_CMD_SET_WIPHY: u8 = 2;
+const NL80211_CMD_SET_INTERFACE: u8 = 6;
+const NL80211_CMD_REQ_SET_REG: u8 = 27;
+const NL80211_ATTR_IFINDEX: u16 = 3;
+const NL80211_ATTR_IFTYPE: u16 = 5;
+const NL80211_ATTR_MNTR_FLAGS: u16 = 23;
+const NL80211_ATTR_REG_ALPHA2: u16 = 33;
+const NL80211_ATTR_WIPHY_FREQ: u16 = 38;
+const NL80211_ATTR_WIPHY_TX_POWER_SETTING: u16 = 97;
+const NL80211_ATTR_WIPHY_TX_POWER_LEVEL: u16 = 98;
+const NL80211_ATTR_CHANNEL_WIDTH: u16 = 159;
+const NL80211_ATTR_CENTER_FREQ1: u16 = 160;
+const NL80211_IFTYPE_MONITOR: u32 = 6;
+const NL80211_TX_POWER_FIXED: u32 = 2;
+
+// Flags of the monitor mode, values of nl80211_mntr_flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MonitorFlag {
+    FcsFail = 1,    // also pass frames with a bad checksum
+    PlcpFail = 2,   // also pass frames with a bad plcp header
+    Control = 3,    // also pass control frames
+    OtherBss = 4,   // don't filter frames of other networks
+    CookFrames = 5, // report frames after processing
+    Active = 6,     // acknowledge unicast frames to the interface's address
+}
+
+#[derive(Debug)]
+pub enum NetlinkError {
+    Socket(io::Error),
+    NoSuchInterface(String),
+    Nl80211Unavailable,
+    InvalidAddress(String),
+    Request { request: &'static str, interface: String, error: io::Error },
+}
+
+impl fmt::Display for NetlinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetlinkError::Socket(e) => write!(f, "Netlink socket error: {}", e),
+            NetlinkError::NoSuchInterface(name) => write!(f, "No network interface {}", name),
+            NetlinkError::Nl80211Unavailable => write!(f, "nl80211 is not available, the kernel lacks cfg80211"),
+            NetlinkError::InvalidAddress(address) => write!(f, "Invalid address {}, expected e.g. 10.5.0.1/24", address),
+            NetlinkError::Request { request, interface, error } => {
+                write!(f, "{}: failed to {}: {}", interface, request, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for NetlinkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NetlinkError::Socket(e) | NetlinkError::Request { error: e, .. } => Some(e),
+            _ => None,
+        }
+    }
+}
+
+pub fn interface_index(interface: &str) -> Result<u32, NetlinkError> {
+    let name = CString::new(interface).map_err(|_| NetlinkError::NoSuchInterface(interface.to_string()))?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(NetlinkError::NoSuchInterface(interface.to_string())),
+        index => Ok(index),
+    }
+}
+
+pub fn set_link_up(interface: &str, up: bool) -> Result<(), NetlinkError> {
+    let flags = if up { libc::IFF_UP as u32 } else { 0 };
+    let payload = Payload::link(interface_index(interface)?, flags, libc::IFF_UP as u32);
+    let request = if up { "bring up" } else { "bring down" };
+    NetlinkSocket::open(libc::NETLINK_ROUTE)?.request(request, interface, libc::RTM_NEWLINK, 0, &payload)?;
+    Ok(())
+}
+
+pub fn set_mtu(interface: &str, mtu: u32) -> Result<(), NetlinkError> {
+    let payload = Payload::link(interface_index(interface)?, 0, 0).u32(libc::IFLA_MTU, mtu);
+    NetlinkSocket::open(libc::NETLINK_ROUTE)?.request("set the mtu", interface, libc::RTM_NEWLINK, 0, &payload)?;
+    Ok(())
+}
+
+// Adds an address with its prefix length, e.g. 10.5.0.1/24
+pub fn add_address(interface: &str, address: &str) -> Result<(), NetlinkError> {
+    let invalid = || NetlinkError::InvalidAddress(address.to_string());
+    let (ip, prefix_len) = address.split_once('/').ok_or_else(invalid)?;
+    let ip: IpAddr = ip.parse().map_err(|_| invalid())?;
+    let prefix_len: u8 = prefix_len.parse().map_err(|_| invalid())?;
+    let (family, octets, max_prefix_len) = match ip {
+        IpAddr::V4(ip) => (libc::AF_INET, ip.octets().to_vec(), 32),
+        IpAddr::V6(ip) => (libc::AF_INET6, ip.octets().to_vec(), 128),
+    };
+    if prefix_len > max_prefix_len {
+        return Err(invalid());
+    }
+
+    let payload = Payload::address(interface_index(interface)?, family as u8, prefix_len)
+        .bytes(libc::IFA_LOCAL, &octets)
+        .bytes(libc::IFA_ADDRESS, &octets);
+    let flags = (libc::NLM_F_CREATE | libc::NLM_F_EXCL) as u16;
+    NetlinkSocket::open(libc::NETLINK_ROUTE)?.request("add the address", interface, libc::RTM_NEWADDR, flags, &payload)?;
+    Ok(())
+}
+
+// The interface has to be down to change its type
+pub fn set_monitor_mode(interface: &str, flags: &[MonitorFlag]) -> Result<(), NetlinkError> {
+    let flags = flags.iter().fold(Payload::default(), |payload, &flag| payload.flag(flag as u16));
+    let payload = Payload::genl(NL80211_CMD_SET_INTERFACE)
+        .u32(NL80211_ATTR_IFINDEX, interface_index(interface)?)
+        .u32(NL80211_ATTR_IFTYPE, NL80211_IFTYPE_MONITOR)
+        .nested(NL80211_ATTR_MNTR_FLAGS, flags);
+    Nl80211::open()?.request("set monitor mode", interface, &payload)
+}
+
+pub fn set_channel(interface: &str, channel: &Channel) -> Result<(), NetlinkError> {
+    // values of nl80211_chan_width
+    let width = match channel.bandwidth {
+        Bandwidth::Bw10 => 7,
+        Bandwidth::Bw20 => 1,
+        Bandwidth::Bw40 => 2,
+        Bandwidth::Bw80 => 3,
+        Bandwidth::Bw160 => 5,
+    };
+    let payload = Payload::genl(NL80211_CMD_SET_WIPHY)
+        .u32(NL80211_ATTR_IFINDEX, interface_index(interface)?)
+        .u32(NL80211_ATTR_WIPHY_FREQ, channel.frequency)
+        .u32(NL80211_ATTR_CHANNEL_WIDTH, width)
+        .u32(NL80211_ATTR_CENTER_FREQ1, channel.center_frequency);
+    Nl80211::open()?.request("set the channel", interface, &payload)
+}
+
+// Fixed tx power in mBm (1/100 dBm)
+pub fn set_tx_power(interface: &str, mbm: u32) -> Result<(), NetlinkError> {
+    let payload = Payload::genl(NL80211_CMD_SET_WIPHY)
+        .u32(NL80211_ATTR_IFINDEX, interface_index(interface)?)
+        .u32(NL80211_ATTR_WIPHY_TX_POWER_SETTING, NL80211_TX_POWER_FIXED)
+        .u32(NL80211_ATTR_WIPHY_TX_POWER_LEVEL, mbm);
+    Nl80211::open()?.request("set the tx power", interface, &payload)
+}
+
+// Asks for the rules of a country (ISO 3166 alpha2 code, 00 is the world), they decide which channels
+// and tx powers are allowed
+pub fn set_region(alpha2: &str) -> Result<(), NetlinkError> {
+    let payload = Payload::genl(NL80211_CMD_REQ_SET_REG).bytes(NL80211_ATTR_REG_ALPHA2, format!("{}\0", alpha2).as_bytes());
+    Nl80211::open()?.request("set the region", alpha2, &payload)
+}
+
+// Fixed header of a request followed by its attributes
+#[derive(Default)]
+struct Payload(Vec<u8>);
+
+impl Payload {
+    // struct ifinfomsg
+    fn link(index: u32, flags: u32, change: u32) -> Self {
+        let mut header = vec![libc::AF_UNSPEC as u8, 0, 0, 0];
+        header.extend_from_slice(&index.to_ne_bytes());
+        header.extend_from_slice(&flags.to_ne_bytes());
+        header.extend_from_slice(&change.to_ne_bytes());
+        Self(header)
+    }
+
+    // struct ifaddrmsg, global scope
+    fn address(index: u32, family: u8, prefix_len: u8) -> Self {
+        let mut header = vec![family, prefix_len, 0, 0];
+        header.extend_from_slice(&index.to_ne_bytes());
+        Self(header)
+    }
+
+    // struct genlmsghdr
+    fn genl(command: u8) -> Self {
+        Self(vec![command, 0, 0, 0])
+    }
+
+    fn bytes(mut self, kind: u16, data: &[u8]) -> Self {
+        self.0.extend_from_slice(&((NLA_HEADER_SIZE + data.len()) as u16).to_ne_bytes());
+        self.0.extend_from_slice(&kind.to_ne_bytes());
+        self.0.extend_from_slice(data);
+        self.0.resize(align(self.0.len()), 0);
+        self
+    }
+
+    fn u32(self, kind: u16, value: u32) -> Self {
+        self.bytes(kind, &value.to_ne_bytes())
+    }
+
+    fn flag(self, kind: u16) -> Self {
+        self.bytes(kind, &[])
+    }
+
+    fn nested(self, kind: u16, attributes: Payload) -> Self {
+        self.bytes(kind, &attributes.0)
+    }
+}
+
+struct NetlinkSocket {
+    fd: OwnedFd,
+    seq: u32,
+}
+
+impl NetlinkSocket {
+    fn open(protocol: i32) -> Result<Self, NetlinkError> {
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, protocol) };
+        if fd < 0 {
+            return Err(NetlinkError::Socket(io::Error::last_os_error()));
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let timeout = libc::timeval { tv_sec: RECV_TIMEOUT.as_secs() as libc::time_t, tv_usec: 0 };
+        let result = unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const libc::timeval as *const libc::c_void,
+                size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(NetlinkError::Socket(io::Error::last_os_error()));
+        }
+
+        // the kernel assigns the port id
+        let mut address: libc::sockaddr_nl = unsafe { zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let result = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(NetlinkError::Socket(io::Error::last_os_error()));
+        }
+
+        Ok(Self { fd, seq: 0 })
+    }
+
+    // Sends one request and waits for its acknowledgement, returns the payloads of the replies before it
+    fn request(
+        &mut self,
+        request: &'static str,
+        interface: &str,
+        message_type: u16,
+        flags: u16,
+        payload: &Payload,
+    ) -> Result<Vec<Vec<u8>>, NetlinkError> {
+        self.seq += 1;
+        let mut message = Vec::with_capacity(NLMSG_HEADER_SIZE + payload.0.len());
+        message.extend_from_slice(&((NLMSG_HEADER_SIZE + payload.0.len()) as u32).to_ne_bytes());
+        message.extend_from_slice(&message_type.to_ne_bytes());
+        message.extend_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_ACK) as u16 | flags).to_ne_bytes());
+        message.extend_from_slice(&self.seq.to_ne_bytes());
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        message.extend_from_slice(&payload.0);
+
+        let sent = unsafe { libc::send(self.fd.as_raw_fd(), message.as_ptr() as *const libc::c_void, message.len(), 0) };
+        if sent < 0 {
+            return Err(NetlinkError::Socket(io::Error::last_os_error()));
+        }
+
+        let mut replies = Vec::new();
+        let mut buffer = vec![0u8; RECV_BUFFER_SIZE];
+        loop {
+            let received = unsafe { libc::recv(self.fd.as_raw_fd(), buffer.as_mut_ptr() as *mut libc::c_void, buffer.len(), 0) };
+            if received < 0 {
+                return Err(NetlinkError::Socket(io::Error::last_os_error()));
+            }
+
+            let mut messages = &buffer[..received as usize];
+            while messages.len() >= NLMSG_HEADER_SIZE {
+                let length = u32::from_ne_bytes(messages[0..4].try_into().unwrap()) as usize;
+                if length < NLMSG_HEADER_SIZE || length > messages.len() {
+                    break;
+                }
+                let reply_type = u16::from_ne_bytes(messages[4..6].try_into().unwrap());
+                let seq = u32::from_ne_bytes(messages[8..12].try_into().unwrap());
+                let body = &messages[NLMSG_HEADER_SIZE..length];
+                messages = &messages[align(length).min(messages.len())..];
+
+                // leftovers of an earlier request
+                if seq != self.seq {
+                    continue;
+                }
+                match reply_type as i32 {
+                    libc::NLMSG_ERROR => {
+                        let error = body.get(0..4).map_or(0, |error| i32::from_ne_bytes(error.try_into().unwrap()));
+                        // an error of 0 is the acknowledgement
+                        if error == 0 {
+                            return Ok(replies);
+                        }
+                        return Err(NetlinkError::Request {
+                            request,
+                            interface: interface.to_string(),
+                            error: io::Error::from_raw_os_error(-error),
+                        });
+                    }
+                    libc::NLMSG_DONE => return Ok(replies),
+                    _ => replies.push(body.to_vec()),
+                }
+            }
+        }
+    }
+}
+
+// Generic netlink socket for nl80211 requests
+struct Nl80211 {
+    socket: NetlinkSocket,
+    family: u16,
+}
+
+impl Nl80211 {
+    fn open() -> Result<Self, NetlinkError> {
+        let mut socket = NetlinkSocket::open(libc::NETLINK_GENERIC)?;
+
+        // the family id of nl80211 is assigned when cfg80211 loads
+        let payload = Payload::genl(libc::CTRL_CMD_GETFAMILY as u8).bytes(libc::CTRL_ATTR_FAMILY_NAME as u16, b"nl80211\0");
+        let replies = match socket.request("resolve nl80211", "the kernel", libc::GENL_ID_CTRL as u16, 0, &payload) {
+            Err(NetlinkError::Request { error, .. }) if error.raw_os_error() == Some(libc::ENOENT) => {
+                return Err(NetlinkError::Nl80211Unavailable);
+            }
+            replies => replies?,
+        };
+        let family = replies
+            .iter()
+            .filter_map(|reply| reply.get(GENL_HEADER_SIZE..))
+            .find_map(|attributes| find_attribute(attributes, libc::CTRL_ATTR_FAMILY_ID as u16))
+            .and_then(|family| family.get(0..2))
+            .map(|family| u16::from_ne_bytes(family.try_into().unwrap()))
+            .ok_or(NetlinkError::Nl80211Unavailable)?;
+
+        Ok(Self { socket, family })
+    }
+
+    fn request(&mut self, request: &'static str, interface: &str, payload: &Payload) -> Result<(), NetlinkError> {
+        self.socket.request(request, interface, self.family, 0, payload).map(|_| ())
+    }
+}
+
+fn find_attribute(mut attributes: &[u8], kind: u16) -> Option<&[u8]> {
+    while attributes.len() >= NLA_HEADER_SIZE {
+        let length = u16::from_ne_bytes(attributes[0..2].try_into().unwrap()) as usize;
+        if length < NLA_HEADER_SIZE || length > attributes.len() {
+            return None;
+        }
+        // the upper bits mark nested and byte order swapped attributes
+        if u16::from_ne_bytes(attributes[2..4].try_into().unwrap()) & 0x3fff == kind {
+            return Some(&attributes[NLA_HEADER_SIZE..length]);
+        }
+        attributes = &attributes[align(length).min(attributes.len())..];
+    }
+    None
+}
+
+// netlink messages and attributes are 4 byte aligned
+fn align(length: usize) -> usize {
+    (length + 3) & !3
+}
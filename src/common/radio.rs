@@ -0,0 +1,27 @@
+// The radio a transmitter or receiver works on. The wifi cards (raw socket and pcap capture) are one
+// backend, the simulated channel of common::sim_channel is another one that needs no root.
+
+use clap::ValueEnum;
+
+// Sends complete frames: radiotap header, 802.11 header and payload. All streams of a card share it.
+pub trait RadioTx: Send + Sync {
+    // The parts are sent as one frame, so they don't have to be copied together first. Returns the
+    // sent bytes, headers included, None if the frame was dropped because the queue was full.
+    fn send_frame(&self, parts: &[&[u8]]) -> Result<Option<usize>, Box<dyn std::error::Error>>;
+}
+
+// Which received frames end with the 4 byte frame check sequence
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum FcsMode {
+    #[default]
+    Always,     // every frame, what the drivers of the supported cards deliver
+    Radiotap,   // only frames with the fcs flag in their radiotap header
+}
+
+// Receives complete frames as a monitor mode card captures them: radiotap header with the signal,
+// 802.11 header, payload and maybe the FCS. Every card is read by its own thread.
+pub trait RadioRx: Send {
+    // Waits a few ms at most, the capture threads check in between whether they are still needed.
+    // None if no frame arrived.
+    fn receive_frame(&mut self) -> Result<Option<&[u8]>, Box<dyn std::error::Error>>;
+}
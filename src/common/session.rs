@@ -0,0 +1,284 @@
+use crypto_box::aead::Aead;
+use crypto_box::{PublicKey, SalsaBox, SecretKey};
+
+use super::crypto::{Key, KEY_SIZE};
+use super::keypair::Keypair;
+use super::wfb_ng;
+
+const SESSION_NONCE_SIZE: usize = 24;
+const SESSION_FEC_SIZE: usize = 11;
+const SESSION_DATA_SIZE: usize = 8 + 4 + SESSION_FEC_SIZE + KEY_SIZE;
+// wsession_data_t of wfb-ng: epoch, channel_id, fec_type, k, n, session_key (big endian)
+const WFB_NG_SESSION_DATA_SIZE: usize = 8 + 4 + 3 + KEY_SIZE;
+
+// FEC settings of the transmitter, announced so a receiver knows what to expect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionFec {
+    Disabled,
+    RaptorQ {
+        block_size: u32,
+        packet_size: u16,
+        redundant_pkgs: u32,
+    },
+    ReedSolomon {
+        k: u8,
+        n: u8,
+    },
+}
+
+impl SessionFec {
+    fn to_bytes(self) -> [u8; SESSION_FEC_SIZE] {
+        let mut bytes = [0u8; SESSION_FEC_SIZE];
+        match self {
+            SessionFec::Disabled => (),
+            SessionFec::RaptorQ { block_size, packet_size, redundant_pkgs } => {
+                bytes[0] = 1;
+                bytes[1..5].copy_from_slice(&block_size.to_le_bytes());
+                bytes[5..7].copy_from_slice(&packet_size.to_le_bytes());
+                bytes[7..11].copy_from_slice(&redundant_pkgs.to_le_bytes());
+            }
+            SessionFec::ReedSolomon { k, n } => {
+                bytes[0] = 2;
+                bytes[1] = k;
+                bytes[2] = n;
+            }
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes[0] {
+            0 => Some(SessionFec::Disabled),
+            1 => Some(SessionFec::RaptorQ {
+                block_size: u32::from_le_bytes(bytes[1..5].try_into().unwrap()),
+                packet_size: u16::from_le_bytes(bytes[5..7].try_into().unwrap()),
+                redundant_pkgs: u32::from_le_bytes(bytes[7..11].try_into().unwrap()),
+            }),
+            2 => Some(SessionFec::ReedSolomon {
+                k: bytes[1],
+                n: bytes[2],
+            }),
+            _ => None,
+        }
+    }
+}
+
+// Session as reported in the statistics, without the key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionInfo {
+    pub epoch: u64,
+    pub fec: SessionFec,
+}
+
+impl SessionInfo {
+    // Fields of the session object in the json statistics, named like wfb-ng's
+    pub fn to_json(&self) -> String {
+        let (fec_type, fec_k, fec_n) = match self.fec {
+            SessionFec::Disabled => ("none", 0, 0),
+            SessionFec::RaptorQ { block_size, redundant_pkgs, .. } => ("raptorq", block_size, redundant_pkgs),
+            SessionFec::ReedSolomon { k, n } => ("VDM_RS", k as u32, n as u32),
+        };
+        format!(
+            "{{\"epoch\":{},\"fec_type\":\"{}\",\"fec_k\":{},\"fec_n\":{}}}",
+            self.epoch, fec_type, fec_k, fec_n
+        )
+    }
+}
+
+impl From<&SessionData> for SessionInfo {
+    fn from(session: &SessionData) -> Self {
+        Self { epoch: session.epoch, fec: session.fec }
+    }
+}
+
+// Content of a session announcement, sent encrypted and authenticated with the keypair
+#[derive(Debug, Clone, Copy)]
+pub struct SessionData {
+    pub epoch: u64,             // 8 bytes - increases with every new session key
+    pub channel_id: u32,        // 4 bytes - (link_id << 8) | radio_port, binds the session to one stream
+    pub fec: SessionFec,        // 11 bytes - fec type and parameters
+    pub session_key: Key,       // 32 bytes - key of all data packets in this session
+}
+
+impl SessionData {
+    pub fn to_bytes(&self) -> [u8; SESSION_DATA_SIZE] {
+        let mut bytes = [0u8; SESSION_DATA_SIZE];
+        bytes[0..8].copy_from_slice(&self.epoch.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.channel_id.to_le_bytes());
+        bytes[12..23].copy_from_slice(&self.fec.to_bytes());
+        bytes[23..55].copy_from_slice(&self.session_key);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < SESSION_DATA_SIZE {
+            return None;
+        }
+
+        Some(Self {
+            epoch: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            channel_id: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            fec: SessionFec::from_bytes(&bytes[12..23])?,
+            session_key: bytes[23..55].try_into().unwrap(),
+        })
+    }
+
+    // wfb-ng only knows Reed-Solomon, so other fec settings can't be announced in its layout
+    pub fn to_wfb_ng_bytes(&self) -> Option<[u8; WFB_NG_SESSION_DATA_SIZE]> {
+        let SessionFec::ReedSolomon { k, n } = self.fec else { return None };
+
+        let mut bytes = [0u8; WFB_NG_SESSION_DATA_SIZE];
+        bytes[0..8].copy_from_slice(&self.epoch.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.channel_id.to_be_bytes());
+        bytes[12] = wfb_ng::FEC_TYPE_VDM_RS;
+        bytes[13] = k;
+        bytes[14] = n;
+        bytes[15..47].copy_from_slice(&self.session_key);
+        Some(bytes)
+    }
+
+    // newer wfb-ng versions append optional tags, they are ignored
+    pub fn from_wfb_ng_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < WFB_NG_SESSION_DATA_SIZE || bytes[12] != wfb_ng::FEC_TYPE_VDM_RS {
+            return None;
+        }
+
+        Some(Self {
+            epoch: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            channel_id: u32::from_be_bytes(bytes[8..12].try_into().unwrap()),
+            fec: SessionFec::ReedSolomon {
+                k: bytes[13],
+                n: bytes[14],
+            },
+            session_key: bytes[15..47].try_into().unwrap(),
+        })
+    }
+}
+
+// crypto_box (x25519, XSalsa20-Poly1305) between drone and ground station key, like wfb-ng's session packets
+pub fn session_box(keypair: &Keypair) -> SalsaBox {
+    SalsaBox::new(
+        &PublicKey::from_bytes(keypair.peer_public_key),
+        &SecretKey::from_bytes(keypair.secret_key),
+    )
+}
+
+// Returns the payload of a session packet: random nonce followed by the sealed session data
+pub fn seal_session(session_box: &SalsaBox, session: &SessionData, wfb_ng: bool) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut nonce = [0u8; SESSION_NONCE_SIZE];
+    getrandom::getrandom(&mut nonce)?;
+
+    let session_data = if wfb_ng {
+        session.to_wfb_ng_bytes().ok_or("wfb-ng sessions require Reed-Solomon fec")?.to_vec()
+    } else {
+        session.to_bytes().to_vec()
+    };
+
+    let sealed = session_box
+        .encrypt(&nonce.into(), &session_data[..])
+        .map_err(|_| "Failed to seal session packet")?;
+
+    Ok([&nonce[..], &sealed[..]].concat())
+}
+
+// Returns None if the session packet was not sealed by the peer of this keypair
+#[cfg(feature = "receiver")]
+pub fn open_session(session_box: &SalsaBox, packet: &[u8], wfb_ng: bool) -> Option<SessionData> {
+    if packet.len() < SESSION_NONCE_SIZE {
+        return None;
+    }
+    let (nonce, sealed) = packet.split_at(SESSION_NONCE_SIZE);
+    let nonce: [u8; SESSION_NONCE_SIZE] = nonce.try_into().unwrap();
+
+    let session = session_box.decrypt(&nonce.into(), sealed).ok()?;
+    if wfb_ng {
+        SessionData::from_wfb_ng_bytes(&session)
+    } else {
+        SessionData::from_bytes(&session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::from_hex;
+
+    // Keys and nonce of libsodium's crypto_box test vector
+    const DRONE_SECRET: &str = "68f208412d8dd5db9d0c6d18512e86f0ec75665ab841372d57b042b27ef89d4c";
+    const DRONE_PUBLIC: &str = "ac3a70ba35df3c3fae427a7c72021d68f2c1e044040b75f17313c0c8b5d4241d";
+    const GS_SECRET: &str = "b581fb5ae182a16f603f39270d4e3b95bc008310b727a11dd4e784a0044d461b";
+    const GS_PUBLIC: &str = "e8980c86e032f1eb2975052e8d65bddd15c3b59641174ec9678a53789d92c754";
+
+    // wsession_data_t of epoch 0x0102030405060708, channel 0x00070001, VDM_RS 8/12, key a0..bf
+    const WFB_NG_SESSION_DATA: &str = "01020304050607080007000101080c\
+                                       a0a1a2a3a4a5a6a7a8a9aaabacadaeafb0b1b2b3b4b5b6b7b8b9babbbcbdbebf";
+
+    fn keypair(secret: &str, peer_public: &str) -> Keypair {
+        Keypair {
+            key_id: 0,
+            role: None,
+            secret_key: from_hex(secret).try_into().unwrap(),
+            peer_public_key: from_hex(peer_public).try_into().unwrap(),
+        }
+    }
+
+    fn session_data() -> SessionData {
+        SessionData {
+            epoch: 0x0102030405060708,
+            channel_id: 0x00070001,
+            fec: SessionFec::ReedSolomon { k: 8, n: 12 },
+            session_key: core::array::from_fn(|i| 0xa0 + i as u8),
+        }
+    }
+
+    #[test]
+    fn wfb_ng_session_data_layout() {
+        let session = session_data();
+        assert_eq!(session.to_wfb_ng_bytes().unwrap().to_vec(), from_hex(WFB_NG_SESSION_DATA));
+
+        let parsed = SessionData::from_wfb_ng_bytes(&from_hex(WFB_NG_SESSION_DATA)).unwrap();
+        assert_eq!(parsed.epoch, session.epoch);
+        assert_eq!(parsed.channel_id, session.channel_id);
+        assert_eq!(parsed.fec, session.fec);
+        assert_eq!(parsed.session_key, session.session_key);
+
+        let raptorq = SessionData { fec: SessionFec::RaptorQ { block_size: 1, packet_size: 1, redundant_pkgs: 1 }, ..session };
+        assert!(raptorq.to_wfb_ng_bytes().is_none());
+    }
+
+    #[test]
+    fn sealed_session_opens_with_crypto_box() {
+        let drone_box = session_box(&keypair(DRONE_SECRET, GS_PUBLIC));
+        let gs_box = session_box(&keypair(GS_SECRET, DRONE_PUBLIC));
+
+        let packet = seal_session(&drone_box, &session_data(), true).unwrap();
+        let (nonce, sealed) = packet.split_at(SESSION_NONCE_SIZE);
+        let nonce: [u8; SESSION_NONCE_SIZE] = nonce.try_into().unwrap();
+        assert_eq!(gs_box.decrypt(&nonce.into(), sealed).unwrap(), from_hex(WFB_NG_SESSION_DATA));
+    }
+
+    // A session packet of wfb-ng's session_packet() with the nonce of the crypto_box test vector
+    #[cfg(feature = "receiver")]
+    #[test]
+    fn opens_wfb_ng_session_packet() {
+        let packet = from_hex(
+            "0269696ee955b62b73cd62bda875fc73d68219e0036b7a0b37\
+             b5d59efa38cbf1ecf47adada24cdcbdb5249c4035d0ecc3f2ec1fb42ade97b5790a6cd73b377dfbe31\
+             dff3bb5745bded27bff0d89ec62dbf6dab41397dbb8e",
+        );
+        assert_eq!(packet[0], wfb_ng::PACKET_TYPE_SESSION);
+
+        let gs_box = session_box(&keypair(GS_SECRET, DRONE_PUBLIC));
+        let session = open_session(&gs_box, &packet[1..], true).unwrap();
+        let expected = session_data();
+        assert_eq!(session.epoch, expected.epoch);
+        assert_eq!(session.channel_id, expected.channel_id);
+        assert_eq!(session.fec, expected.fec);
+        assert_eq!(session.session_key, expected.session_key);
+
+        // the native layout doesn't parse it, and neither does a third key
+        assert!(open_session(&gs_box, &packet[1..], false).is_none());
+        let other_box = session_box(&keypair(GS_SECRET, GS_PUBLIC));
+        assert!(open_session(&other_box, &packet[1..], true).is_none());
+    }
+}
@@ -0,0 +1,251 @@
+// In-process radio channel, for tests and experiments without root or wifi cards. Every frame a
+// transmitter sends reaches every receiver of the channel, each receiver with its own share of loss,
+// reordering, duplication and corruption, like cards at different spots. The impairments come from
+// a seeded generator, so the same seed and the same frames give the same result.
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::hw_headers;
+use super::radio::{RadioRx, RadioTx};
+
+// How long a receiver waits for a frame, like the capture timeout of the wifi cards
+const RECEIVE_TIMEOUT: Duration = Duration::from_millis(10);
+// The received frames start with this radiotap header: flags and the signal, and end with an FCS
+// like the ones of the wifi cards
+const RX_RADIOTAP_HEADER: [u8; 10] = [
+    0x00, 0x00, // <-- radiotap version
+    0x0a, 0x00, // <- radiotap header length
+    0x22, 0x00, 0x00, 0x00, // <-- radiotap present flags: RADIOTAP_FLAGS + RADIOTAP_DBM_ANTSIGNAL
+    0x10, // flags: frame includes FCS
+    0x00, // signal in dBm, set per frame
+];
+// the check already passed, its value doesn't matter
+const FCS: [u8; 4] = [0; 4];
+
+// What happens to the frames on their way, every receiver draws on its own
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelConditions {
+    pub loss: f64,              // chance that a frame is lost
+    pub burst_loss: f64,        // chance that a frame starts a burst of lost frames
+    pub burst_length: u32,      // frames a burst takes out, the first one included
+    pub reorder: f64,           // chance that a frame is held back
+    pub reorder_distance: u32,  // later frames that overtake a held back one
+    pub duplicate: f64,         // chance that a frame arrives twice
+    pub corrupt: f64,           // chance that a bit of the payload flips and the FCS check misses it
+    pub signal: i8,             // dBm, reported in the radiotap header
+}
+
+impl Default for ChannelConditions {
+    // A perfect channel
+    fn default() -> Self {
+        Self {
+            loss: 0.0,
+            burst_loss: 0.0,
+            burst_length: 1,
+            reorder: 0.0,
+            reorder_distance: 1,
+            duplicate: 0.0,
+            corrupt: 0.0,
+            signal: -50,
+        }
+    }
+}
+
+// Frames seen by the receivers, summed over all of them
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelStats {
+    pub sent: u64,          // frames of the transmitters
+    pub lost: u64,          // single and burst losses
+    pub reordered: u64,
+    pub duplicated: u64,
+    pub corrupted: u64,
+    pub delivered: u64,     // duplicates included
+}
+
+#[derive(Clone)]
+pub struct SimChannel {
+    shared: Arc<Mutex<Shared>>,
+}
+
+struct Shared {
+    conditions: ChannelConditions,
+    seed: u64,
+    links: Vec<Link>,
+    stats: ChannelStats,
+}
+
+// The way to one receiver
+struct Link {
+    frames: Sender<Vec<u8>>,
+    random: Random,
+    burst_left: u32,
+    held: Vec<(u32, Vec<u8>)>,  // held back frames with the frames still to overtake them
+}
+
+pub struct SimTx {
+    shared: Arc<Mutex<Shared>>,
+}
+
+pub struct SimRx {
+    frames: Receiver<Vec<u8>>,
+    frame: Vec<u8>,
+}
+
+impl SimChannel {
+    pub fn new(conditions: ChannelConditions, seed: u64) -> Self {
+        let shared = Shared { conditions, seed, links: Vec::new(), stats: ChannelStats::default() };
+        Self { shared: Arc::new(Mutex::new(shared)) }
+    }
+
+    // Applies to the following frames
+    pub fn set_conditions(&self, conditions: ChannelConditions) {
+        self.shared.lock().unwrap().conditions = conditions;
+    }
+
+    pub fn stats(&self) -> ChannelStats {
+        self.shared.lock().unwrap().stats
+    }
+
+    pub fn transmitter(&self) -> SimTx {
+        SimTx { shared: self.shared.clone() }
+    }
+
+    // A receiver only gets the frames sent after it joined
+    pub fn receiver(&self) -> SimRx {
+        let mut shared = self.shared.lock().unwrap();
+        let (frames_s, frames) = mpsc::channel();
+        let random = Random::new(shared.seed ^ (shared.links.len() as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        shared.links.push(Link { frames: frames_s, random, burst_left: 0, held: Vec::new() });
+        SimRx { frames, frame: Vec::new() }
+    }
+}
+
+impl Shared {
+    fn transmit(&mut self, frame: &[u8]) {
+        self.stats.sent += 1;
+        let conditions = self.conditions;
+        let stats = &mut self.stats;
+        // receivers that are gone drop out
+        self.links.retain_mut(|link| link.transmit(frame, &conditions, stats));
+    }
+}
+
+impl Link {
+    // Returns false once the receiver is gone
+    fn transmit(&mut self, frame: &[u8], conditions: &ChannelConditions, stats: &mut ChannelStats) -> bool {
+        if self.burst_left > 0 {
+            self.burst_left -= 1;
+            stats.lost += 1;
+            return true;
+        }
+        if self.random.chance(conditions.burst_loss) {
+            self.burst_left = conditions.burst_length.saturating_sub(1);
+            stats.lost += 1;
+            return true;
+        }
+        if self.random.chance(conditions.loss) {
+            stats.lost += 1;
+            return true;
+        }
+
+        let mut frame = frame.to_vec();
+        // the headers stay intact, corrupted headers would only end up in another stream
+        let payload_start = RX_RADIOTAP_HEADER.len() + hw_headers::IEEE80211_HEADER.len();
+        let payload_end = frame.len() - FCS.len();
+        if payload_end > payload_start && self.random.chance(conditions.corrupt) {
+            let bit = self.random.below(((payload_end - payload_start) * 8) as u64) as usize;
+            frame[payload_start + bit / 8] ^= 1 << (bit % 8);
+            stats.corrupted += 1;
+        }
+
+        let mut delivered = Vec::new();
+        if self.random.chance(conditions.reorder) {
+            self.held.push((conditions.reorder_distance.max(1), frame));
+            stats.reordered += 1;
+        } else {
+            if self.random.chance(conditions.duplicate) {
+                delivered.push(frame.clone());
+                stats.duplicated += 1;
+            }
+            delivered.push(frame);
+
+            // held back frames go out once enough frames overtook them
+            for (overtaken, _) in self.held.iter_mut() {
+                *overtaken -= 1;
+            }
+            while let Some(index) = self.held.iter().position(|(overtaken, _)| *overtaken == 0) {
+                delivered.push(self.held.remove(index).1);
+            }
+        }
+
+        stats.delivered += delivered.len() as u64;
+        delivered.into_iter().all(|frame| self.frames.send(frame).is_ok())
+    }
+}
+
+impl RadioTx for SimTx {
+    // The radiotap header of the transmitter is replaced by the one a receiving card would report
+    fn send_frame(&self, parts: &[&[u8]]) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+        let frame = parts.concat();
+        if frame.len() < 4 {
+            return Err("Frame without radiotap header".into());
+        }
+        let radiotap_len = u16::from_le_bytes([frame[2], frame[3]]) as usize;
+        if frame.len() < radiotap_len {
+            return Err("Frame shorter than its radiotap header".into());
+        }
+
+        let mut shared = self.shared.lock().unwrap();
+        let mut received = RX_RADIOTAP_HEADER.to_vec();
+        received[9] = shared.conditions.signal as u8;
+        received.extend_from_slice(&frame[radiotap_len..]);
+        received.extend_from_slice(&FCS);
+        shared.transmit(&received);
+        Ok(Some(frame.len()))
+    }
+}
+
+impl RadioRx for SimRx {
+    fn receive_frame(&mut self) -> Result<Option<&[u8]>, Box<dyn std::error::Error>> {
+        match self.frames.recv_timeout(RECEIVE_TIMEOUT) {
+            Ok(frame) => {
+                self.frame = frame;
+                Ok(Some(&self.frame))
+            }
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            // no frame will arrive anymore, like a card without a transmitter in range
+            Err(RecvTimeoutError::Disconnected) => {
+                thread::sleep(RECEIVE_TIMEOUT);
+                Ok(None)
+            }
+        }
+    }
+}
+
+// splitmix64, good enough to draw impairments and reproducible across platforms
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        // no draw for impairments that are off, so they don't shift the others
+        probability > 0.0 && ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+}
@@ -0,0 +1,65 @@
+// Read access to the statistics of a running transmitter or receiver, for applications embedding wfb_rs,
+// and the json records both of them publish for ground station tools
+use std::net::UdpSocket;
+use std::os::unix::net::UnixDatagram;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone)]
+pub struct StatsHandle<T> {
+    stats: Arc<Mutex<T>>,
+}
+
+impl<T: Clone> StatsHandle<T> {
+    pub(crate) fn new(stats: Arc<Mutex<T>>) -> Self {
+        Self { stats }
+    }
+
+    // Returns a copy of the current statistics
+    pub fn get(&self) -> T {
+        self.stats.lock().unwrap().clone()
+    }
+}
+
+// Receives one json record per datagram, a unix datagram socket ("unix:/path" or an absolute
+// path) or udp (host:port)
+pub enum StatsSocket {
+    Udp(UdpSocket),
+    Unix(UnixDatagram),
+}
+
+impl StatsSocket {
+    pub fn connect(address: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = address.strip_prefix("unix:").or(address.starts_with('/').then_some(address));
+        match path {
+            Some(path) => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                Ok(Self::Unix(socket))
+            }
+            None => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect(address)?;
+                Ok(Self::Udp(socket))
+            }
+        }
+    }
+
+    pub fn send(&self, record: &str) {
+        let record = format!("{}\n", record);
+        // the records are best effort, nobody listening is fine
+        let _ = match self {
+            Self::Udp(socket) => socket.send(record.as_bytes()),
+            Self::Unix(socket) => socket.send(record.as_bytes()),
+        };
+    }
+}
+
+// Counter in the json records: [change since the last record, total]
+pub(crate) fn json_counter(name: &str, current: u64, last: u64) -> String {
+    format!("\"{}\":[{},{}]", name, current.saturating_sub(last), current)
+}
+
+pub(crate) fn json_timestamp() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |t| t.as_secs_f64())
+}
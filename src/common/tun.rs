@@ -0,0 +1,76 @@
+// TUN interface of the tunnel mode, it carries plain ip packets without a link layer header.
+// One interface is shared by the transmitter reading from it and the receiver writing to it.
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem::zeroed;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use super::netlink;
+
+const TUN_DEVICE: &str = "/dev/net/tun";
+
+pub struct Tun {
+    file: File,
+    name: String,
+}
+
+impl Tun {
+    // Creates the interface, a name like "wfb%d" lets the kernel pick the number
+    pub fn create(name: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        if name.len() >= libc::IFNAMSIZ {
+            return Err(format!("Interface name {} is too long", name).into());
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(TUN_DEVICE)
+            .map_err(|e| format!("Failed to open {}, you need root privileges to do so: {}", TUN_DEVICE, e))?;
+
+        let mut ifr: libc::ifreq = unsafe { zeroed() };
+        for (dst, src) in ifr.ifr_name.iter_mut().zip(name.bytes()) {
+            *dst = src as libc::c_char;
+        }
+        // no packet information header in front of the ip packets
+        ifr.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+
+        if unsafe { libc::ioctl(file.as_raw_fd(), libc::TUNSETIFF, &ifr) } < 0 {
+            return Err(format!("Failed to create tun interface {}: {}", name, io::Error::last_os_error()).into());
+        }
+
+        let name = unsafe { CStr::from_ptr(ifr.ifr_name.as_ptr()) }.to_string_lossy().into_owned();
+        Ok(Self { file, name })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Assigns the address (e.g. 10.5.0.1/24) and brings the interface up
+    pub fn configure(&self, address: Option<&str>, mtu: u16) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(address) = address {
+            netlink::add_address(&self.name, address)?;
+        }
+        netlink::set_mtu(&self.name, mtu as u32)?;
+        netlink::set_link_up(&self.name, true)?;
+        Ok(())
+    }
+
+    // Reads one ip packet, fails with TimedOut if none arrived within the timeout
+    pub fn recv(&self, buffer: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        let mut pollfd = libc::pollfd { fd: self.file.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        let timeout = timeout.map_or(-1, |timeout| timeout.as_millis().min(i32::MAX as u128) as i32);
+        match unsafe { libc::poll(&mut pollfd, 1, timeout) } {
+            0 => Err(io::ErrorKind::TimedOut.into()),
+            ready if ready < 0 => Err(io::Error::last_os_error()),
+            _ => (&self.file).read(buffer),
+        }
+    }
+
+    // Writes one ip packet
+    pub fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        (&self.file).write(packet)
+    }
+}
@@ -1,30 +1,48 @@
 use std::process::Command;
 
-// This function sets up wifi drivers for sending data via monitor mode. It is designed for OpenIPC Cameras and requires iw
-pub fn set_monitor_mode(interface_name: &str) -> Result<(), Box<dyn std::error::Error>> {
-    if let Err(_) = Command::new("modprobe").arg("8812eu").output() {
-        //Pass, driver must not be set
+use super::bandwidth::Bandwidth;
+use super::channel::{Channel, Ht40};
+use super::hopping::Hopping;
+use super::netlink::{self, MonitorFlag, NetlinkError};
+
+// Card setup of --wifi-setup, the channel width comes from the bandwidth of the radiotap header
+#[derive(Debug, Clone)]
+pub struct WifiSetup {
+    pub frequency: u32,         // MHz, of the primary channel
+    pub ht40: Option<Ht40>,
+    pub region: Option<String>,
+    pub driver: Option<String>, // kernel module to load first
+    pub hopping: Option<Hopping>,
+}
+
+// This function sets up wifi drivers for sending data via monitor mode. It is designed for OpenIPC Cameras
+pub fn setup_wifi(interface_name: &str, setup: &WifiSetup, bandwidth: Bandwidth) -> Result<Channel, Box<dyn std::error::Error>> {
+    // checked before the card is touched
+    let channel = Channel::new(setup.frequency, bandwidth, setup.ht40)?;
+
+    if let Some(driver) = &setup.driver {
+        // it may as well be built in or not needed for this card
+        let _ = Command::new("modprobe").arg(driver).output();
     }
+    if let Some(region) = &setup.region {
+        netlink::set_region(region)?;
+    }
+    set_monitor_mode(interface_name)?;
+    set_channel(interface_name, &channel)?;
+    Ok(channel)
+}
+
+pub fn set_channel(interface_name: &str, channel: &Channel) -> Result<(), NetlinkError> {
+    netlink::set_channel(interface_name, channel)
+}
 
-    Command::new("ip")
-        .args(["link", "set", interface_name, "down"])
-        .output()?;
-    Command::new("iw")
-        .args(["dev", interface_name, "set", "monitor", "otherbss"])
-        .output()
-        .expect("Could not find iw command, can't set monitor mode");
-    Command::new("ip")
-        .args(["link", "set", interface_name, "up"])
-        .output()?;
-    Command::new("iw")
-        .args(["dev", interface_name, "set", "channel", "149"])
-        .output()?;
-    Ok(())
+pub fn set_monitor_mode(interface_name: &str) -> Result<(), NetlinkError> {
+    netlink::set_link_up(interface_name, false)?;
+    netlink::set_monitor_mode(interface_name, &[MonitorFlag::OtherBss])?;
+    netlink::set_link_up(interface_name, true)
 }
 
-pub fn set_tx_power(interface_name: &str, tx_power: u8) -> Result<(), Box<dyn std::error::Error>> {
-    Command::new("iw")
-        .args(["dev", interface_name, "set", "txpower", "fixed", format!("{}", tx_power as u16 * 50).as_str()])
-        .output()?;
-    Ok(())
+// tx_power is in units of 0.5 dBm
+pub fn set_tx_power(interface_name: &str, tx_power: u8) -> Result<(), NetlinkError> {
+    netlink::set_tx_power(interface_name, tx_power as u32 * 50)
 }
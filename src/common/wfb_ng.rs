@@ -0,0 +1,108 @@
+// Packet layout of wfb-ng, used in the Reed-Solomon fec mode. Other than our own format there is no
+// magic header, the first byte after the IEEE 802.11 header is the packet type. All integers are big endian.
+use std::mem::size_of;
+
+pub const PACKET_TYPE_DATA: u8 = 0x1;
+pub const PACKET_TYPE_SESSION: u8 = 0x2;
+
+// fec_type of the session data, the only one wfb-ng supports
+pub const FEC_TYPE_VDM_RS: u8 = 0x1;
+
+// wpacket_hdr_t flag of empty packets that only close a block
+pub const PACKET_FLAG_FEC_ONLY: u8 = 0x1;
+
+const BLOCK_HEADER_SIZE: usize = 1 + size_of::<u64>();
+const PACKET_HEADER_SIZE: usize = 1 + size_of::<u16>();
+
+// wfb-ng's WIFI_MTU, fragments must fit it including the authentication tag
+const WIFI_MTU: usize = 4045;
+pub const MAX_FEC_PAYLOAD: usize = WIFI_MTU - 24 - BLOCK_HEADER_SIZE - 16;
+pub const MAX_PAYLOAD_SIZE: usize = MAX_FEC_PAYLOAD - PACKET_HEADER_SIZE;
+
+// block index is limited to 56 bits, the lowest nonce byte is the fragment index
+pub const MAX_BLOCK_IDX: u64 = (1 << 55) - 1;
+
+pub fn data_nonce(block_idx: u64, fragment_idx: u8) -> u64 {
+    (block_idx << 8) | fragment_idx as u64
+}
+
+#[cfg(feature = "receiver")]
+pub fn split_data_nonce(data_nonce: u64) -> (u64, u8) {
+    (data_nonce >> 8, data_nonce as u8)
+}
+
+// wblock_hdr_t, in front of every encrypted fragment and authenticated as associated data
+#[derive(Debug, Clone, Copy)]
+pub struct BlockHeader {
+    pub packet_type: u8,    // 1 byte - PACKET_TYPE_DATA
+    pub data_nonce: u64,    // 8 bytes - (block_idx << 8) | fragment_idx
+}
+
+impl BlockHeader {
+    pub fn new(data_nonce: u64) -> Self {
+        Self {
+            packet_type: PACKET_TYPE_DATA,
+            data_nonce,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; BLOCK_HEADER_SIZE] {
+        let mut bytes = [0u8; BLOCK_HEADER_SIZE];
+        bytes[0] = self.packet_type;
+        bytes[1..9].copy_from_slice(&self.data_nonce.to_be_bytes());
+        bytes
+    }
+
+    #[cfg(feature = "receiver")]
+    pub fn from_bytes(bytes: &[u8]) -> Option<(Self, &[u8])> {
+        if bytes.len() < BLOCK_HEADER_SIZE {
+            return None;
+        }
+
+        let packet_type = bytes[0];
+        let data_nonce = u64::from_be_bytes(bytes[1..9].try_into().unwrap());
+
+        Some((Self {
+            packet_type,
+            data_nonce
+        }, &bytes[BLOCK_HEADER_SIZE..]))
+    }
+}
+
+// wpacket_hdr_t, in front of every udp packet inside a fragment
+#[derive(Debug, Clone, Copy)]
+pub struct PacketHeader {
+    pub flags: u8,          // 1 byte - PACKET_FLAG_FEC_ONLY
+    pub packet_size: u16,   // 2 bytes - size of the udp packet that follows
+}
+
+impl PacketHeader {
+    pub fn new(flags: u8, packet_size: u16) -> Self {
+        Self {
+            flags,
+            packet_size,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; PACKET_HEADER_SIZE] {
+        let mut bytes = [0u8; PACKET_HEADER_SIZE];
+        bytes[0] = self.flags;
+        bytes[1..3].copy_from_slice(&self.packet_size.to_be_bytes());
+        bytes
+    }
+
+    #[cfg(feature = "receiver")]
+    pub fn from_bytes(bytes: &[u8]) -> Option<(Self, &[u8])> {
+        if bytes.len() < PACKET_HEADER_SIZE {
+            return None;
+        }
+
+        let flags = bytes[0];
+        let packet_size = u16::from_be_bytes(bytes[1..3].try_into().unwrap());
+
+        Some((Self {
+            flags,
+            packet_size
+        }, &bytes[PACKET_HEADER_SIZE..]))
+    }
+}
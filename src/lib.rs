@@ -4,5 +4,5 @@ mod rx;
 mod tx;
 
 #[cfg(feature = "receiver")]
-pub use rx::Receiver;
-pub use tx::Transmitter;
+pub use rx::{AntennaStats, CardStats, FecStats, QueueStats, Receiver, RejectedFrames, RxConfig, RxDevice, RxOutput, RxRunConfig, RxStats};
+pub use tx::{Transmitter, TxConfig, TxDevice, TxInput, TxRunConfig, TxStats};
@@ -1,112 +1,830 @@
 mod rx_hardware_interface;
 mod rx_fec;
+mod rx_crypto;
+mod rx_rs_fec;
+mod rx_diversity;
+mod rx_queue;
 
+use std::collections::HashMap;
 use std::net::UdpSocket;
-use std::sync::mpsc::channel;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use rx_hardware_interface::RXHwInt;
+use rx_hardware_interface::{PcapCapture, RXFrame, RXHwInt};
 use rx_fec::RXFec;
-use crate::common::magic_header::MagicHeader;
+use rx_crypto::RXCrypto;
+use rx_rs_fec::RXRsFec;
+use rx_diversity::DuplicateFilter;
+pub use rx_diversity::{AntennaStats, CardStats};
+use rx_queue::{QueueReceiver, QueueSender};
+pub use rx_queue::QueueStats;
+use crate::common::bandwidth::Bandwidth;
+use crate::common::fec::FecMode;
+use crate::common::feedback::{FecFeedback, FEEDBACK_INTERVAL};
+use crate::common::hopping::{HopFollower, HopSchedule, SyncData};
+use crate::common::control::{self, ControlCommand};
+use crate::common::keypair::{KeyRole, Keypair};
+use crate::common::magic_header::{MagicHeader, PacketType};
+use crate::common::session::{SessionFec, SessionInfo};
+use crate::common::metrics::{self, MetricsWriter};
+use crate::common::radio::{FcsMode, RadioRx};
+use crate::common::stats::{self, StatsHandle, StatsSocket};
+use crate::common::tun::Tun;
+use crate::common::utils::{self, WifiSetup};
+use crate::common::wfb_ng;
+
+// Frames waiting for decoding, from all cards together
+const CAPTURE_QUEUE_SIZE: usize = 1024;
+// Decoded packets waiting to be forwarded
+const FORWARD_QUEUE_SIZE: usize = 1024;
+
+// Number of frames the receiver dropped, by reason
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RejectedFrames {
+    pub unknown_magic: u64,     // no wfb packet or another magic
+    pub unauthenticated: u64,   // failed authentication
+    pub replayed: u64,          // nonce was already received or is outside the replay window
+    pub unknown_session: u64,   // data packet of a session we have no key for (yet)
+    pub rejected_sessions: u64, // authentic session packet of an older epoch or another channel
+    pub invalid_fec: u64,       // malformed fec header or packet
+}
+
+// Fec blocks since the start
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FecStats {
+    pub completed: u64,     // blocks decoded
+    pub recovered: u64,     // decoded blocks that were missing source packets
+    pub lost: u64,          // blocks that could not be decoded
+    pub repair_used: u64,   // repair packets the decoded blocks needed
+}
+
+// Where the receiver forwards the decoded packets
+pub enum RxOutput {
+    Udp(String),    // udp address, host:port
+    Tun(Arc<Tun>),  // ip packets of the tunnel mode
+}
+
+// Where the frames come from, one entry per card
+pub enum RxDevice {
+    Wifi(String),               // monitor mode card, capturing on it needs root
+    Radio(Box<dyn RadioRx>),    // any other radio, e.g. a simulated channel
+}
+
+// Settings of a receiver, the defaults are the ones of wfb_rs_rx
+#[derive(Debug, Clone)]
+pub struct RxConfig {
+    pub magic: u32,
+    pub radio_port: u8,
+    pub link_id: u32,
+    pub wifi_setup: Option<WifiSetup>,  // only for wifi cards
+    pub bandwidth: Bandwidth,           // of the transmitter, the wifi setup tunes to a channel this wide
+    pub fec_mode: FecMode,
+    pub forward_partial: bool,
+    pub interleave_depth: usize,
+    pub key_file: Option<String>,       // ground station key, enables decryption
+    pub fcs: FcsMode,
+}
+
+impl Default for RxConfig {
+    fn default() -> Self {
+        Self {
+            magic: 0x57627273,
+            radio_port: 0,
+            link_id: 7669206,
+            wifi_setup: None,
+            bandwidth: Bandwidth::Bw20,
+            fec_mode: FecMode::Raptorq,
+            forward_partial: false,
+            interleave_depth: 1,
+            key_file: None,
+            fcs: FcsMode::Always,
+        }
+    }
+}
+
+// Where Receiver::run reports and takes queries, all off by default
+#[derive(Debug, Clone)]
+pub struct RxRunConfig {
+    pub feedback_address: Option<String>,   // host:port of the transmitter's feedback port
+    pub control_port: Option<u16>,
+    pub stats_address: Option<String>,
+    pub metrics_address: Option<String>,
+    pub log_interval: Duration,
+}
+
+impl Default for RxRunConfig {
+    fn default() -> Self {
+        Self {
+            feedback_address: None,
+            control_port: None,
+            stats_address: None,
+            metrics_address: None,
+            log_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+enum OutputSocket {
+    Udp(UdpSocket),
+    Tun(Arc<Tun>),
+}
+
+// Statistics of the receiver since the start, see Receiver::stats
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RxStats {
+    pub channel_id: u32,
+    pub session: Option<SessionInfo>,   // latest accepted session, only with encryption
+    pub frequency: u32,             // MHz, follows the frequency hopping, 0 without wifi setup
+    pub frames_in: u64,             // frames of all cards, duplicates included
+    pub bytes_in: u64,
+    pub duplicates: u64,            // frames another card delivered first
+    pub packets_out: u64,           // forwarded udp packets
+    pub bytes_out: u64,
+    pub send_errors: u64,           // udp packets that could not be forwarded
+    pub fec: FecStats,
+    pub fec_pending_blocks: usize,  // blocks the decoder still waits for
+    pub rejected: RejectedFrames,
+    pub cards: Vec<CardStats>,      // reception and RSSI of every card
+    pub capture_queue: QueueStats,  // frames waiting for decoding
+    pub forward_queue: QueueStats,  // udp packets waiting for forwarding
+}
+
+impl RxStats {
+    pub fn to_prometheus(&self) -> String {
+        let mut metrics = MetricsWriter::default();
+        metrics.counter("wfb_rx_frames_total", "Frames received on all cards, duplicates included", self.frames_in);
+        metrics.counter("wfb_rx_frame_bytes_total", "Bytes of the received frames", self.bytes_in);
+        metrics.counter("wfb_rx_duplicate_frames_total", "Frames another card delivered first", self.duplicates);
+        metrics.counter("wfb_rx_packets_out_total", "Forwarded udp packets", self.packets_out);
+        metrics.counter("wfb_rx_bytes_out_total", "Bytes of the forwarded udp packets", self.bytes_out);
+        metrics.counter("wfb_rx_send_errors_total", "Udp packets that could not be forwarded", self.send_errors);
+        metrics.counter("wfb_rx_fec_blocks_completed_total", "Decoded fec blocks", self.fec.completed);
+        metrics.counter("wfb_rx_fec_blocks_recovered_total", "Decoded fec blocks that were missing source packets", self.fec.recovered);
+        metrics.counter("wfb_rx_fec_blocks_lost_total", "Fec blocks that could not be decoded", self.fec.lost);
+        metrics.counter("wfb_rx_fec_repair_used_total", "Repair packets the decoded blocks needed", self.fec.repair_used);
+        metrics.gauge("wfb_rx_fec_pending_blocks", "Fec blocks the decoder still waits for", self.fec_pending_blocks);
+        metrics.gauge("wfb_rx_frequency_mhz", "Current channel frequency in MHz", self.frequency);
+        let rejected = &self.rejected;
+        metrics.family("wfb_rx_rejected_frames_total", "counter", "Dropped frames by reason", [
+            ("reason=\"unknown_magic\"", rejected.unknown_magic),
+            ("reason=\"unauthenticated\"", rejected.unauthenticated),
+            ("reason=\"replayed\"", rejected.replayed),
+            ("reason=\"unknown_session\"", rejected.unknown_session),
+            ("reason=\"rejected_session\"", rejected.rejected_sessions),
+            ("reason=\"invalid_fec\"", rejected.invalid_fec),
+        ]);
+        metrics.family("wfb_rx_queue_depth", "gauge", "Items waiting between the receiver stages", [
+            ("stage=\"capture\"", self.capture_queue.depth),
+            ("stage=\"forward\"", self.forward_queue.depth),
+        ]);
+        metrics.family("wfb_rx_queue_dropped_total", "counter", "Items dropped because a stage queue was full", [
+            ("stage=\"capture\"", self.capture_queue.dropped),
+            ("stage=\"forward\"", self.forward_queue.dropped),
+        ]);
+        metrics.family("wfb_rx_card_frames_total", "counter", "Frames received per card", self.cards
+            .iter()
+            .enumerate()
+            .map(|(card, stats)| (format!("card=\"{}\"", card), stats.packets)));
+
+        let antennas: Vec<(String, &AntennaStats)> = self.cards
+            .iter()
+            .enumerate()
+            .flat_map(|(card, stats)| stats.antennas
+                .iter()
+                .map(move |(antenna, stats)| (format!("card=\"{}\",antenna=\"{}\"", card, antenna), stats)))
+            .collect();
+        metrics.family("wfb_rx_antenna_frames_total", "counter", "Frames received per antenna",
+            antennas.iter().map(|(labels, stats)| (labels, stats.packets)));
+        metrics.family("wfb_rx_antenna_rssi_dbm_sum", "gauge", "Sum of the RSSI of all frames, divide by the frames for the average",
+            antennas.iter().map(|(labels, stats)| (labels, stats.rssi_sum)));
+        metrics.family("wfb_rx_antenna_rssi_dbm", "gauge", "RSSI of the latest frame",
+            antennas.iter().map(|(labels, stats)| (labels, stats.rssi_last)));
+        metrics.family("wfb_rx_antenna_noise_dbm", "gauge", "Noise of the latest frame that reported it",
+            antennas.iter().filter_map(|(labels, stats)| Some((labels, stats.noise?))));
+        metrics.finish()
+    }
+
+    // Record of the changes since `last`, laid out like the rx records of wfb-ng's json stats.
+    // Antennas are numbered (card << 8) | antenna, their RSSI average covers the interval.
+    pub fn to_json(&self, last: &RxStats) -> String {
+        let rejected = |r: &RejectedFrames| (r.unauthenticated + r.replayed + r.unknown_session, r.unknown_magic + r.invalid_fec);
+        let (dec_err, bad) = rejected(&self.rejected);
+        let (last_dec_err, last_bad) = rejected(&last.rejected);
+        let packets = [
+            stats::json_counter("all", self.frames_in, last.frames_in),
+            stats::json_counter("all_bytes", self.bytes_in, last.bytes_in),
+            stats::json_counter("dup", self.duplicates, last.duplicates),
+            stats::json_counter("dec_err", dec_err, last_dec_err),
+            stats::json_counter("bad", bad, last_bad),
+            stats::json_counter("fec_ok", self.fec.completed, last.fec.completed),
+            stats::json_counter("fec_rec", self.fec.repair_used, last.fec.repair_used),
+            stats::json_counter("lost", self.fec.lost, last.fec.lost),
+            stats::json_counter("out", self.packets_out, last.packets_out),
+            stats::json_counter("out_bytes", self.bytes_out, last.bytes_out),
+            stats::json_counter("out_err", self.send_errors, last.send_errors),
+        ];
+
+        let mut antennas = Vec::new();
+        for (card, card_stats) in self.cards.iter().enumerate() {
+            for (&antenna, antenna_stats) in &card_stats.antennas {
+                let (last_packets, last_rssi_sum) = last.cards
+                    .get(card)
+                    .and_then(|last_card| last_card.antennas.get(&antenna))
+                    .map_or((0, 0), |last| (last.packets, last.rssi_sum));
+                let packets = antenna_stats.packets - last_packets;
+                if packets == 0 {
+                    continue;
+                }
+                antennas.push(format!(
+                    "{{\"ant\":{},\"pkt_recv\":{},\"rssi_min\":{},\"rssi_avg\":{},\"rssi_max\":{},\"noise\":{}}}",
+                    (card as u32) << 8 | antenna as u32,
+                    packets,
+                    antenna_stats.rssi_min,
+                    (antenna_stats.rssi_sum - last_rssi_sum) / packets as i64,
+                    antenna_stats.rssi_max,
+                    antenna_stats.noise.map_or("null".to_string(), |noise| noise.to_string()),
+                ));
+            }
+        }
+
+        format!(
+            "{{\"type\":\"rx\",\"timestamp\":{:.3},\"id\":\"{:#x}\",\"packets\":{{{}}},\"rx_ant_stats\":[{}],\"frequency\":{},\"session\":{}}}",
+            stats::json_timestamp(),
+            self.channel_id,
+            packets.join(","),
+            antennas.join(","),
+            self.frequency,
+            self.session.map_or("null".to_string(), |session| session.to_json()),
+        )
+    }
+}
+
+// Queues of the streams by radio port, the capture threads sort the frames of all cards into them
+// and stop once every stream is gone
+type Routes = Arc<RwLock<HashMap<u8, QueueSender<(usize, RXFrame)>>>>;
+
+// Settings the streams added with add_stream take over from the first one
+#[derive(Clone)]
+struct SharedSettings {
+    magic: u32,
+    link_id: u32,
+    forward_partial: bool,
+    interleave_depth: usize,
+    keypair: Option<Arc<Keypair>>,
+}
 
 pub struct Receiver {
-    rxs: Vec<RXHwInt>,
+    frames: QueueReceiver<(usize, RXFrame)>,
+    routes: Routes,
+    shared: SharedSettings,
+    cards: usize,
+    stats: Arc<Mutex<RxStats>>,
+    duplicates: DuplicateFilter,
     fec: RXFec,
+    rs_fec: Option<RXRsFec>,
+    crypto: Option<RXCrypto>,
     magic_header: MagicHeader,
+    fec_mode: FecMode,
+    channel_id: u32,
+    session: Option<SessionInfo>,
+    unknown_magic: u64,
+    invalid_rs_fec: u64,
+    rssi_sum: i64,      // signal of the best antenna of the frames since the last feedback
+    rssi_frames: u32,
+    hopper: Option<HopFollower>,
 }
 
 impl Receiver {
-    pub fn new(
-        magic: u32,
-        radio_port: u16,
-        link_id: u32,
-        wifi_devices: Vec<String>,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
-        let channel_id = link_id << 8 | radio_port as u32;
+    pub fn new(devices: Vec<RxDevice>, config: RxConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let RxConfig {
+            magic,
+            radio_port,
+            link_id,
+            wifi_setup,
+            bandwidth,
+            fec_mode,
+            forward_partial,
+            interleave_depth,
+            key_file,
+            fcs,
+        } = config;
+        let link_id = link_id & 0xffffff;
 
-        let rxs: Vec<RXHwInt> = wifi_devices
+        let hopping = wifi_setup
+            .as_ref()
+            .and_then(|wifi_setup| wifi_setup.hopping.as_ref().map(|hopping| (hopping, wifi_setup.ht40)));
+        if hopping.is_some() && fec_mode == FecMode::Rs {
+            return Err("Frequency hopping is not available in the rs fec mode".into());
+        }
+        let wifi_devices: Vec<String> = devices
+            .iter()
+            .filter_map(|device| match device {
+                RxDevice::Wifi(wifi_device) => Some(wifi_device.clone()),
+                RxDevice::Radio(_) => None,
+            })
+            .collect();
+        let mut frequency = 0;
+        if let Some(wifi_setup) = &wifi_setup {
+            if wifi_devices.is_empty() {
+                return Err("The wifi setup needs a wifi card".into());
+            }
+            for wifi in &wifi_devices {
+                let channel = utils::setup_wifi(wifi, wifi_setup, bandwidth)?;
+                println!("{} is on {}", wifi, channel);
+                frequency = channel.frequency;
+            }
+        }
+
+        let rxs: Vec<RXHwInt> = devices
             .into_iter()
-            .map(|dev| RXHwInt::new(dev, channel_id))
-            .collect::<Result<_, _>>()?;
+            .map(|device| {
+                let radio: Box<dyn RadioRx> = match device {
+                    RxDevice::Wifi(wifi_device) => Box::new(PcapCapture::open(&wifi_device, link_id)?),
+                    RxDevice::Radio(radio) => radio,
+                };
+                Ok(RXHwInt::new(radio, link_id, fcs))
+            })
+            .collect::<Result<_, Box<dyn std::error::Error>>>()?;
+        let cards = rxs.len();
+
+        // every card captures in its own thread, the frames of all of them meet in one queue per stream
+        let routes = Routes::default();
+        let keypair = match key_file {
+            Some(key_file) => Some(Arc::new(Keypair::load(&key_file, KeyRole::GroundStation)?)),
+            None => None,
+        };
+        let shared = SharedSettings {
+            magic,
+            link_id,
+            forward_partial,
+            interleave_depth: interleave_depth.max(1),
+            keypair,
+        };
+
+        // only the main stream hops, the others share its cards
+        let hopper = match hopping {
+            Some((hopping, ht40)) => {
+                let schedule = HopSchedule::new(hopping, bandwidth, ht40, shared.keypair.as_deref().ok_or("Frequency hopping requires a key file")?)?;
+                let hopper = HopFollower::new(schedule, wifi_devices)?;
+                println!("Hopping every {} ms, scanning for the transmitter", hopping.dwell.as_millis());
+                frequency = hopper.channel().frequency;
+                Some(hopper)
+            }
+            None => None,
+        };
+
+        let mut receiver = Self::new_stream(routes.clone(), shared, cards, radio_port, fec_mode)?;
+        receiver.stats.lock().unwrap().frequency = frequency;
+        receiver.hopper = hopper;
+
+        for (card, rx) in rxs.into_iter().enumerate() {
+            Self::spawn_capture(card, rx, routes.clone());
+        }
+
+        Ok(receiver)
+    }
+
+    // Another stream of the same cards on its own radio port, the fec mode has to match the one of
+    // the transmitter. The stream runs on its own, e.g. in another thread, and has its own statistics.
+    pub fn add_stream(&self, radio_port: u8, fec_mode: FecMode) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::new_stream(self.routes.clone(), self.shared.clone(), self.cards, radio_port, fec_mode)
+    }
+
+    fn new_stream(
+        routes: Routes,
+        shared: SharedSettings,
+        cards: usize,
+        radio_port: u8,
+        fec_mode: FecMode,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        // the Reed-Solomon mode speaks the wfb-ng protocol, which always encrypts
+        let wfb_ng = fec_mode == FecMode::Rs;
+        if wfb_ng && shared.keypair.is_none() {
+            return Err("Reed-Solomon fec mode requires a key file".into());
+        }
+
+        let channel_id = shared.link_id << 8 | radio_port as u32;
+
+        let (frames_s, frames) = rx_queue::queue(CAPTURE_QUEUE_SIZE);
+        {
+            let mut routes = routes.write().unwrap();
+            if routes.contains_key(&radio_port) {
+                return Err(format!("Radio port {} is already received", radio_port).into());
+            }
+            routes.insert(radio_port, frames_s);
+        }
 
+        let fec = RXFec::new(shared.forward_partial, shared.interleave_depth as u64);
 
-        let fec = RXFec::new();
+        let crypto = shared.keypair.as_ref().map(|keypair| RXCrypto::new(keypair, channel_id, wfb_ng));
 
-        let magic_header = MagicHeader::new(magic);
+        let magic_header = MagicHeader::new(shared.magic);
 
         Ok(Self {
-            rxs,
+            frames,
+            routes,
+            shared,
+            cards,
+            stats: Arc::new(Mutex::new(RxStats {
+                channel_id,
+                cards: vec![CardStats::default(); cards],
+                ..Default::default()
+            })),
+            duplicates: DuplicateFilter::new(),
             fec,
+            rs_fec: None,
+            crypto,
             magic_header,
+            fec_mode,
+            channel_id,
+            session: None,
+            unknown_magic: 0,
+            invalid_rs_fec: 0,
+            rssi_sum: 0,
+            rssi_frames: 0,
+            hopper: None,
         })
     }
 
-    pub fn run(mut self,
-        client_address: String,
-        client_port: u16,
-        log_interval: Duration)
-        -> Result<(), Box<dyn std::error::Error>> {
+    pub fn run(mut self, output: RxOutput, config: RxRunConfig) -> Result<(), Box<dyn std::error::Error>> {
+        let RxRunConfig { feedback_address, control_port, stats_address, metrics_address, log_interval } = config;
 
-        let udp_socket = UdpSocket::bind("0.0.0.0:0")?; // Bind to any available port
-        
-        let compound_output_address = format!("{}:{}", client_address, client_port);
-        udp_socket.connect(&compound_output_address)?;
+        let output = match output {
+            RxOutput::Udp(client_address) => {
+                let udp_socket = UdpSocket::bind("0.0.0.0:0")?; // Bind to any available port
+                udp_socket.connect(&client_address)?;
+                OutputSocket::Udp(udp_socket)
+            }
+            RxOutput::Tun(tun) => OutputSocket::Tun(tun),
+        };
+
+        // return channel for the fec statistics, used by the transmitter to adapt its redundancy
+        let feedback_socket = match feedback_address {
+            Some(feedback_address) => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect(&feedback_address)?;
+                Some(socket)
+            }
+            None => None,
+        };
+        let mut last_feedback = Instant::now();
         
-        let (sent_bytes_s, sent_bytes_r) = channel();
-        let (received_bytes_s, received_bytes_r) = channel();
+        // forwarding runs in its own stage, so a slow udp socket doesn't hold up decoding
+        let (forward_s, forward_r) = rx_queue::queue::<Vec<u8>>(FORWARD_QUEUE_SIZE);
+        let stats = self.stats.clone();
+        thread::spawn(move || {
+            while let Ok(udp_pkg) = forward_r.recv() {
+                let result = match &output {
+                    OutputSocket::Udp(udp_socket) => udp_socket.send(&udp_pkg),
+                    OutputSocket::Tun(tun) => tun.send(&udp_pkg),
+                };
+                let mut stats = stats.lock().unwrap();
+                match result {
+                    Err(e) => {
+                        eprintln!("Error forwarding packet: {}", e);
+                        stats.send_errors += 1;
+                    }
+                    Ok(sent) => {
+                        stats.packets_out += 1;
+                        stats.bytes_out += sent as u64;
+                    }
+                }
+                stats.forward_queue = forward_r.stats();
+            }
+        });
+
+        // json records for ground station tools, next to the log lines
+        let stats_socket = match stats_address {
+            Some(stats_address) => Some(StatsSocket::connect(&stats_address)?),
+            None => None,
+        };
+
+        if let Some(metrics_address) = metrics_address {
+            let stats = self.stats();
+            metrics::serve(&metrics_address, move || stats.get().to_prometheus())?;
+        }
+
+        // the receiver has nothing to change at runtime, it only answers queries
+        if let Some(control_port) = control_port {
+            let control_r = control::listen(control_port)?;
+            let stats = self.stats();
+            thread::spawn(move || {
+                while let Ok(request) = control_r.recv() {
+                    request.reply(match request.command {
+                        ControlCommand::GetStats => Ok(stats.get().to_json(&RxStats::default())),
+                        ControlCommand::Set(_) => Err("the receiver has no settings to change".to_string()),
+                    });
+                }
+            });
+        }
 
         // start logtask
+        let stats = self.stats();
         thread::spawn(move || {
+            let mut last = stats.get();
             loop {
-                let (sent_packets, sent_bytes): (u32, u32) = sent_bytes_r.try_iter().fold((0, 0), |(count, sum), v| (count + 1, sum + v));
-                let (received_packets, received_bytes): (u32, u32) = received_bytes_r.try_iter().fold((0, 0), |(count, sum), v| (count + 1, sum + v));
+                thread::sleep(log_interval);
+                let current = stats.get();
                 println!(
-                    "Packets R->T {}->{},\tBytes {}->{}",
-                    received_packets,
-                    sent_packets,
-                    received_bytes,
-                    sent_bytes,
+                    "Port {}: Packets R->T {}->{},\tBytes {}->{}",
+                    current.channel_id & 0xff,
+                    current.frames_in - last.frames_in,
+                    current.packets_out - last.packets_out,
+                    current.bytes_in - last.bytes_in,
+                    current.bytes_out - last.bytes_out,
                 );
-                thread::sleep(log_interval);
+                if current.rejected != last.rejected {
+                    println!("Rejected frames: {:?}", current.rejected);
+                }
+                for (card, (card_stats, last_card)) in current.cards.iter().zip(&last.cards).enumerate() {
+                    println!(
+                        "Card {}: {} packets, {} unique",
+                        card,
+                        card_stats.packets - last_card.packets,
+                        card_stats.unique - last_card.unique,
+                    );
+                    for (antenna, antenna_stats) in &card_stats.antennas {
+                        let (last_packets, last_rssi_sum) = last_card.antennas
+                            .get(antenna)
+                            .map_or((0, 0), |last| (last.packets, last.rssi_sum));
+                        let packets = antenna_stats.packets - last_packets;
+                        if packets == 0 {
+                            continue;
+                        }
+                        println!(
+                            "  Antenna {}: {} packets, RSSI {} dBm, noise {} dBm",
+                            antenna,
+                            packets,
+                            (antenna_stats.rssi_sum - last_rssi_sum) / packets as i64,
+                            antenna_stats.noise.map_or("-".to_string(), |noise| noise.to_string()),
+                        );
+                    }
+                }
+                println!(
+                    "Queues: capture {} (max {}, {} dropped), forward {} (max {}, {} dropped)",
+                    current.capture_queue.depth,
+                    current.capture_queue.max_depth,
+                    current.capture_queue.dropped - last.capture_queue.dropped,
+                    current.forward_queue.depth,
+                    current.forward_queue.max_depth,
+                    current.forward_queue.dropped - last.forward_queue.dropped,
+                );
+                if let Some(stats_socket) = &stats_socket {
+                    stats_socket.send(&current.to_json(&last));
+                }
+                last = current;
             }
         });
 
         loop {
-            let (decoded_data, received_bytes) = self.recv()?;
-            received_bytes_s.send(received_bytes)?;
+            // a link that decodes nothing is reported as well, so the transmitter can react to it
+            let decoded_data = match &feedback_socket {
+                Some(_) => self.recv_timeout(FEEDBACK_INTERVAL.saturating_sub(last_feedback.elapsed()))?.unwrap_or_default(),
+                None => self.recv()?,
+            };
 
-            for udp_pkg in decoded_data {
-                match udp_socket.send(&udp_pkg) {
-                    Err(e) => {
-                        eprintln!("Error forwarding packet: {}", e);
-                    }
-                    Ok(sent) => {
-                        sent_bytes_s.send(sent as u32)?;
+            if let Some(feedback_socket) = &feedback_socket {
+                if last_feedback.elapsed() >= FEEDBACK_INTERVAL {
+                    last_feedback = Instant::now();
+                    if let Err(e) = feedback_socket.send(&self.feedback().to_bytes()) {
+                        eprintln!("Error sending feedback: {}", e);
                     }
                 }
             }
+
+            for udp_pkg in decoded_data {
+                if !forward_s.send(udp_pkg) {
+                    return Err("Forwarding stopped".into());
+                }
+            }
+        }
+    }
+
+    // Returns the link report since the last call
+    pub fn feedback(&mut self) -> FecFeedback {
+        let fec = match self.rs_fec.as_mut() {
+            Some(rs_fec) => rs_fec.take_feedback(),
+            None => self.fec.take_feedback(),
+        };
+        let rssi = (self.rssi_frames > 0).then(|| (self.rssi_sum / self.rssi_frames as i64) as i8);
+        self.rssi_sum = 0;
+        self.rssi_frames = 0;
+        FecFeedback {
+            channel_id: self.channel_id,
+            rssi,
+            ..fec
+        }
+    }
+
+    // Returns a handle to the statistics, which stays valid while the receiver runs
+    pub fn stats(&self) -> StatsHandle<RxStats> {
+        StatsHandle::new(self.stats.clone())
+    }
+
+    fn fec_stats(&self) -> FecStats {
+        match &self.rs_fec {
+            Some(rs_fec) => rs_fec.stats,
+            None => self.fec.stats,
+        }
+    }
+
+    pub fn rejected(&self) -> RejectedFrames {
+        let mut rejected = RejectedFrames {
+            unknown_magic: self.unknown_magic,
+            invalid_fec: self.fec.invalid_packets
+                + self.invalid_rs_fec
+                + self.rs_fec.as_ref().map_or(0, |rs_fec| rs_fec.invalid_packets),
+            ..Default::default()
+        };
+        if let Some(crypto) = &self.crypto {
+            rejected.unauthenticated = crypto.unauthenticated;
+            rejected.replayed = crypto.replayed;
+            rejected.unknown_session = crypto.unknown_session;
+            rejected.rejected_sessions = crypto.rejected_sessions;
+        }
+        rejected
+    }
+
+    // Blocks until frames decode to udp packets
+    pub fn recv(&mut self) -> Result<Vec<Vec<u8>>, Box<dyn std::error::Error>> {
+        loop {
+            if let Some(decoded_data) = self.recv_until(None)? {
+                return Ok(decoded_data);
+            }
         }
     }
 
-    pub fn recv(&mut self) -> Result<(Vec<Vec<u8>>, u32), Box<dyn std::error::Error>> {
-        let mut received_bytes = 0;
+    // Like recv, but gives up after the timeout. None if no packets were decoded until then.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<Vec<Vec<u8>>>, Box<dyn std::error::Error>> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    fn recv_until(&mut self, deadline: Option<Instant>) -> Result<Option<Vec<Vec<u8>>>, Box<dyn std::error::Error>> {
         loop {
-            for rx in &mut self.rxs {
-                let Some(raw_packet) = rx.receive_packet()? else { continue; };
-                received_bytes += raw_packet.len() as u32;
-
-                let Some((fec_pkg, wfb_packet)) = self.magic_header.from_bytes(&raw_packet) else { continue; };
-                
-                let decoded_data = if fec_pkg {
-                    let Some(decoded_data) = self.fec.process_fec_packet(&wfb_packet) else { continue; };
-                    decoded_data
-                } else {
-                    vec![wfb_packet.to_vec()]
-                };
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(None);
+            }
+            let Some((card, frame)) = self.next_frame(deadline)? else { continue };
 
-                return Ok((decoded_data, received_bytes));
+            if let Some(signal) = frame.antennas.iter().map(|antenna| antenna.signal).max() {
+                self.rssi_sum += signal as i64;
+                self.rssi_frames += 1;
+            }
+
+            // with several cards the same frame usually arrives more than once, the first one wins
+            let unique = self.cards == 1 || self.duplicates.insert(&frame);
+            let decoded_data = if unique { self.process_frame(&frame.payload) } else { None };
+
+            let mut stats = self.stats.lock().unwrap();
+            stats.frames_in += 1;
+            stats.bytes_in += frame.payload.len() as u64;
+            stats.duplicates += !unique as u64;
+            stats.cards[card].add_frame(&frame.antennas, unique);
+            stats.fec = self.fec_stats();
+            stats.fec_pending_blocks = self.rs_fec.as_ref().map_or(self.fec.pending_blocks(), |rs_fec| rs_fec.pending_blocks());
+            stats.rejected = self.rejected();
+            stats.capture_queue = self.frames.stats();
+            stats.session = self.session;
+            drop(stats);
+
+            if decoded_data.is_some() {
+                return Ok(decoded_data);
             }
         }
     }
+
+    // Waits for the next frame, while hopping only until the next hop is due
+    fn next_frame(&mut self, deadline: Option<Instant>) -> Result<Option<(usize, RXFrame)>, Box<dyn std::error::Error>> {
+        let next_hop = self.hopper.as_mut().map(|hopper| {
+            hopper.poll();
+            (hopper.channel().frequency, hopper.next_hop())
+        });
+        if let Some((frequency, _)) = next_hop {
+            self.stats.lock().unwrap().frequency = frequency;
+        }
+
+        let Some(until) = next_hop.map(|(_, next_hop)| next_hop).into_iter().chain(deadline).min() else {
+            return Ok(Some(self.frames.recv().map_err(|_| "All capture threads stopped")?));
+        };
+        match self.frames.recv_timeout(until.saturating_duration_since(Instant::now())) {
+            Ok(frame) => Ok(Some(frame)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err("All capture threads stopped".into()),
+        }
+    }
+
+    // Authenticates and decodes one frame, returns the udp packets it completes
+    fn process_frame(&mut self, raw_packet: &[u8]) -> Option<Vec<Vec<u8>>> {
+        if self.fec_mode == FecMode::Rs {
+            return self.process_wfb_ng_packet(raw_packet);
+        }
+
+        let Some((packet_type, wfb_packet)) = self.magic_header.from_bytes(raw_packet) else {
+            self.unknown_magic += 1;
+            return None;
+        };
+
+        if packet_type == PacketType::Session {
+            let crypto = self.crypto.as_mut()?;
+            if let Some(session) = crypto.process_session_packet(wfb_packet) {
+                println!("New session, epoch {}, fec {:?}", session.epoch, session.fec);
+                self.session = Some(SessionInfo::from(&session));
+            }
+            return None;
+        }
+
+        // frames that fail authentication are dropped here
+        let wfb_packet = match self.crypto.as_mut() {
+            Some(crypto) => {
+                let magic_header = &raw_packet[..raw_packet.len() - wfb_packet.len()];
+                crypto.decrypt_packet(magic_header, wfb_packet)?
+            }
+            None => wfb_packet.to_vec(),
+        };
+
+        match packet_type {
+            PacketType::Fec => self.fec.process_fec_packet(&wfb_packet),
+            PacketType::LegacyFec => self.fec.process_legacy_fec_packet(&wfb_packet),
+            // never forwarded, streams of other radio ports just drop them
+            PacketType::Sync => {
+                let sync = SyncData::from_bytes(&wfb_packet)?;
+                self.hopper.as_mut()?.process_sync(&sync);
+                None
+            }
+            _ => Some(vec![wfb_packet]),
+        }
+    }
+
+    fn spawn_capture(card: usize, mut rx: RXHwInt, routes: Routes) {
+        thread::spawn(move || {
+            while !routes.read().unwrap().is_empty() {
+                match rx.receive_packet() {
+                    Ok(Some(frame)) => {
+                        let radio_port = frame.radio_port;
+                        let disconnected = match routes.read().unwrap().get(&radio_port) {
+                            Some(frames) => !frames.send((card, frame)),
+                            None => false,  // nobody receives this radio port
+                        };
+                        if disconnected {
+                            routes.write().unwrap().remove(&radio_port);
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => eprintln!("Error reading frame of card {}: {}", card, e),
+                }
+            }
+        });
+    }
+
+    // wfb-ng packets carry no magic header, the first byte is the packet type
+    fn process_wfb_ng_packet(&mut self, raw_packet: &[u8]) -> Option<Vec<Vec<u8>>> {
+        let crypto = self.crypto.as_mut()?;
+
+        match raw_packet.first() {
+            Some(&wfb_ng::PACKET_TYPE_SESSION) => {
+                let session = crypto.process_session_packet(&raw_packet[1..])?;
+                println!("New session, epoch {}, fec {:?}", session.epoch, session.fec);
+                self.session = Some(SessionInfo::from(&session));
+
+                // block indices start over with a new session
+                let SessionFec::ReedSolomon { k, n } = session.fec else { return None };
+                let old = self.rs_fec.take();
+                if let Some(old) = &old {
+                    self.invalid_rs_fec += old.invalid_packets;
+                }
+                match RXRsFec::new(k, n) {
+                    Ok(mut rs_fec) => {
+                        rs_fec.stats = old.map_or_else(FecStats::default, |old| old.stats);
+                        self.rs_fec = Some(rs_fec);
+                    }
+                    Err(e) => {
+                        eprintln!("Session with unusable fec: {}", e);
+                        self.invalid_rs_fec += 1;
+                    }
+                }
+                None
+            }
+            Some(&wfb_ng::PACKET_TYPE_DATA) => {
+                let (data_nonce, fragment) = crypto.decrypt_fragment(raw_packet)?;
+                let rs_fec = self.rs_fec.as_mut()?;
+                let decoded_data = rs_fec.process_fragment(data_nonce, fragment);
+                if decoded_data.is_empty() {
+                    return None;
+                }
+                Some(decoded_data)
+            }
+            _ => {
+                self.unknown_magic += 1;
+                None
+            }
+        }
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.routes.write().unwrap().remove(&(self.channel_id as u8));
+    }
 }
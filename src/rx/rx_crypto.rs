@@ -0,0 +1,335 @@
+use crypto_box::SalsaBox;
+
+use crate::common::crypto::{self, CryptoHeader};
+use crate::common::keypair::Keypair;
+use crate::common::session::{self, SessionData};
+use crate::common::wfb_ng::{self, BlockHeader};
+
+// Number of nonces below the highest one that are still accepted, covers reordering between cards
+const REPLAY_WINDOW_SIZE: u64 = 1024;
+const REPLAY_WINDOW_WORDS: usize = REPLAY_WINDOW_SIZE as usize / 64;
+
+// Sliding window over the packet nonces of one session, every nonce is accepted only once
+struct ReplayWindow {
+    highest: Option<u64>,
+    bitmap: [u64; REPLAY_WINDOW_WORDS],
+}
+
+impl ReplayWindow {
+    fn new() -> Self {
+        Self {
+            highest: None,
+            bitmap: [0; REPLAY_WINDOW_WORDS],
+        }
+    }
+
+    fn bit(nonce: u64) -> (usize, u64) {
+        let index = nonce % REPLAY_WINDOW_SIZE;
+        ((index / 64) as usize, 1 << (index % 64))
+    }
+
+    fn is_replay(&self, nonce: u64) -> bool {
+        let Some(highest) = self.highest else { return false };
+        if nonce > highest {
+            return false;
+        }
+        if highest - nonce >= REPLAY_WINDOW_SIZE {
+            return true;
+        }
+        let (word, mask) = Self::bit(nonce);
+        self.bitmap[word] & mask != 0
+    }
+
+    // only called for authenticated packets, so a forged nonce can't move the window
+    fn mark(&mut self, nonce: u64) {
+        match self.highest {
+            Some(highest) if nonce <= highest => (),
+            Some(highest) => {
+                // forget the nonces that are shifted out of the window
+                for cleared in (highest + 1..=nonce).take(REPLAY_WINDOW_SIZE as usize) {
+                    let (word, mask) = Self::bit(cleared);
+                    self.bitmap[word] &= !mask;
+                }
+                self.highest = Some(nonce);
+            }
+            None => self.highest = Some(nonce),
+        }
+        let (word, mask) = Self::bit(nonce);
+        self.bitmap[word] |= mask;
+    }
+}
+
+pub(super) struct RXCrypto {
+    session_box: SalsaBox,
+    channel_id: u32,
+    session: Option<SessionData>,
+    replay_window: ReplayWindow,
+    pub unauthenticated: u64,
+    pub replayed: u64,
+    pub unknown_session: u64,
+    pub rejected_sessions: u64,
+    wfb_ng: bool,
+}
+
+impl RXCrypto {
+    pub fn new(keypair: &Keypair, channel_id: u32, wfb_ng: bool) -> Self {
+        Self {
+            session_box: session::session_box(keypair),
+            channel_id,
+            session: None,
+            replay_window: ReplayWindow::new(),
+            unauthenticated: 0,
+            replayed: 0,
+            unknown_session: 0,
+            rejected_sessions: 0,
+            wfb_ng,
+        }
+    }
+
+    // Returns the session if the packet announced a new one, sessions of an older epoch are rejected
+    pub fn process_session_packet(&mut self, packet: &[u8]) -> Option<SessionData> {
+        let Some(new_session) = session::open_session(&self.session_box, packet, self.wfb_ng) else {
+            self.unauthenticated += 1;
+            return None;
+        };
+
+        if new_session.channel_id != self.channel_id {
+            self.rejected_sessions += 1;
+            return None;
+        }
+
+        if let Some(session) = &self.session {
+            if new_session.epoch < session.epoch {
+                self.rejected_sessions += 1;
+                return None;
+            }
+            // the same epoch is just the periodic re-announcement of the current session,
+            // except for wfb-ng, which keeps the epoch when it restarts with a new key
+            if new_session.epoch == session.epoch {
+                if new_session.session_key == session.session_key {
+                    return None;
+                }
+                if !self.wfb_ng {
+                    self.rejected_sessions += 1;
+                    return None;
+                }
+            }
+        }
+
+        self.session = Some(new_session);
+        self.replay_window = ReplayWindow::new();
+        Some(new_session)
+    }
+
+    // Returns the decrypted wfb packet, or None if it failed authentication, was replayed or belongs to another session
+    pub fn decrypt_packet(&mut self, magic_header: &[u8], packet: &[u8]) -> Option<Vec<u8>> {
+        let Some((crypto_header, ciphertext)) = CryptoHeader::from_bytes(packet) else {
+            self.unauthenticated += 1;
+            return None;
+        };
+
+        let Some(session) = self.session.as_ref().filter(|s| s.epoch == crypto_header.epoch) else {
+            self.unknown_session += 1;
+            return None;
+        };
+
+        if self.replay_window.is_replay(crypto_header.nonce) {
+            self.replayed += 1;
+            return None;
+        }
+
+        let header = &packet[..packet.len() - ciphertext.len()];
+        let ad = [magic_header, header].concat();
+        let Some(decrypted) = crypto::decrypt(&session.session_key, &crypto_header.nonce.to_le_bytes(), &ad, ciphertext) else {
+            self.unauthenticated += 1;
+            return None;
+        };
+
+        self.replay_window.mark(crypto_header.nonce);
+        Some(decrypted)
+    }
+
+    // Decrypts a wfb-ng data packet, returns the data nonce and the fragment
+    pub fn decrypt_fragment(&mut self, packet: &[u8]) -> Option<(u64, Vec<u8>)> {
+        let Some((block_header, ciphertext)) = BlockHeader::from_bytes(packet) else {
+            self.unauthenticated += 1;
+            return None;
+        };
+
+        let Some(session) = self.session.as_ref() else {
+            self.unknown_session += 1;
+            return None;
+        };
+
+        if self.replay_window.is_replay(block_header.data_nonce) {
+            self.replayed += 1;
+            return None;
+        }
+
+        let header = &packet[..packet.len() - ciphertext.len()];
+        let nonce = block_header.data_nonce.to_be_bytes();
+        let Some(fragment) = crypto::decrypt(&session.session_key, &nonce, header, ciphertext) else {
+            self.unauthenticated += 1;
+            return None;
+        };
+        if fragment.len() > wfb_ng::MAX_FEC_PAYLOAD {
+            self.unauthenticated += 1;
+            return None;
+        }
+
+        self.replay_window.mark(block_header.data_nonce);
+        Some((block_header.data_nonce, fragment))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::crypto::{Key, KEY_SIZE};
+    use crate::common::from_hex;
+    use crate::common::keypair::generate_keypairs;
+    use crate::common::session::SessionFec;
+
+    const CHANNEL_ID: u32 = 0x0700;
+    const MAGIC_HEADER: [u8; 4] = [0x73, 0x72, 0x62, 0x57];
+
+    #[test]
+    fn window_accepts_every_nonce_once() {
+        let mut window = ReplayWindow::new();
+        for nonce in [10, 12, 11, 9, 1000] {
+            assert!(!window.is_replay(nonce), "{}", nonce);
+            window.mark(nonce);
+            assert!(window.is_replay(nonce), "{}", nonce);
+        }
+        assert!(!window.is_replay(13));
+    }
+
+    #[test]
+    fn window_edge() {
+        let mut window = ReplayWindow::new();
+        let highest = 5 * REPLAY_WINDOW_SIZE;
+        window.mark(highest);
+        assert!(!window.is_replay(highest - REPLAY_WINDOW_SIZE + 1));
+        assert!(window.is_replay(highest - REPLAY_WINDOW_SIZE));
+        assert!(window.is_replay(0));
+
+        // the oldest nonce of the window is forgotten once the window moves on
+        window.mark(highest - REPLAY_WINDOW_SIZE + 1);
+        window.mark(highest + 1);
+        assert!(window.is_replay(highest - REPLAY_WINDOW_SIZE + 1));
+    }
+
+    #[test]
+    fn window_jumps_to_far_future_nonces() {
+        let mut window = ReplayWindow::new();
+        for nonce in 0..REPLAY_WINDOW_SIZE {
+            window.mark(nonce);
+        }
+
+        // the nonces in between were never seen, although they share bits with the old ones
+        let far = 3 * REPLAY_WINDOW_SIZE + 100;
+        window.mark(far);
+        assert!((far - REPLAY_WINDOW_SIZE + 1..far).all(|nonce| !window.is_replay(nonce)));
+        assert!(window.is_replay(far));
+        assert!(window.is_replay(REPLAY_WINDOW_SIZE - 1));
+
+        window.mark(u64::MAX);
+        assert!(window.is_replay(u64::MAX));
+        assert!(!window.is_replay(u64::MAX - 1));
+        assert!(window.is_replay(far));
+    }
+
+    // Receiver with a session announced by the transmitter, and the session key
+    fn rx_crypto(epoch: u64) -> (RXCrypto, crypto_box::SalsaBox, Key) {
+        let (drone, ground_station) = generate_keypairs(1).unwrap();
+        let tx_box = session::session_box(&drone);
+        let session_key = [0x42; KEY_SIZE];
+        let mut crypto = RXCrypto::new(&ground_station, CHANNEL_ID, false);
+        let session = SessionData { epoch, channel_id: CHANNEL_ID, fec: SessionFec::Disabled, session_key };
+        let packet = session::seal_session(&tx_box, &session, false).unwrap();
+        assert!(crypto.process_session_packet(&packet).is_some());
+        (crypto, tx_box, session_key)
+    }
+
+    fn encrypt_packet(session_key: &Key, epoch: u64, nonce: u64, payload: &[u8]) -> Vec<u8> {
+        let header = CryptoHeader::new(epoch, nonce).to_bytes();
+        let ad = [&MAGIC_HEADER[..], &header[..]].concat();
+        [&header[..], &crypto::encrypt(session_key, &nonce.to_le_bytes(), &ad, payload)].concat()
+    }
+
+    #[test]
+    fn counts_rejected_packets() {
+        let (mut crypto, _, session_key) = rx_crypto(5);
+
+        let packet = encrypt_packet(&session_key, 5, 0, b"payload");
+        assert_eq!(crypto.decrypt_packet(&MAGIC_HEADER, &packet).as_deref(), Some(&b"payload"[..]));
+        assert_eq!(crypto.decrypt_packet(&MAGIC_HEADER, &packet), None);
+        assert_eq!(crypto.replayed, 1);
+
+        let mut forged = encrypt_packet(&session_key, 5, 1, b"payload");
+        *forged.last_mut().unwrap() ^= 1;
+        assert_eq!(crypto.decrypt_packet(&MAGIC_HEADER, &forged), None);
+        assert_eq!(crypto.decrypt_packet(&MAGIC_HEADER, &packet[..10]), None);
+        assert_eq!(crypto.unauthenticated, 2);
+        // a forged packet doesn't use up its nonce
+        let packet = encrypt_packet(&session_key, 5, 1, b"payload");
+        assert!(crypto.decrypt_packet(&MAGIC_HEADER, &packet).is_some());
+
+        let packet = encrypt_packet(&session_key, 6, 2, b"payload");
+        assert_eq!(crypto.decrypt_packet(&MAGIC_HEADER, &packet), None);
+        assert_eq!(crypto.unknown_session, 1);
+        assert_eq!((crypto.replayed, crypto.unauthenticated), (1, 2));
+    }
+
+    #[test]
+    fn rejects_older_and_foreign_sessions() {
+        let (mut crypto, tx_box, session_key) = rx_crypto(5);
+        let seal = |epoch, channel_id, session_key| {
+            let session = SessionData { epoch, channel_id, fec: SessionFec::Disabled, session_key };
+            session::seal_session(&tx_box, &session, false).unwrap()
+        };
+
+        // the periodic announcement of the current session is no new session
+        assert!(crypto.process_session_packet(&seal(5, CHANNEL_ID, session_key)).is_none());
+        assert_eq!(crypto.rejected_sessions, 0);
+
+        assert!(crypto.process_session_packet(&seal(4, CHANNEL_ID, [1; KEY_SIZE])).is_none());
+        assert!(crypto.process_session_packet(&seal(5, CHANNEL_ID, [1; KEY_SIZE])).is_none());
+        assert!(crypto.process_session_packet(&seal(6, CHANNEL_ID + 1, [1; KEY_SIZE])).is_none());
+        assert_eq!(crypto.rejected_sessions, 3);
+
+        let mut forged = seal(6, CHANNEL_ID, [1; KEY_SIZE]);
+        forged[30] ^= 1;
+        assert!(crypto.process_session_packet(&forged).is_none());
+        assert_eq!(crypto.unauthenticated, 1);
+
+        // a new session starts with a new window
+        assert!(crypto.process_session_packet(&seal(6, CHANNEL_ID, [1; KEY_SIZE])).is_some());
+        let packet = encrypt_packet(&[1; KEY_SIZE], 6, 0, b"payload");
+        assert!(crypto.decrypt_packet(&MAGIC_HEADER, &packet).is_some());
+    }
+
+    #[test]
+    fn decrypts_wfb_ng_data_packet() {
+        let (drone, ground_station) = generate_keypairs(1).unwrap();
+        let mut crypto = RXCrypto::new(&ground_station, CHANNEL_ID, true);
+        let session_key = core::array::from_fn(|i| 0xa0 + i as u8);
+        let session = SessionData { epoch: 1, channel_id: CHANNEL_ID, fec: SessionFec::ReedSolomon { k: 8, n: 12 }, session_key };
+        let packet = session::seal_session(&session::session_box(&drone), &session, true).unwrap();
+        assert!(crypto.process_session_packet(&packet).is_some());
+
+        let packet = from_hex("010000000000000305\
+                               13f6dac3baf3d3af7da513e52d6702141dca61491d90db64");
+        let fragment = [&[0, 0, 5][..], b"hello"].concat();
+        assert_eq!(crypto.decrypt_fragment(&packet), Some(((3 << 8) | 5, fragment)));
+        assert_eq!(crypto.decrypt_fragment(&packet), None);
+        assert_eq!(crypto.replayed, 1);
+
+        // the header is authenticated
+        let mut forged = packet.clone();
+        forged[8] = 6;
+        assert_eq!(crypto.decrypt_fragment(&forged), None);
+        assert_eq!(crypto.unauthenticated, 1);
+    }
+}
+
@@ -0,0 +1,99 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::BuildHasher;
+
+use super::rx_hardware_interface::{AntennaSignal, RXFrame};
+
+// Number of recent frames a duplicate is looked up in, several cards deliver a frame within a few frames
+const DUPLICATE_WINDOW: usize = 256;
+
+// Signal of one antenna since the start
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AntennaStats {
+    pub packets: u64,
+    pub rssi_min: i8,           // dBm
+    pub rssi_max: i8,           // dBm
+    pub rssi_sum: i64,          // dBm, divided by packets gives the average
+    pub rssi_last: i8,          // dBm of the latest frame
+    pub noise: Option<i8>,      // dBm of the latest frame that reported it
+}
+
+impl AntennaStats {
+    pub fn rssi_avg(&self) -> i8 {
+        (self.rssi_sum / self.packets.max(1) as i64) as i8
+    }
+}
+
+// Reception of one wifi card since the start
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CardStats {
+    pub packets: u64,                           // frames received
+    pub unique: u64,                            // frames no other card delivered first
+    pub antennas: BTreeMap<u8, AntennaStats>,   // by antenna index
+}
+
+impl CardStats {
+    pub(super) fn add_frame(&mut self, antennas: &[AntennaSignal], unique: bool) {
+        self.packets += 1;
+        self.unique += unique as u64;
+        for antenna in antennas {
+            let stats = self.antennas.entry(antenna.antenna).or_insert(AntennaStats {
+                packets: 0,
+                rssi_min: antenna.signal,
+                rssi_max: antenna.signal,
+                rssi_sum: 0,
+                rssi_last: antenna.signal,
+                noise: None,
+            });
+            stats.packets += 1;
+            stats.rssi_min = stats.rssi_min.min(antenna.signal);
+            stats.rssi_max = stats.rssi_max.max(antenna.signal);
+            stats.rssi_sum += antenna.signal as i64;
+            stats.rssi_last = antenna.signal;
+            stats.noise = antenna.noise.or(stats.noise);
+        }
+    }
+}
+
+// Drops frames that were already received on another card. The 802.11 sequence number the transmitter
+// increments for every frame picks the candidates, but it restarts with the transmitter, drivers may
+// rewrite it and the frames are not authenticated yet. So a frame is only dropped if its payload hash
+// matches as well, with random keys forged frames can't aim at the hash of a frame still to come.
+pub(super) struct DuplicateFilter {
+    recent: VecDeque<(u16, u64)>,
+    seen: HashMap<u16, Vec<u64>>,     // payload hashes by sequence number
+    hasher: RandomState,
+}
+
+impl DuplicateFilter {
+    pub fn new() -> Self {
+        Self {
+            recent: VecDeque::with_capacity(DUPLICATE_WINDOW),
+            seen: HashMap::with_capacity(DUPLICATE_WINDOW),
+            hasher: RandomState::new(),
+        }
+    }
+
+    // Returns true if the frame is new
+    pub fn insert(&mut self, frame: &RXFrame) -> bool {
+        let hash = self.hasher.hash_one(&frame.payload);
+        let hashes = self.seen.entry(frame.sequence).or_default();
+        if hashes.contains(&hash) {
+            return false;
+        }
+        hashes.push(hash);
+
+        if self.recent.len() == DUPLICATE_WINDOW {
+            if let Some((sequence, hash)) = self.recent.pop_front() {
+                if let Some(hashes) = self.seen.get_mut(&sequence) {
+                    hashes.retain(|&seen| seen != hash);
+                    if hashes.is_empty() {
+                        self.seen.remove(&sequence);
+                    }
+                }
+            }
+        }
+        self.recent.push_back((frame.sequence, hash));
+        true
+    }
+}
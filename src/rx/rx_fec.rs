@@ -1,94 +1,344 @@
 use raptorq::{SourceBlockDecoder, EncodingPacket};
 use std::mem::size_of;
 use std::iter::once;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
-use crate::common::fec::{self, FecHeader};
+use crate::common::fec::{self, FecHeader, PacketTable, FEC_VERSION_LEGACY, MAX_BLOCK_SIZE};
+use crate::common::feedback::FecFeedback;
+use super::FecStats;
+
+// raptorq PayloadId in front of every symbol: source block number and encoding symbol id
+const PAYLOAD_ID_SIZE: usize = 4;
+
+// raptorq needs symbols of at least its alignment, the capture is limited to 4096 bytes anyway
+const MIN_PACKET_SIZE: u16 = 8;
+const MAX_PACKET_SIZE: u16 = 4096;
+
+// pending and decoded blocks further than this from the current one are forgotten
+const CLEANUP_WINDOW: u64 = 64;
+
+struct BlockDecoder {
+    header: FecHeader,
+    decoder: SourceBlockDecoder,
+    symbol_size: usize,
+    source_symbols: u32,
+    received_repair: u32,
+    // only kept when forwarding partial blocks
+    received_source: BTreeMap<u32, Vec<u8>>,
+    packet_table: Vec<(u32, u32)>,
+}
+
+impl BlockDecoder {
+    // Rebuilds the udp packets that lie entirely in received source symbols
+    fn surviving_packets(mut self) -> Vec<Vec<u8>> {
+        let symbol_size = self.symbol_size;
+        self.packet_table.sort_unstable();
+        self.packet_table.dedup();
+
+        self.packet_table
+            .iter()
+            .filter(|(_, len)| *len > 0)
+            .filter_map(|&(offset, len)| {
+                let (start, end) = (offset as usize, offset as usize + len as usize);
+                let symbols = (start / symbol_size) as u32..=((end - 1) / symbol_size) as u32;
+                let data: Vec<u8> = symbols
+                    .map(|esi| self.received_source.get(&esi).map(|symbol| &symbol[..]))
+                    .collect::<Option<Vec<_>>>()?
+                    .concat();
+                let first = start - start / symbol_size * symbol_size;
+                Some(data[first..first + len as usize].to_vec())
+            })
+            .collect()
+    }
+}
 
 pub(super) struct RXFec {
-    fec_decoders: HashMap<u8, SourceBlockDecoder>,
-    decoded_blocks: HashSet<u8>,
+    fec_decoders: HashMap<u64, BlockDecoder>,
+    decoded_blocks: HashSet<u64>,
+    legacy_block_seq: u64,
+    // decoded blocks waiting for older ones that may still complete
+    ready_blocks: BTreeMap<u64, Vec<Vec<u8>>>,
+    interleave_depth: u64,
+    feedback: FecFeedback,
+    forward_partial: bool,
+    pub stats: FecStats,
+    pub invalid_packets: u64,
 }
 
 impl RXFec {
-    pub fn new() -> Self {
+    // with interleaving, the blocks of the same depth complete in any order and are decoded side by side
+    pub fn new(forward_partial: bool, interleave_depth: u64) -> Self {
         Self {
             fec_decoders: HashMap::new(),
             decoded_blocks: HashSet::new(),
+            // far enough from 0 that extending the legacy block id never wraps
+            legacy_block_seq: 1 << 32,
+            ready_blocks: BTreeMap::new(),
+            interleave_depth,
+            feedback: FecFeedback::default(),
+            forward_partial,
+            stats: FecStats::default(),
+            invalid_packets: 0,
         }
     }
+
+    // Blocks still waiting for symbols
+    pub fn pending_blocks(&self) -> usize {
+        self.fec_decoders.len()
+    }
+
+    // Returns the block statistics since the last call
+    pub fn take_feedback(&mut self) -> FecFeedback {
+        std::mem::take(&mut self.feedback)
+    }
+
     pub fn process_fec_packet(
         &mut self,
         packet: &[u8],
     ) -> Option<Vec<Vec<u8>>> {
 
-        // decoding fec header, returning the raw data if none is found
+        // decoding fec header, unknown versions are dropped
         let Some((fec_header, packet)) = FecHeader::from_bytes(packet) else {
+            self.invalid_packets += 1;
+            return None;
+        };
+
+        let Some((packet_table, packet)) = PacketTable::from_bytes(packet) else {
+            self.invalid_packets += 1;
+            return None;
+        };
+
+        self.process_symbol(fec_header, packet_table, packet)
+    }
+
+    // Legacy packets carry no packet table and only the 8 bit block id, which is extended
+    // to a sequence number relative to the newest legacy block
+    pub fn process_legacy_fec_packet(
+        &mut self,
+        packet: &[u8],
+    ) -> Option<Vec<Vec<u8>>> {
+
+        let Some((mut fec_header, packet)) = FecHeader::from_legacy_bytes(packet) else {
+            self.invalid_packets += 1;
             return None;
         };
 
-        // get block id:
-        let block_id = packet.get(0)?;
+        let distance = (fec_header.block_seq as u8).wrapping_sub(self.legacy_block_seq as u8) as i8;
+        fec_header.block_seq = self.legacy_block_seq.wrapping_add_signed(distance as i64);
+        self.legacy_block_seq = self.legacy_block_seq.max(fec_header.block_seq);
+
+        self.process_symbol(fec_header, PacketTable::default(), packet)
+    }
+
+    fn process_symbol(
+        &mut self,
+        fec_header: FecHeader,
+        packet_table: PacketTable,
+        packet: &[u8],
+    ) -> Option<Vec<Vec<u8>>> {
+
+        // reject headers that would make raptorq panic
+        if !(MIN_PACKET_SIZE..=MAX_PACKET_SIZE).contains(&fec_header.packet_size)
+            || !(1..=MAX_BLOCK_SIZE).contains(&fec_header.block_size) {
+            self.invalid_packets += 1;
+            return None;
+        }
+
+        // raptorq carries the lowest byte of the block sequence
+        let block_seq = fec_header.block_seq;
+        if packet.first() != Some(&(block_seq as u8)) {
+            self.invalid_packets += 1;
+            return None;
+        }
 
         // Check if we've already successfully decoded this block
-        if self.decoded_blocks.contains(block_id) {
+        if self.decoded_blocks.contains(&block_seq) {
             // Already decoded this block, ignore this packet
             return None;
         }
 
         // Get or create decoder for this block
-        if !self.fec_decoders.contains_key(block_id) {
+        if !self.fec_decoders.contains_key(&block_seq) {
             // Create ObjectTransmissionInformation with proper parameters
             // (transfer_length, symbol_size, sub_symbol_size, source_symbols, repair_symbols)
 
             let (config, padding) = fec::get_raptorq_oti(fec_header.block_size, fec_header.packet_size);
-            self.fec_decoders
-                .insert(*block_id, SourceBlockDecoder::new(*block_id, &config, config.transfer_length() + padding));
+            if config.source_blocks() != 1 {
+                self.invalid_packets += 1;
+                return None;
+            }
+            let block_length = config.transfer_length() + padding;
+            self.fec_decoders.insert(block_seq, BlockDecoder {
+                header: fec_header,
+                decoder: SourceBlockDecoder::new(block_seq as u8, &config, block_length),
+                symbol_size: config.symbol_size() as usize,
+                source_symbols: (block_length / config.symbol_size() as u64) as u32,
+                received_repair: 0,
+                received_source: BTreeMap::new(),
+                packet_table: Vec::new(),
+            });
+        }
+
+        let block_decoder = self.fec_decoders.get_mut(&block_seq).unwrap();
+
+        // all packets of a block must agree on the header and carry exactly one symbol
+        if block_decoder.header != fec_header || packet.len() != PAYLOAD_ID_SIZE + block_decoder.symbol_size {
+            self.invalid_packets += 1;
+            return None;
         }
 
-        let decoder = self.fec_decoders.get_mut(block_id).unwrap();
-        
         let packet = EncodingPacket::deserialize(packet);
+        // the repair packets follow the source packets, so those received before decoding were needed
+        let symbol_id = packet.payload_id().encoding_symbol_id();
+        if symbol_id >= block_decoder.source_symbols {
+            block_decoder.received_repair += 1;
+        } else if self.forward_partial {
+            block_decoder.received_source.insert(symbol_id, packet.data().to_vec());
+            block_decoder.packet_table.extend(packet_table.packets);
+        }
 
         // add packet to decoder
         // Try to decode with current packets
-        if let Some(mut decoded_data) = decoder.decode(once(packet)) {
+        if let Some(decoded_data) = block_decoder.decoder.decode(once(packet)) {
+            self.feedback.decoded_blocks += 1;
+            self.feedback.max_repair_used = self.feedback.max_repair_used.max(block_decoder.received_repair);
+            self.stats.completed += 1;
+            self.stats.recovered += (block_decoder.received_repair > 0) as u64;
+            self.stats.repair_used += block_decoder.received_repair as u64;
+
             // Successfully decoded! Get the original udp packages:
-            let Some(num_pkgs_lim) = decoded_data.pop() else { return None };
-            if decoded_data.len() < num_pkgs_lim as usize * size_of::<u16>() { return None };
-            let indices_start_index = decoded_data.len() - num_pkgs_lim as usize * size_of::<u16>();
-            let pkg_indices: Vec<_> = decoded_data[indices_start_index..]
-                .chunks(size_of::<u16>())
-                .map(|b| u16::from_le_bytes(b.try_into().unwrap()))
-                .collect();
-            let mut packets = Vec::new();
-            for i in pkg_indices.windows(2) {
-                let (start, end) = (i[0] as usize, i[1] as usize);
-                packets.push(decoded_data[start..end].to_vec());
-            }
+            let packets = self.split_block(fec_header.version, &decoded_data);
+            self.ready_blocks.insert(block_seq, packets);
 
             // Clean up
-            self.fec_decoders.remove(block_id);
-            self.decoded_blocks.insert(*block_id);
+            self.fec_decoders.remove(&block_seq);
+            self.decoded_blocks.insert(block_seq);
 
-            return Some(packets);
+            // blocks at least the interleaving depth before this one were sent completely, they won't be completed anymore
+            self.abandon_blocks_before(block_seq.saturating_sub(self.interleave_depth - 1));
+            return self.release_ready_blocks();
         }
 
-        // Clean up old decoders to prevent memory leak
-        // Remove decoders older than current block_id - 64
-        let cleanup_limit = 64;
-        let cleanup_threshold_high = block_id.wrapping_add(cleanup_limit);
-        let cleanup_threshold_low = block_id.wrapping_sub(cleanup_limit);
-        let condition: Box<dyn Fn(u8) -> bool> = if cleanup_threshold_high > cleanup_threshold_low {
-            Box::new(|a| cleanup_threshold_low < a && a < cleanup_threshold_high)
+        // Clean up old decoders to prevent memory leak, like the blocks given up on after a decoded one
+        let evicted: Vec<u64> = self.fec_decoders
+            .keys()
+            .copied()
+            .filter(|&seq| seq.abs_diff(block_seq) >= CLEANUP_WINDOW)
+            .collect();
+        for seq in evicted {
+            self.abandon_block(seq);
+        }
+        // Also clean up decoded blocks tracker
+        self.decoded_blocks.retain(|&seq| seq.abs_diff(block_seq) < CLEANUP_WINDOW);
+
+        // the cleanup may have dropped the block the ready ones were waiting for
+        self.release_ready_blocks()
+    }
+
+    // Returns the decoded blocks in order, as far as no older block is still being decoded
+    fn release_ready_blocks(&mut self) -> Option<Vec<Vec<u8>>> {
+        let oldest_pending = self.fec_decoders.keys().min().copied();
+        let mut packets = Vec::new();
+        while let Some(entry) = self.ready_blocks.first_entry() {
+            if oldest_pending.is_some_and(|pending| pending < *entry.key()) {
+                break;
+            }
+            packets.extend(entry.remove());
+        }
+        (!packets.is_empty()).then_some(packets)
+    }
+
+    // Splits a decoded block by the udp package limiter info at its end: the packet offsets
+    // followed by their count, 16 bit offsets and an 8 bit count in legacy blocks, 32 bit otherwise
+    fn split_block(&mut self, version: u8, decoded_data: &[u8]) -> Vec<Vec<u8>> {
+        let (count_size, offset_size) = if version == FEC_VERSION_LEGACY {
+            (size_of::<u8>(), size_of::<u16>())
         } else {
-            Box::new(|a| cleanup_threshold_low < a || a < cleanup_threshold_high)
+            (size_of::<u32>(), size_of::<u32>())
         };
-        self.fec_decoders.retain(|&k, _| condition(k));
-        // Also clean up decoded blocks tracker
-        self.decoded_blocks.retain(|&k| condition(k));
 
-        return None; // Need more packets
+        let Some(count_start) = decoded_data.len().checked_sub(count_size) else {
+            self.invalid_packets += 1;
+            return Vec::new();
+        };
+        let num_pkgs_lim = decoded_data[count_start..]
+            .iter()
+            .rev()
+            .fold(0usize, |count, &b| count << 8 | b as usize);
+        // the count is not authenticated without encryption, a forged one may overflow on 32 bit targets
+        let indices_start_index = num_pkgs_lim
+            .checked_mul(offset_size)
+            .and_then(|indices_size| count_start.checked_sub(indices_size));
+        let Some(indices_start_index) = indices_start_index else {
+            self.invalid_packets += 1;
+            return Vec::new();
+        };
+
+        let pkg_indices: Vec<usize> = decoded_data[indices_start_index..count_start]
+            .chunks(offset_size)
+            .map(|b| b.iter().rev().fold(0usize, |offset, &b| offset << 8 | b as usize))
+            .collect();
+        let mut packets = Vec::new();
+        for i in pkg_indices.windows(2) {
+            let (start, end) = (i[0], i[1]);
+            if start > end || end > indices_start_index {
+                self.invalid_packets += 1;
+                break;
+            }
+            packets.push(decoded_data[start..end].to_vec());
+        }
+        packets
+    }
+
+    // Gives up on the pending blocks before `block_seq`, what survived of them is forwarded when partial
+    // forwarding is on
+    fn abandon_blocks_before(&mut self, block_seq: u64) {
+        let abandoned: Vec<u64> = self.fec_decoders
+            .keys()
+            .copied()
+            .filter(|&seq| seq < block_seq)
+            .collect();
+
+        for seq in abandoned {
+            self.abandon_block(seq);
+        }
+    }
+
+    // Counts a pending block as lost and keeps what survived of it when forwarding partial blocks
+    fn abandon_block(&mut self, block_seq: u64) {
+        let Some(block_decoder) = self.fec_decoders.remove(&block_seq) else {
+            return;
+        };
+        self.decoded_blocks.insert(block_seq);
+        self.feedback.lost_blocks += 1;
+        self.stats.lost += 1;
+        if self.forward_partial {
+            self.ready_blocks.insert(block_seq, block_decoder.surviving_packets());
+        }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::fec::FEC_VERSION;
+
+    #[test]
+    fn split_block_rejects_forged_packet_count() {
+        let mut fec = RXFec::new(false, 1);
+        for count in [u32::MAX, u32::MAX / 4 + 1, 3] {
+            let block = [&[0u8; 8][..], &count.to_le_bytes()].concat();
+            assert!(fec.split_block(FEC_VERSION, &block).is_empty());
+        }
+        assert_eq!(fec.invalid_packets, 3);
+    }
+
+    #[test]
+    fn split_block() {
+        let mut fec = RXFec::new(false, 1);
+        // two packets of 3 and 2 bytes: offsets 0, 3 and the end 5, then their count
+        let block = [&b"abcde"[..], &[0, 0, 0, 0, 3, 0, 0, 0, 5, 0, 0, 0, 3, 0, 0, 0]].concat();
+        assert_eq!(fec.split_block(FEC_VERSION, &block), vec![b"abc".to_vec(), b"de".to_vec()]);
+        assert_eq!(fec.invalid_packets, 0);
+    }
+}
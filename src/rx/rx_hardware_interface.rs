@@ -1,42 +1,57 @@
 use pcap::{self, Active, Capture};
-use radiotap::Radiotap;
+use radiotap::field::{self, Kind};
+use radiotap::RadiotapIterator;
 
 use crate::common::hw_headers;
+use crate::common::radio::{FcsMode, RadioRx};
 
+const FCS_SIZE: usize = 4;
+
+// Frames of one card, with the wifi headers removed
 pub(super) struct RXHwInt {
+    radio: Box<dyn RadioRx>,
+    link_id: u32,
+    fcs: FcsMode,
+}
+
+// pcap capture on a monitor mode card
+pub(super) struct PcapCapture {
     wifi_capture: Capture<Active>,
 }
 
+// Signal of one antenna of the card, as reported in the radiotap header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct AntennaSignal {
+    pub antenna: u8,
+    pub signal: i8,         // dBm
+    pub noise: Option<i8>,  // dBm, not every driver reports it
+}
+
+pub(super) struct RXFrame {
+    pub payload: Vec<u8>,
+    pub radio_port: u8,     // lowest byte of the channel id, picks the stream
+    pub sequence: u16,      // 802.11 sequence number, counts the frames of the transmitter
+    pub antennas: Vec<AntennaSignal>,
+}
+
 
 impl RXHwInt {
-    pub fn new(wifi_device: String, channel_id: u32) -> Result<Self, Box<dyn std::error::Error>> {
-        let wifi_capture = Self::open_wifi_capture(wifi_device, channel_id)?;
-        Ok(Self { wifi_capture })
+    // Receives the frames of all radio ports of the link
+    pub fn new(radio: Box<dyn RadioRx>, link_id: u32, fcs: FcsMode) -> Self {
+        Self { radio, link_id, fcs }
     }
-    pub fn receive_packet(&mut self) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
-        match self.wifi_capture.next_packet() {
-            Ok(packet) if packet.len() > 0 => {
-                Ok(Self::process_packet(&packet)?)
-            }
-            Ok(_packet) => {
-                //TODO reset fec (?)
-                eprintln!("packet len <= 0");
-                Ok(None)
-            }
-            Err(pcap::Error::TimeoutExpired) => {
-                // Timeout is normal, continue
-                Ok(None)
-            }
-            Err(e) => {
-                eprintln!("Error receiving packet: {}", e);
-                Ok(None)
-            }
+    pub fn receive_packet(&mut self) -> Result<Option<RXFrame>, Box<dyn std::error::Error>> {
+        match self.radio.receive_frame()? {
+            Some(packet) => Self::process_packet(packet, self.link_id, self.fcs),
+            None => Ok(None),
         }
     }
-    // Reads and removes the radiotap and wifi headers
+    // Reads and removes the radiotap and wifi headers and the fcs, keeps the signal of every antenna
     pub fn process_packet(
         packet: &[u8],
-    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        link_id: u32,
+        fcs: FcsMode,
+    ) -> Result<Option<RXFrame>, Box<dyn std::error::Error>> {
 
         if packet.len() < 4 {
             eprintln!("packet too short");
@@ -46,30 +61,93 @@ impl RXHwInt {
         // Parse minimal radiotap header to get length
         let radiotap_len = u16::from_le_bytes([packet[2], packet[3]]) as usize;
 
-        //Parse the whole radiotap header via library
-        let _radiotap_header = Radiotap::from_bytes(packet)?;
+        let mut has_fcs = fcs == FcsMode::Always;
+        // one entry per radiotap namespace with a signal, the antenna field follows the signal
+        let mut signals: Vec<(Option<u8>, i8, Option<i8>)> = Vec::new();
+        for radiotap_field in RadiotapIterator::from_bytes(packet)? {
+            let (kind, data) = radiotap_field?;
+            match kind {
+                Kind::Flags => {
+                    let flags: field::Flags = field::from_bytes(data)?;
+                    if flags.bad_fcs {
+                        return Ok(None);
+                    }
+                    has_fcs |= flags.fcs;
+                }
+                Kind::AntennaSignal => {
+                    let signal: field::AntennaSignal = field::from_bytes(data)?;
+                    signals.push((None, signal.value, None));
+                }
+                Kind::AntennaNoise => {
+                    let noise: field::AntennaNoise = field::from_bytes(data)?;
+                    if let Some(last) = signals.last_mut() {
+                        last.2 = Some(noise.value);
+                    }
+                }
+                Kind::Antenna => {
+                    let antenna: field::Antenna = field::from_bytes(data)?;
+                    if let Some(last) = signals.last_mut() {
+                        last.0 = Some(antenna.value);
+                    }
+                }
+                _ => {}
+            }
+        }
 
-        //println!("Received header: {:?}", radiotap_header);
-        //let header_len = radiotap_header.header.size;
+        // the first namespace usually holds the combined signal of all antennas and the noise,
+        // the per antenna values follow in extra namespaces. Without those the card has one antenna.
+        let noise = signals.iter().find_map(|&(_, _, noise)| noise);
+        let mut antennas: Vec<AntennaSignal> = signals
+            .iter()
+            .filter_map(|&(antenna, signal, own_noise)| Some(AntennaSignal {
+                antenna: antenna?,
+                signal,
+                noise: own_noise.or(noise),
+            }))
+            .collect();
+        if antennas.is_empty() {
+            antennas.extend(signals.first().map(|&(_, signal, noise)| AntennaSignal { antenna: 0, signal, noise }));
+        }
 
         // Skip radiotap header and IEEE 802.11 header
         let payload_start = radiotap_len + hw_headers::IEEE80211_HEADER.len();
+        let payload_end = packet.len().saturating_sub(if has_fcs { FCS_SIZE } else { 0 });
 
-        if packet.len() <= payload_start {
+        if payload_end <= payload_start {
             eprintln!("packet has no payload");
             return Ok(None); // No payload
         }
 
-        let payload = &packet[payload_start..];
+        // the pcap filter only lets the frames of the link through, other radios deliver everything
+        let address = &packet[radiotap_len + 10..radiotap_len + 16];
+        if address[..2] != hw_headers::IEEE80211_HEADER[10..12]
+            || u32::from_be_bytes(address[2..].try_into().unwrap()) >> 8 != link_id
+        {
+            return Ok(None);
+        }
 
-        //there are four bytes at the end where i dont know where it is coming from, so i remove them
-        //TODO figure out what that is
-        let payload = &payload[..payload.len().saturating_sub(4)];
+        // (seq_num << 4) + fragment_num in the last two bytes of the IEEE 802.11 header
+        let sequence = u16::from_le_bytes([packet[payload_start - 2], packet[payload_start - 1]]) >> 4;
+        // the channel id ends the second address
+        let radio_port = address[5];
 
-        Ok(Some(payload.to_vec()))
+        Ok(Some(RXFrame {
+            payload: packet[payload_start..payload_end].to_vec(),
+            radio_port,
+            sequence,
+            antennas,
+        }))
+    }
+}
+
+impl PcapCapture {
+    // Captures the frames of all radio ports of the link
+    pub fn open(wifi_device: &str, link_id: u32) -> Result<Self, Box<dyn std::error::Error>> {
+        let wifi_capture = Self::open_wifi_capture(wifi_device, link_id)?;
+        Ok(Self { wifi_capture })
     }
 
-    pub fn open_wifi_capture(wifi_device: String, channel_id: u32) -> Result<Capture<Active>, Box<dyn std::error::Error>> {
+    pub fn open_wifi_capture(wifi_device: &str, link_id: u32) -> Result<Capture<Active>, Box<dyn std::error::Error>> {
         let wifi_max_size = 4096;
 
         let wifi_card = pcap::Device::list()?
@@ -88,15 +166,118 @@ impl RXHwInt {
             return Err(format!("Unknown encapsulation on interface {}", wifi_device).into());
         }
 
-        // Set the BPF filter to match the original C++ code
+        // Set the BPF filter to match the original C++ code, for every radio port of the link
         let filter = format!(
-            "ether[0x0a:2]==0x5742 && ether[0x0c:4] == {:#010x}",
-            channel_id
+            "ether[0x0a:2]==0x5742 && ether[0x0c:4] & 0xffffff00 == {:#010x}",
+            link_id << 8
         );
         cap.filter(&filter, true)?;
 
-        cap = cap.setnonblock()?;
-
+        // every card is read by its own thread, so the capture blocks until a frame or the timeout
         Ok(cap)
     }
 }
+
+impl RadioRx for PcapCapture {
+    fn receive_frame(&mut self) -> Result<Option<&[u8]>, Box<dyn std::error::Error>> {
+        match self.wifi_capture.next_packet() {
+            Ok(packet) if !packet.is_empty() => {
+                Ok(Some(packet.data))
+            }
+            Ok(_packet) => {
+                //TODO reset fec (?)
+                eprintln!("packet len <= 0");
+                Ok(None)
+            }
+            Err(pcap::Error::TimeoutExpired) => {
+                // Timeout is normal, continue
+                Ok(None)
+            }
+            Err(e) => {
+                eprintln!("Error receiving packet: {}", e);
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINK_ID: u32 = 0x123456;
+    const CHANNEL_ID: u32 = LINK_ID << 8 | 3;
+
+    // radiotap header of a card with one antenna: flags and signal
+    fn radiotap(flags: u8) -> Vec<u8> {
+        vec![0x00, 0x00, 0x0a, 0x00, 0x22, 0x00, 0x00, 0x00, flags, (-50i8) as u8]
+    }
+
+    fn frame(radiotap: &[u8], channel_id: u32, trailer: &[u8]) -> Vec<u8> {
+        let ieee_header = hw_headers::get_ieee80211_header(0x08, channel_id, 42 << 4);
+        [radiotap, &ieee_header[..], b"payload", trailer].concat()
+    }
+
+    fn payload(packet: &[u8], fcs: FcsMode) -> Option<Vec<u8>> {
+        RXHwInt::process_packet(packet, LINK_ID, fcs).unwrap().map(|frame| frame.payload)
+    }
+
+    #[test]
+    fn strips_the_fcs() {
+        let fcs = [0xde, 0xad, 0xbe, 0xef];
+
+        // flagged in the radiotap header
+        let packet = frame(&radiotap(0x10), CHANNEL_ID, &fcs);
+        assert_eq!(payload(&packet, FcsMode::Always).as_deref(), Some(&b"payload"[..]));
+        assert_eq!(payload(&packet, FcsMode::Radiotap).as_deref(), Some(&b"payload"[..]));
+
+        // not flagged, only removed when every frame has one
+        let packet = frame(&radiotap(0x00), CHANNEL_ID, &fcs);
+        assert_eq!(payload(&packet, FcsMode::Always).as_deref(), Some(&b"payload"[..]));
+        assert_eq!(payload(&packet, FcsMode::Radiotap), Some([&b"payload"[..], &fcs].concat()));
+        let packet = frame(&radiotap(0x00), CHANNEL_ID, &[]);
+        assert_eq!(payload(&packet, FcsMode::Radiotap).as_deref(), Some(&b"payload"[..]));
+
+        // failed the check
+        let packet = frame(&radiotap(0x50), CHANNEL_ID, &fcs);
+        assert_eq!(payload(&packet, FcsMode::Always), None);
+    }
+
+    #[test]
+    fn reads_the_wifi_header() {
+        let packet = frame(&radiotap(0x10), CHANNEL_ID, &[0; FCS_SIZE]);
+        let received = RXHwInt::process_packet(&packet, LINK_ID, FcsMode::Always).unwrap().unwrap();
+        assert_eq!(received.radio_port, 3);
+        assert_eq!(received.sequence, 42);
+        assert_eq!(received.antennas, [AntennaSignal { antenna: 0, signal: -50, noise: None }]);
+
+        // frames of other links and without payload are dropped
+        let packet = frame(&radiotap(0x10), (LINK_ID + 1) << 8 | 3, &[0; FCS_SIZE]);
+        assert!(RXHwInt::process_packet(&packet, LINK_ID, FcsMode::Always).unwrap().is_none());
+        let packet = [&radiotap(0x10)[..], &hw_headers::get_ieee80211_header(0x08, CHANNEL_ID, 0), &[0; FCS_SIZE]].concat();
+        assert!(RXHwInt::process_packet(&packet, LINK_ID, FcsMode::Always).unwrap().is_none());
+    }
+
+    // radiotap header of a card with two antennas: flags, the combined signal and noise, then one
+    // namespace with signal and antenna index per antenna
+    #[test]
+    fn reads_the_signal_of_every_antenna() {
+        let radiotap = [
+            0x00, 0x00, 0x18, 0x00,
+            0x62, 0x00, 0x00, 0xa0, // flags, signal, noise, radiotap namespace, ext
+            0x20, 0x08, 0x00, 0xa0, // signal, antenna, radiotap namespace, ext
+            0x20, 0x08, 0x00, 0x00, // signal, antenna
+            0x10, (-40i8) as u8, (-90i8) as u8, // flags, signal, noise
+            (-42i8) as u8, 0x00, // signal and index of antenna 0
+            (-45i8) as u8, 0x01, // signal and index of antenna 1
+            0x00,
+        ];
+        let packet = frame(&radiotap, CHANNEL_ID, &[0; FCS_SIZE]);
+        let received = RXHwInt::process_packet(&packet, LINK_ID, FcsMode::Radiotap).unwrap().unwrap();
+        assert_eq!(received.payload, b"payload");
+        assert_eq!(received.antennas, [
+            AntennaSignal { antenna: 0, signal: -42, noise: Some(-90) },
+            AntennaSignal { antenna: 1, signal: -45, noise: Some(-90) },
+        ]);
+    }
+}
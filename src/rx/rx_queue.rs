@@ -0,0 +1,97 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvError, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Fill level of a queue between two receiver stages
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct QueueStats {
+    pub depth: usize,       // items waiting right now
+    pub max_depth: usize,   // most items that were waiting at once
+    pub dropped: u64,       // items dropped because the queue was full, since the start
+}
+
+#[derive(Default)]
+struct QueueDepth {
+    depth: AtomicUsize,
+    max_depth: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+// Bounded channel that keeps track of how full it is. A full queue drops new items instead of
+// blocking the stage in front of it, so a slow stage shows up as drops rather than as kernel loss.
+pub(super) fn queue<T>(capacity: usize) -> (QueueSender<T>, QueueReceiver<T>) {
+    let (sender, receiver) = mpsc::sync_channel(capacity);
+    let depth = Arc::new(QueueDepth::default());
+    (
+        QueueSender { sender, depth: depth.clone() },
+        QueueReceiver { receiver, depth },
+    )
+}
+
+pub(super) struct QueueSender<T> {
+    sender: SyncSender<T>,
+    depth: Arc<QueueDepth>,
+}
+
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        Self { sender: self.sender.clone(), depth: self.depth.clone() }
+    }
+}
+
+impl QueueDepth {
+    fn stats(&self) -> QueueStats {
+        QueueStats {
+            depth: self.depth.load(Ordering::Relaxed),
+            max_depth: self.max_depth.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<T> QueueSender<T> {
+    // Returns false once the receiving stage is gone
+    pub fn send(&self, item: T) -> bool {
+        // count first, the receiver may take the item before we get to it
+        let depth = self.depth.depth.fetch_add(1, Ordering::Relaxed) + 1;
+        match self.sender.try_send(item) {
+            Ok(()) => {
+                self.depth.max_depth.fetch_max(depth, Ordering::Relaxed);
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                self.depth.depth.fetch_sub(1, Ordering::Relaxed);
+                self.depth.dropped.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                self.depth.depth.fetch_sub(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+}
+
+pub(super) struct QueueReceiver<T> {
+    receiver: mpsc::Receiver<T>,
+    depth: Arc<QueueDepth>,
+}
+
+impl<T> QueueReceiver<T> {
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let item = self.receiver.recv()?;
+        self.depth.depth.fetch_sub(1, Ordering::Relaxed);
+        Ok(item)
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let item = self.receiver.recv_timeout(timeout)?;
+        self.depth.depth.fetch_sub(1, Ordering::Relaxed);
+        Ok(item)
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.depth.stats()
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+
+use crate::common::fec::reed_solomon::ReedSolomon;
+use crate::common::wfb_ng::{self, PacketHeader};
+use crate::common::feedback::FecFeedback;
+use super::FecStats;
+
+// Number of incomplete blocks kept, the oldest one is flushed when another block starts
+const MAX_PENDING_BLOCKS: usize = 40;
+
+struct RsBlock {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    next_forward: usize,    // index of the first data fragment that was not forwarded yet
+    max_fragment_size: usize,
+}
+
+impl RsBlock {
+    fn new(n: usize) -> Self {
+        Self {
+            fragments: vec![None; n],
+            received: 0,
+            next_forward: 0,
+            max_fragment_size: 0,
+        }
+    }
+}
+
+pub(super) struct RXRsFec {
+    rs: ReedSolomon,
+    blocks: BTreeMap<u64, RsBlock>,
+    next_block: Option<u64>,    // blocks below this index are done, their fragments are ignored
+    pub stats: FecStats,
+    feedback: FecFeedback,
+    pub invalid_packets: u64,
+}
+
+impl RXRsFec {
+    pub fn new(k: u8, n: u8) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            rs: ReedSolomon::new(k, n)?,
+            blocks: BTreeMap::new(),
+            next_block: None,
+            stats: FecStats::default(),
+            feedback: FecFeedback::default(),
+            invalid_packets: 0,
+        })
+    }
+
+    // Blocks still waiting for fragments
+    pub fn pending_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    // Returns the block statistics since the last call
+    pub fn take_feedback(&mut self) -> FecFeedback {
+        std::mem::take(&mut self.feedback)
+    }
+
+    // Returns the udp packets that can be forwarded in order after this fragment
+    pub fn process_fragment(&mut self, data_nonce: u64, fragment: Vec<u8>) -> Vec<Vec<u8>> {
+        let (block_idx, fragment_idx) = wfb_ng::split_data_nonce(data_nonce);
+        let fragment_idx = fragment_idx as usize;
+
+        if fragment_idx >= self.rs.n() || fragment.is_empty() {
+            self.invalid_packets += 1;
+            return vec![];
+        }
+        if self.next_block.is_some_and(|next| block_idx < next) {
+            return vec![];
+        }
+
+        let mut out = Vec::new();
+
+        if !self.blocks.contains_key(&block_idx) {
+            // make room by giving up on the oldest block
+            while self.blocks.len() >= MAX_PENDING_BLOCKS {
+                let Some((oldest_idx, _)) = self.blocks.first_key_value() else { break };
+                if *oldest_idx > block_idx {
+                    // the new block is older than everything pending, it would be flushed right away
+                    return out;
+                }
+                let (oldest_idx, oldest) = self.blocks.pop_first().unwrap();
+                self.flush_block(oldest, &mut out);
+                self.next_block = Some(oldest_idx + 1);
+            }
+            self.blocks.insert(block_idx, RsBlock::new(self.rs.n()));
+        }
+
+        let block = self.blocks.get_mut(&block_idx).unwrap();
+        if block.fragments[fragment_idx].is_some() {
+            return out;
+        }
+        block.max_fragment_size = block.max_fragment_size.max(fragment.len());
+        block.fragments[fragment_idx] = Some(fragment);
+        block.received += 1;
+
+        if block.received >= self.rs.k() {
+            // everything before a complete block is lost for good
+            while let Some(entry) = self.blocks.first_entry() {
+                if *entry.key() >= block_idx {
+                    break;
+                }
+                let older = entry.remove();
+                self.flush_block(older, &mut out);
+            }
+
+            let mut block = self.blocks.remove(&block_idx).unwrap();
+            let size = block.max_fragment_size;
+            let missing = block.fragments[..self.rs.k()].iter().filter(|f| f.is_none()).count() as u64;
+            self.stats.completed += 1;
+            self.stats.recovered += (missing > 0) as u64;
+            self.stats.repair_used += missing;
+            self.feedback.decoded_blocks += 1;
+            self.feedback.max_repair_used = self.feedback.max_repair_used.max(missing as u32);
+            if self.rs.reconstruct(&mut block.fragments, size).is_none() {
+                self.invalid_packets += 1;
+            }
+            self.flush_block(block, &mut out);
+            self.next_block = Some(block_idx + 1);
+        }
+
+        // forward what arrived in order in the oldest pending block
+        if let Some(mut entry) = self.blocks.first_entry() {
+            let block = entry.get_mut();
+            while block.next_forward < self.rs.k() {
+                let Some(fragment) = block.fragments[block.next_forward].as_ref() else { break };
+                if let Some(packet) = Self::unpack_fragment(fragment, &mut self.invalid_packets) {
+                    out.push(packet);
+                }
+                block.next_forward += 1;
+            }
+        }
+
+        out
+    }
+
+    // Forwards the remaining data fragments of a block, missing ones are skipped
+    fn flush_block(&mut self, block: RsBlock, out: &mut Vec<Vec<u8>>) {
+        let k = self.rs.k();
+        if block.received < k {
+            self.stats.lost += 1;
+            self.feedback.lost_blocks += 1;
+        }
+        for fragment in block.fragments.into_iter().take(k).skip(block.next_forward).flatten() {
+            if let Some(packet) = Self::unpack_fragment(&fragment, &mut self.invalid_packets) {
+                out.push(packet);
+            }
+        }
+    }
+
+    // Strips the packet header and padding, empty fec only packets are dropped
+    fn unpack_fragment(fragment: &[u8], invalid_packets: &mut u64) -> Option<Vec<u8>> {
+        let Some((packet_header, payload)) = PacketHeader::from_bytes(fragment) else {
+            *invalid_packets += 1;
+            return None;
+        };
+        if packet_header.flags & wfb_ng::PACKET_FLAG_FEC_ONLY != 0 {
+            return None;
+        }
+        let packet_size = packet_header.packet_size as usize;
+        if packet_size > payload.len() || packet_size > wfb_ng::MAX_PAYLOAD_SIZE {
+            *invalid_packets += 1;
+            return None;
+        }
+        Some(payload[..packet_size].to_vec())
+    }
+}
@@ -1,101 +1,613 @@
 mod tx_hardware_interface;
 mod tx_fec;
+mod tx_crypto;
+mod tx_rs_fec;
+mod tx_interleaver;
+mod tx_rate;
 
 use std::net::UdpSocket;
-use std::sync::mpsc::channel;
-use std::time::Duration;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{io, thread};
 
-use super::common::{hw_headers, magic_header, bandwidth::Bandwidth};
+use super::common::{hw_headers, magic_header, utils, wfb_ng, bandwidth::Bandwidth};
+use super::common::channel::Channel;
+use super::common::fec::{FecMode, FEC_VERSION, FEC_VERSION_LEGACY};
+use super::common::feedback::FecFeedback;
+use super::common::hopping::{HopSchedule, Hopper};
+use super::common::control::{self, ControlCommand, Setting};
+use super::common::keypair::{KeyRole, Keypair};
+use super::common::session::{SessionFec, SessionInfo};
+use super::common::metrics::{self, MetricsWriter};
+use super::common::radio::RadioTx;
+use super::common::stats::{self, StatsHandle, StatsSocket};
+use super::common::tun::Tun;
+use super::common::utils::WifiSetup;
 
-use tx_hardware_interface::TXHwInt;
+use tx_hardware_interface::{RawSocket, TXHwInt};
 use tx_fec::TXFec;
+use tx_crypto::TXCrypto;
+use tx_rs_fec::TXRsFec;
+use tx_interleaver::TXInterleaver;
+use tx_rate::RateController;
 use magic_header::MagicHeader;
 
+const SESSION_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const CONTROL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Statistics of the transmitter since the start, see Transmitter::stats
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TxStats {
+    pub channel_id: u32,
+    pub session: Option<SessionInfo>,   // only with encryption
+    pub packets_in: u64,        // udp packets received
+    pub bytes_in: u64,
+    pub packets_out: u64,       // frames sent, fec and session packets included
+    pub bytes_out: u64,
+    pub session_packets: u64,
+    pub sync_packets: u64,      // frequency hopping
+    pub fec_blocks: u64,        // fec blocks encoded
+    pub repair_packets: u64,    // repair packets of those blocks
+    pub redundant_pkgs: u32,    // current repair packets per raptorq block, follows the adaptive redundancy
+    pub mcs_index: u8,          // current rate, follows the rate control
+    pub bandwidth: u16,         // MHz
+    pub frequency: u32,         // MHz, follows the frequency hopping, 0 without wifi setup
+    pub send_errors: u64,       // frames the socket refused
+    pub enobufs_drops: u64,     // frames dropped because the driver queue was full
+}
+
+impl TxStats {
+    pub fn to_prometheus(&self) -> String {
+        let mut metrics = MetricsWriter::default();
+        metrics.counter("wfb_tx_packets_in_total", "Udp packets received", self.packets_in);
+        metrics.counter("wfb_tx_bytes_in_total", "Bytes of the received udp packets", self.bytes_in);
+        metrics.counter("wfb_tx_frames_total", "Frames sent, fec and session packets included", self.packets_out);
+        metrics.counter("wfb_tx_frame_bytes_total", "Bytes of the sent frames", self.bytes_out);
+        metrics.counter("wfb_tx_session_packets_total", "Session announcements sent", self.session_packets);
+        metrics.counter("wfb_tx_sync_packets_total", "Frequency hopping sync packets sent", self.sync_packets);
+        metrics.counter("wfb_tx_fec_blocks_total", "Encoded fec blocks", self.fec_blocks);
+        metrics.counter("wfb_tx_fec_repair_packets_total", "Repair packets of the encoded blocks", self.repair_packets);
+        metrics.gauge("wfb_tx_redundant_pkgs", "Repair packets per raptorq block", self.redundant_pkgs);
+        metrics.gauge("wfb_tx_mcs_index", "Current MCS index", self.mcs_index);
+        metrics.gauge("wfb_tx_bandwidth_mhz", "Current channel width in MHz", self.bandwidth);
+        metrics.gauge("wfb_tx_frequency_mhz", "Current channel frequency in MHz", self.frequency);
+        metrics.counter("wfb_tx_send_errors_total", "Frames the socket refused", self.send_errors);
+        metrics.counter("wfb_tx_enobufs_drops_total", "Frames dropped because the driver queue was full", self.enobufs_drops);
+        metrics.finish()
+    }
+
+    // Record of the changes since `last`, laid out like the tx records of wfb-ng's json stats
+    pub fn to_json(&self, last: &TxStats) -> String {
+        let packets = [
+            stats::json_counter("incoming", self.packets_in, last.packets_in),
+            stats::json_counter("incoming_bytes", self.bytes_in, last.bytes_in),
+            stats::json_counter("injected", self.packets_out, last.packets_out),
+            stats::json_counter("injected_bytes", self.bytes_out, last.bytes_out),
+            stats::json_counter("dropped", self.enobufs_drops, last.enobufs_drops),
+            stats::json_counter("send_errors", self.send_errors, last.send_errors),
+            stats::json_counter("session", self.session_packets, last.session_packets),
+            stats::json_counter("sync", self.sync_packets, last.sync_packets),
+            stats::json_counter("fec_blocks", self.fec_blocks, last.fec_blocks),
+            stats::json_counter("fec_repair", self.repair_packets, last.repair_packets),
+        ];
+        format!(
+            "{{\"type\":\"tx\",\"timestamp\":{:.3},\"id\":\"{:#x}\",\"packets\":{{{}}},\"redundant_pkgs\":{},\"mcs\":{},\"bandwidth\":{},\"frequency\":{},\"session\":{}}}",
+            stats::json_timestamp(),
+            self.channel_id,
+            packets.join(","),
+            self.redundant_pkgs,
+            self.mcs_index,
+            self.bandwidth,
+            self.frequency,
+            self.session.map_or("null".to_string(), |session| session.to_json()),
+        )
+    }
+}
+
+// Where the transmitter reads the packets it sends
+pub enum TxInput {
+    Udp(u16),       // udp port, on all interfaces
+    Tun(Arc<Tun>),  // ip packets of the tunnel mode
+}
+
+// Where the frames go out
+pub enum TxDevice {
+    Wifi(String),               // monitor mode card, sending on it needs root
+    Radio(Arc<dyn RadioTx>),    // any other radio, e.g. a simulated channel
+}
+
+enum InputSocket {
+    Udp(UdpSocket),
+    Tun(Arc<Tun>),
+}
+
+// Settings of a transmitter, the defaults are the ones of wfb_rs_tx
+#[derive(Debug, Clone)]
+pub struct TxConfig {
+    pub magic: u32,
+    pub radio_port: u8,
+    pub link_id: u32,
+    pub bandwidth: Bandwidth,
+    pub short_gi: bool,
+    pub stbc: u8,
+    pub ldpc: bool,
+    pub mcs_index: u8,
+    pub vht_mode: bool,
+    pub vht_nss: u8,
+    pub wifi_setup: Option<WifiSetup>,                  // only for wifi cards
+    pub fec_disabled: bool,
+    pub fec_mode: FecMode,
+    pub legacy_fec_header: bool,
+    pub min_block_size: u32,
+    pub wifi_packet_size: u16,
+    pub redundant_pkgs: u32,
+    pub adaptive_redundancy: Option<(u32, u32)>,        // min and max redundant packages, follows the feedback
+    pub rate_control: Option<(u8, u8)>,                 // min and max MCS index, follows the feedback
+    pub interleave_depth: usize,
+    pub fec_k: u8,
+    pub fec_n: u8,
+    pub max_block_age: Option<Duration>,
+    pub key_file: Option<String>,                       // drone key, enables encryption
+    pub epoch: u64,                                     // must increase between restarts
+    pub session_interval: Duration,
+    pub key_rotation: Option<Duration>,
+}
+
+impl Default for TxConfig {
+    fn default() -> Self {
+        Self {
+            magic: 0x57627273,
+            radio_port: 0,
+            link_id: 7669206,
+            bandwidth: Bandwidth::Bw20,
+            short_gi: true,
+            stbc: 1,
+            ldpc: true,
+            mcs_index: 1,
+            vht_mode: false,
+            vht_nss: 1,
+            wifi_setup: None,
+            fec_disabled: false,
+            fec_mode: FecMode::Raptorq,
+            legacy_fec_header: false,
+            min_block_size: 10_000,
+            wifi_packet_size: 800,
+            redundant_pkgs: 15,
+            adaptive_redundancy: None,
+            rate_control: None,
+            interleave_depth: 1,
+            fec_k: 8,
+            fec_n: 12,
+            max_block_age: None,
+            key_file: None,
+            // the current unix time increases between restarts
+            epoch: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs()),
+            session_interval: Duration::from_secs(1),
+            key_rotation: None,
+        }
+    }
+}
+
+// Where Transmitter::run reports and takes commands, all off by default
+#[derive(Debug, Clone)]
+pub struct TxRunConfig {
+    pub feedback_address: Option<String>,   // ip:port the receiver reports to
+    pub control_port: Option<u16>,
+    pub stats_address: Option<String>,
+    pub metrics_address: Option<String>,
+    pub buffer_size: usize,                 // largest input packet
+    pub log_interval: Duration,
+}
+
+impl Default for TxRunConfig {
+    fn default() -> Self {
+        Self {
+            feedback_address: None,
+            control_port: None,
+            stats_address: None,
+            metrics_address: None,
+            buffer_size: 1_500,
+            log_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+// Radio parameters of the injected frames, kept to rebuild the radiotap header at runtime
+#[derive(Debug, Clone, Copy)]
+struct RadioSettings {
+    stbc: u8,
+    ldpc: bool,
+    short_gi: bool,
+    bandwidth: Bandwidth,
+    mcs_index: u8,
+    vht_mode: bool,
+    vht_nss: u8,
+}
+
+impl RadioSettings {
+    fn radiotap_header(&self) -> Result<Vec<u8>, String> {
+        hw_headers::check_radio_settings(self.stbc, self.bandwidth, self.mcs_index, self.vht_mode, self.vht_nss)?;
+        Ok(hw_headers::get_radiotap_headers(
+            self.stbc, self.ldpc, self.short_gi, self.bandwidth, self.mcs_index, self.vht_mode, self.vht_nss,
+        ))
+    }
+}
+
+// Radio settings of the card, shared by its streams so a change reaches every one of them
+struct CardRadio {
+    settings: RadioSettings,
+    stats: Vec<Weak<Mutex<TxStats>>>,   // of the streams, they report the current rate
+}
+
+// Settings the streams added with add_stream take over from the first one
+#[derive(Clone)]
+struct SharedSettings {
+    radio: Arc<Mutex<CardRadio>>,
+    magic: u32,
+    link_id: u32,
+    legacy_fec_header: bool,
+    interleave_depth: usize,
+    max_block_age: Option<Duration>,
+    keypair: Option<Arc<Keypair>>,
+    epoch: u64,
+    session_interval: Duration,
+    key_rotation: Option<Duration>,
+    channel: Option<Channel>,   // known when the card was set up here
+}
+
 pub struct Transmitter {
     tx: TXHwInt,
+    wifi_device: Option<String>,    // only wifi cards have a tx power
+    stats: Arc<Mutex<TxStats>>,
     fec: Option<TXFec>,
+    rs_fec: Option<TXRsFec>,
+    interleaver: Option<TXInterleaver>,
+    max_block_age: Option<Duration>,
+    adaptive_redundancy: Option<(u32, u32)>,
+    channel_id: u32,
+    crypto: Option<TXCrypto>,
     magic_header: MagicHeader,
+    session_header: MagicHeader,
+    sync_header: MagicHeader,
+    shared: SharedSettings,
+    rate_control: Option<RateController>,
+    hopper: Option<Hopper>,
 }
 
 impl Transmitter {
-    pub fn new(
-        magic: u32,
-        radio_port: u8,
-        link_id: u32,
-        bandwidth: Bandwidth,
-        short_gi: bool,
-        stbc: u8,
-        ldpc: bool,
-        mcs_index: u8,
-        vht_mode: bool,
-        vht_nss: u8,
-        wifi_device: String,
-        fec_disabled: bool,
-        min_block_size: u16,
-        wifi_packet_size: u16,
-        redundant_pkgs: u32,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
-        let radiotap_header = hw_headers::get_radiotap_headers(
-            stbc, ldpc, short_gi, bandwidth, mcs_index, vht_mode, vht_nss,
-        );
+    pub fn new(device: TxDevice, config: TxConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let TxConfig {
+            magic,
+            radio_port,
+            link_id,
+            bandwidth,
+            short_gi,
+            stbc,
+            ldpc,
+            mcs_index,
+            vht_mode,
+            vht_nss,
+            wifi_setup,
+            fec_disabled,
+            fec_mode,
+            legacy_fec_header,
+            min_block_size,
+            wifi_packet_size,
+            redundant_pkgs,
+            adaptive_redundancy,
+            rate_control,
+            interleave_depth,
+            fec_k,
+            fec_n,
+            max_block_age,
+            key_file,
+            epoch,
+            session_interval,
+            key_rotation,
+        } = config;
+        let mut radio = RadioSettings { stbc, ldpc, short_gi, bandwidth, mcs_index, vht_mode, vht_nss };
+
+        // the rate control starts from the configured rate and goes up to its bandwidth
+        let rate_control = match rate_control {
+            Some((min_mcs, max_mcs)) => {
+                let mut rate_control = RateController::new(min_mcs, max_mcs, bandwidth, stbc, vht_mode, vht_nss)?;
+                (radio.bandwidth, radio.mcs_index) = rate_control.sync(bandwidth, mcs_index, vht_mode, vht_nss);
+                Some(rate_control)
+            }
+            None => None,
+        };
+
+        let radiotap_header = radio.radiotap_header()?;
         let link_id = link_id & 0xffffff;
 
+        let wifi_device = match &device {
+            TxDevice::Wifi(wifi_device) => Some(wifi_device.clone()),
+            TxDevice::Radio(_) => None,
+        };
+
+        // the channel is as wide as the configured bandwidth, the rate control stays within it
+        let channel = match (&wifi_setup, &wifi_device) {
+            (Some(wifi_setup), Some(wifi_device)) => {
+                let channel = utils::setup_wifi(wifi_device, wifi_setup, bandwidth)?;
+                println!("{} is on {}", wifi_device, channel);
+                Some(channel)
+            }
+            (Some(_), None) => return Err("The wifi setup needs a wifi card".into()),
+            (None, _) => None,
+        };
+
         let channel_id = link_id << 8 | radio_port as u32;
 
-        let tx = TXHwInt::new(wifi_device, radiotap_header, channel_id)?;
+        let radio_tx: Arc<dyn RadioTx> = match device {
+            TxDevice::Wifi(wifi_device) => Arc::new(RawSocket::open(&wifi_device)?),
+            TxDevice::Radio(radio_tx) => radio_tx,
+        };
+        let tx = TXHwInt::new(radio_tx, radiotap_header, channel_id);
 
-        let fec = if fec_disabled {
-            None
-        } else { Some(TXFec::new(
-            min_block_size,
-            wifi_packet_size,
-            redundant_pkgs
-        ))};
+        let keypair = match key_file {
+            Some(key_file) => Some(Arc::new(Keypair::load(&key_file, KeyRole::Drone)?)),
+            None => None,
+        };
+
+        // only the main stream hops, the others share its card
+        let hopping = wifi_setup
+            .as_ref()
+            .zip(wifi_device.as_ref())
+            .and_then(|(wifi_setup, wifi_device)| Some((wifi_setup.hopping.as_ref()?, wifi_setup.ht40, wifi_device)));
+        let hopper = match hopping {
+            Some(_) if fec_mode == FecMode::Rs => return Err("Frequency hopping is not available in the rs fec mode".into()),
+            Some((hopping, ht40, wifi_device)) => {
+                let schedule = HopSchedule::new(hopping, bandwidth, ht40, keypair.as_deref().ok_or("Frequency hopping requires a key file")?)?;
+                let hopper = Hopper::start(schedule, wifi_device.clone())?;
+                println!("Hopping every {} ms, starting on {}", hopping.dwell.as_millis(), hopper.channel());
+                Some(hopper)
+            }
+            None => None,
+        };
 
-        let magic_header = if fec_disabled {
-            MagicHeader::new(magic)
+        let fec = if fec_mode == FecMode::Rs {
+            SessionFec::ReedSolomon { k: fec_k, n: fec_n }
+        } else if fec_disabled {
+            SessionFec::Disabled
         } else {
-            MagicHeader::new_fec(magic)
+            SessionFec::RaptorQ {
+                block_size: min_block_size,
+                packet_size: wifi_packet_size,
+                redundant_pkgs,
+            }
         };
 
+        let shared = SharedSettings {
+            radio: Arc::new(Mutex::new(CardRadio { settings: radio, stats: Vec::new() })),
+            magic,
+            link_id,
+            legacy_fec_header,
+            interleave_depth,
+            max_block_age,
+            keypair,
+            epoch,
+            session_interval,
+            key_rotation,
+            channel,
+        };
+
+        let mut transmitter = Self::new_stream(tx, wifi_device, shared, channel_id, fec, adaptive_redundancy)?;
+        transmitter.rate_control = rate_control;
+        if let Some(hopper) = &hopper {
+            transmitter.stats.lock().unwrap().frequency = hopper.channel().frequency;
+        }
+        transmitter.hopper = hopper;
+        Ok(transmitter)
+    }
+
+    // Another stream on the same card and radio settings, sent on its own radio port with its own
+    // fec settings. The stream runs on its own, e.g. in another thread, and has its own statistics.
+    // Changes of the radio settings, by any stream or the rate control, apply to all streams.
+    pub fn add_stream(&self, radio_port: u8, fec: SessionFec) -> Result<Self, Box<dyn std::error::Error>> {
+        let channel_id = self.shared.link_id << 8 | radio_port as u32;
+        let tx = self.tx.stream(channel_id);
+        Self::new_stream(tx, self.wifi_device.clone(), self.shared.clone(), channel_id, fec, None)
+    }
+
+    fn new_stream(
+        tx: TXHwInt,
+        wifi_device: Option<String>,
+        shared: SharedSettings,
+        channel_id: u32,
+        fec: SessionFec,
+        adaptive_redundancy: Option<(u32, u32)>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        // the Reed-Solomon mode speaks the wfb-ng protocol, which always encrypts
+        let wfb_ng = matches!(fec, SessionFec::ReedSolomon { .. });
+        if wfb_ng && shared.keypair.is_none() {
+            return Err("Reed-Solomon fec mode requires a key file".into());
+        }
+
+        let (raptorq_fec, rs_fec) = match fec {
+            SessionFec::ReedSolomon { k, n } => (None, Some(TXRsFec::new(k, n)?)),
+            SessionFec::Disabled => (None, None),
+            SessionFec::RaptorQ { block_size, packet_size, redundant_pkgs } => (Some(TXFec::new(
+                if shared.legacy_fec_header { FEC_VERSION_LEGACY } else { FEC_VERSION },
+                block_size,
+                packet_size,
+                redundant_pkgs
+            )?), None),
+        };
+
+        // interleaving only applies to raptorq blocks, a depth of one sends them as they are
+        let interleaver = (raptorq_fec.is_some() && shared.interleave_depth > 1)
+            .then(|| TXInterleaver::new(shared.interleave_depth));
+
+        let crypto = match &shared.keypair {
+            Some(keypair) => Some(TXCrypto::new(
+                keypair,
+                shared.epoch,
+                channel_id,
+                fec,
+                shared.session_interval,
+                shared.key_rotation,
+                wfb_ng,
+            )?),
+            None => None,
+        };
+
+        let magic_header = if fec == SessionFec::Disabled {
+            MagicHeader::new(shared.magic)
+        } else if shared.legacy_fec_header {
+            MagicHeader::new_legacy_fec(shared.magic)
+        } else {
+            MagicHeader::new_fec(shared.magic)
+        };
+
+        let mut radio = shared.radio.lock().unwrap();
+        let stats = TxStats {
+            channel_id,
+            session: crypto.as_ref().map(|crypto| crypto.session_info()),
+            redundant_pkgs: raptorq_fec.as_ref().map_or(0, |fec| fec.redundant_pkgs()),
+            mcs_index: radio.settings.mcs_index,
+            bandwidth: radio.settings.bandwidth.mhz(),
+            frequency: shared.channel.map_or(0, |channel| channel.frequency),
+            ..Default::default()
+        };
+        let stats = Arc::new(Mutex::new(stats));
+        radio.stats.push(Arc::downgrade(&stats));
+        drop(radio);
+
         Ok(Self {
             tx,
-            fec,
+            wifi_device,
+            stats,
+            fec: raptorq_fec,
+            rs_fec,
+            interleaver,
+            max_block_age: shared.max_block_age,
+            adaptive_redundancy,
+            channel_id,
+            crypto,
             magic_header,
+            session_header: MagicHeader::new_session(shared.magic),
+            sync_header: MagicHeader::new_sync(shared.magic),
+            shared,
+            rate_control: None,
+            hopper: None,
         })
     }
 
-    pub fn run(mut self, source_port: u16, buffer_r: usize, log_interval: Duration) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn run(mut self, input: TxInput, config: TxRunConfig) -> Result<(), Box<dyn std::error::Error>> {
+        let TxRunConfig { feedback_address, control_port, stats_address, metrics_address, buffer_size, log_interval } = config;
+
+        let input = match input {
+            TxInput::Udp(source_port) => InputSocket::Udp(UdpSocket::bind(format!("0.0.0.0:{}", source_port))?),
+            TxInput::Tun(tun) => InputSocket::Tun(tun),
+        };
+
+        let feedback_r = match feedback_address {
+            Some(feedback_address) => Some(Self::listen_feedback(&feedback_address)?),
+            None => None,
+        };
+
+        let control_r = match control_port {
+            Some(control_port) => Some(control::listen(control_port)?),
+            None => None,
+        };
+
+        // json records for ground station tools, next to the log lines
+        let stats_socket = match stats_address {
+            Some(stats_address) => Some(StatsSocket::connect(&stats_address)?),
+            None => None,
+        };
 
-        let udp_socket = UdpSocket::bind(format!("0.0.0.0:{}", source_port))?;
-        
-        let (sent_bytes_s, sent_bytes_r) = channel();
-        let (received_bytes_s, received_bytes_r) = channel();
+        if let Some(metrics_address) = metrics_address {
+            let stats = self.stats();
+            metrics::serve(&metrics_address, move || stats.get().to_prometheus())?;
+        }
 
         // start logtask
+        let stats = self.stats();
         thread::spawn(move || {
+            let mut last = stats.get();
             loop {
-                let (sent_packets, sent_bytes): (u32, u32) = sent_bytes_r.try_iter().fold((0, 0), |(count, sum), v| (count + 1, sum + v));
-                let (received_packets, received_bytes): (u32, u32) = received_bytes_r.try_iter().fold((0, 0), |(count, sum), v| (count + 1, sum + v));
+                thread::sleep(log_interval);
+                let current = stats.get();
                 println!(
-                    "Packets R->T {}->{},\tBytes {}->{}",
-                    received_packets,
-                    sent_packets,
-                    received_bytes,
-                    sent_bytes,
+                    "Port {}: Packets R->T {}->{},\tBytes {}->{}",
+                    current.channel_id & 0xff,
+                    current.packets_in - last.packets_in,
+                    current.packets_out - last.packets_out,
+                    current.bytes_in - last.bytes_in,
+                    current.bytes_out - last.bytes_out,
                 );
-                thread::sleep(log_interval);
+                if current.send_errors != last.send_errors || current.enobufs_drops != last.enobufs_drops {
+                    println!(
+                        "Dropped frames: {} send errors, {} ENOBUFS",
+                        current.send_errors - last.send_errors,
+                        current.enobufs_drops - last.enobufs_drops,
+                    );
+                }
+                if let Some(stats_socket) = &stats_socket {
+                    stats_socket.send(&current.to_json(&last));
+                }
+                last = current;
             }
         });
 
+        let mut read_timeout = None;
         loop {
-            let mut udp_recv_buffer = vec![0u8; buffer_r];
-            let poll_result = udp_socket.recv(&mut udp_recv_buffer);
+            if let Some(feedback_r) = &feedback_r {
+                for feedback in feedback_r.try_iter() {
+                    self.apply_feedback(&feedback);
+                }
+                self.check_feedback();
+            }
+
+            if let Some(control_r) = &control_r {
+                for request in control_r.try_iter() {
+                    let result = match request.command {
+                        ControlCommand::GetStats => Ok(self.stats().get().to_json(&TxStats::default())),
+                        ControlCommand::Set(setting) => {
+                            let result = self.apply_setting(setting);
+                            match &result {
+                                Ok(()) => println!("Applied {:?}", setting),
+                                Err(e) => eprintln!("Error applying {:?}: {}", setting, e),
+                            }
+                            result.map(|()| String::new())
+                        }
+                    };
+                    request.reply(result);
+                }
+            }
+
+            self.flush_expired_block();
+
+            // wake up in time to flush the open fec block or to announce the session
+            let mut timeout = self.next_timeout();
+            if control_r.is_some() {
+                // and for control commands while the input is idle
+                timeout = Some(timeout.map_or(CONTROL_POLL_INTERVAL, |t| t.min(CONTROL_POLL_INTERVAL)));
+            }
+            let mut udp_recv_buffer = vec![0u8; buffer_size];
+            let poll_result = match &input {
+                InputSocket::Udp(udp_socket) => {
+                    if timeout != read_timeout {
+                        udp_socket.set_read_timeout(timeout)?;
+                        read_timeout = timeout;
+                    }
+                    udp_socket.recv(&mut udp_recv_buffer)
+                }
+                InputSocket::Tun(tun) => tun.recv(&mut udp_recv_buffer, timeout),
+            };
 
             match poll_result {
                 Err(err) => match err.kind() {
-                    io::ErrorKind::TimedOut => continue,
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+                        // like in send, a failed announcement is retried with the next one
+                        if let Err(e) = self.announce_session() {
+                            eprintln!("Error announcing session: {}", e);
+                        }
+                        self.follow_hopping();
+                        continue;
+                    }
                     err => {
                         eprintln!("Error polling udp input: {}", err);
                         continue;
@@ -107,45 +619,451 @@ impl Transmitter {
                         eprintln!("Empty packet");
                         continue;
                     }
-                    if received == buffer_r {
+                    if received == buffer_size {
                         eprintln!("Input packet seems too large");
                     }
                     
                     let udp_packet = &udp_recv_buffer[..received];
 
-                    received_bytes_s.send(received as u32)?;
+                    {
+                        let mut stats = self.stats.lock().unwrap();
+                        stats.packets_in += 1;
+                        stats.bytes_in += received as u64;
+                    }
+
+                    self.send(udp_packet);
+                }
+            }
+        }
+    }
+    // Receives the fec statistics of the receiver in the background. The reports are not authenticated
+    // and steer the redundancy and rate, so the address (ip:port) should only be reachable over an
+    // authenticated wfb link, e.g. localhost or the address of a tunnel with key files.
+    fn listen_feedback(feedback_address: &str) -> Result<Receiver<FecFeedback>, Box<dyn std::error::Error>> {
+        let feedback_socket = UdpSocket::bind(feedback_address)
+            .map_err(|e| format!("Failed to listen for feedback on {}: {}", feedback_address, e))?;
+        let (feedback_s, feedback_r) = channel();
+
+        thread::spawn(move || {
+            let mut buffer = [0u8; 64];
+            loop {
+                let received = match feedback_socket.recv(&mut buffer) {
+                    Ok(received) => received,
+                    Err(e) => {
+                        eprintln!("Error receiving feedback: {}", e);
+                        continue;
+                    }
+                };
+                let Some(feedback) = FecFeedback::from_bytes(&buffer[..received]) else { continue; };
+                if feedback_s.send(feedback).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(feedback_r)
+    }
 
-                    let sent = self.send(udp_packet);
+    pub fn apply_feedback(&mut self, feedback: &FecFeedback) {
+        if feedback.channel_id != self.channel_id {
+            return;
+        }
 
-                    sent_bytes_s.send(sent as u32).unwrap();
+        // judged against the repair packets the reported blocks were sent with
+        let redundant_pkgs = match (self.fec.as_ref(), self.rs_fec.as_ref()) {
+            (Some(fec), _) => fec.redundant_pkgs(),
+            (_, Some(rs_fec)) => rs_fec.redundant_pkgs(),
+            _ => 0,
+        };
+        let sent_blocks = self.sent_blocks();
+        if let Some(rate_control) = self.rate_control.as_mut() {
+            if let Some((bandwidth, mcs_index)) = rate_control.update(feedback, redundant_pkgs, sent_blocks) {
+                match self.set_radio(RadioSettings { bandwidth, mcs_index, ..self.radio() }) {
+                    Ok(()) => println!("Rate: {} MHz, MCS {} (rssi {:?})", bandwidth, mcs_index, feedback.rssi),
+                    Err(e) => eprintln!("Error changing the rate: {}", e),
                 }
             }
         }
+
+        let Some((min, max)) = self.adaptive_redundancy else { return };
+        let Some(fec) = self.fec.as_mut() else { return };
+        if let Some(redundant_pkgs) = fec.adapt_redundancy(feedback, min, max) {
+            println!("Redundant packages per block: {}", redundant_pkgs);
+            self.stats.lock().unwrap().redundant_pkgs = redundant_pkgs;
+        }
+    }
+
+    // Steps the rate down when the receiver stopped reporting on the sent blocks, a link that delivers
+    // nothing leaves it nothing to report. Only call it while feedback is expected.
+    pub fn check_feedback(&mut self) {
+        let sent_blocks = self.sent_blocks();
+        let Some(rate_control) = self.rate_control.as_mut() else { return };
+        if let Some((bandwidth, mcs_index)) = rate_control.check_reports(sent_blocks) {
+            match self.set_radio(RadioSettings { bandwidth, mcs_index, ..self.radio() }) {
+                Ok(()) => println!("Rate: {} MHz, MCS {} (no feedback)", bandwidth, mcs_index),
+                Err(e) => eprintln!("Error changing the rate: {}", e),
+            }
+        }
+    }
+
+    // Changes a setting between two fec blocks, the open block is sent as it is
+    pub fn apply_setting(&mut self, setting: Setting) -> Result<(), String> {
+        self.flush_block();
+
+        let mut radio = self.radio();
+        match setting {
+            Setting::Mcs(mcs_index) => radio.mcs_index = mcs_index,
+            Setting::Bandwidth(bandwidth) => radio.bandwidth = bandwidth,
+            Setting::Stbc(stbc) => radio.stbc = stbc,
+            Setting::Ldpc(ldpc) => radio.ldpc = ldpc,
+            Setting::ShortGi(short_gi) => radio.short_gi = short_gi,
+            Setting::Fec { block_size, redundant_pkgs } => {
+                let Some(fec) = self.fec.as_mut() else {
+                    return Err("fec settings need the raptorq fec mode".to_string());
+                };
+                fec.set_block_params(block_size, redundant_pkgs)?;
+                self.stats.lock().unwrap().redundant_pkgs = redundant_pkgs;
+                return Ok(());
+            }
+            Setting::TxPower(tx_power) => {
+                let Some(wifi_device) = &self.wifi_device else {
+                    return Err("the tx power needs a wifi card".to_string());
+                };
+                return utils::set_tx_power(wifi_device, tx_power).map_err(|e| e.to_string());
+            }
+        }
+
+        self.set_radio(radio)?;
+        // a manual rate is where the rate control continues from
+        if let Some(rate_control) = self.rate_control.as_mut() {
+            rate_control.sync(radio.bandwidth, radio.mcs_index, radio.vht_mode, radio.vht_nss);
+        }
+        Ok(())
+    }
+
+    fn radio(&self) -> RadioSettings {
+        self.shared.radio.lock().unwrap().settings
     }
+
+    // Rebuilds the radiotap header of all streams on the card, takes effect with their next frame
+    fn set_radio(&mut self, radio: RadioSettings) -> Result<(), String> {
+        if let Some(channel) = self.shared.channel {
+            if radio.bandwidth.mhz() > channel.bandwidth.mhz() {
+                return Err(format!("{} MHz is wider than the {} MHz channel of the card", radio.bandwidth, channel.bandwidth));
+            }
+        }
+        let mut card = self.shared.radio.lock().unwrap();
+        self.tx.set_radiotap_header(radio.radiotap_header()?);
+        card.settings = radio;
+        // streams that are gone drop out
+        card.stats.retain(|stats| {
+            let Some(stats) = stats.upgrade() else { return false };
+            let mut stats = stats.lock().unwrap();
+            stats.mcs_index = radio.mcs_index;
+            stats.bandwidth = radio.bandwidth.mhz();
+            true
+        });
+        Ok(())
+    }
+
+    // Returns a handle to the statistics, which stays valid while the transmitter runs
+    pub fn stats(&self) -> StatsHandle<TxStats> {
+        StatsHandle::new(self.stats.clone())
+    }
+
+    fn block_start(&self) -> Option<Instant> {
+        let block_start = match (self.fec.as_ref(), self.rs_fec.as_ref()) {
+            (Some(fec), _) => fec.block_start(),
+            (_, Some(rs_fec)) => rs_fec.block_start(),
+            _ => None,
+        };
+        // blocks waiting in the interleaver are older than the one being filled
+        self.interleaver.as_ref().and_then(|i| i.block_start()).or(block_start)
+    }
+
+    fn next_timeout(&self) -> Option<Duration> {
+        let session_timeout = self.crypto.as_ref().map(|_| SESSION_POLL_INTERVAL);
+        let block_timeout = self.max_block_age.zip(self.block_start()).map(|(max_age, start)| {
+            // a zero timeout would block forever
+            max_age.saturating_sub(start.elapsed()).max(Duration::from_millis(1))
+        });
+        let hop_timeout = self.hopper.as_ref().map(|hopper| {
+            hopper.next_event().saturating_duration_since(Instant::now()).max(Duration::from_millis(1))
+        });
+        // the deadline of a missing report, only set while feedback is checked
+        let report_timeout = self.rate_control
+            .as_ref()
+            .and_then(|rate_control| rate_control.report_deadline())
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()).max(Duration::from_millis(1)));
+        [session_timeout, block_timeout, hop_timeout, report_timeout].into_iter().flatten().min()
+    }
+
+    // Sends the open fec block if it is older than the maximum block age, returns the sent bytes
+    pub fn flush_expired_block(&mut self) -> Option<u32> {
+        let max_age = self.max_block_age?;
+        if self.block_start()?.elapsed() < max_age {
+            return None;
+        }
+        self.flush_block()
+    }
+
+    // Sends the open fec block even though it is not full, returns the sent bytes
+    fn flush_block(&mut self) -> Option<u32> {
+        let sent = if let Some(rs_fec) = self.rs_fec.as_mut() {
+            let fragments = rs_fec.flush();
+            self.send_fragments(fragments)
+        } else {
+            let block = self.fec.as_mut()?.flush();
+            match self.interleaver.as_mut() {
+                Some(interleaver) => {
+                    let mut packets = block.and_then(|block| interleaver.push_block(block)).unwrap_or_default();
+                    packets.extend(interleaver.flush().unwrap_or_default());
+                    self.send_block(packets)
+                }
+                None => self.send_block(block?),
+            }
+        };
+        self.update_fec_stats();
+        Some(sent)
+    }
+
+    // Sends a session packet if one is due, receivers need it to decrypt the data packets
+    pub fn announce_session(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(crypto) = self.crypto.as_mut() else { return Ok(()) };
+        let Some(session_packet) = crypto.session_packet()? else { return Ok(()) };
+
+        // wfb-ng has no magic header, just the packet type
+        let packet = if self.rs_fec.is_some() {
+            [&[wfb_ng::PACKET_TYPE_SESSION], &session_packet[..]].concat()
+        } else {
+            [&self.session_header.to_bytes(), &session_packet[..]].concat()
+        };
+        self.send_frame(&packet);
+        let mut stats = self.stats.lock().unwrap();
+        stats.session_packets += 1;
+        stats.session = self.crypto.as_ref().map(|crypto| crypto.session_info());
+        Ok(())
+    }
+
+    // Hops when the dwell time is over and sends a sync packet if one is due, receivers need them
+    // to hop along
+    pub fn follow_hopping(&mut self) {
+        let Some(hopper) = self.hopper.as_mut() else { return };
+        let Some(sync) = hopper.poll() else { return };
+        let frequency = hopper.channel().frequency;
+
+        // authenticated like data packets, so nobody else can move the receivers
+        let sync_header = self.sync_header.to_bytes();
+        let sync = match self.crypto.as_mut() {
+            Some(crypto) => crypto.encrypt_packet(&sync_header, &sync.to_bytes()),
+            None => sync.to_bytes().to_vec(),
+        };
+        self.send_frame(&[&sync_header[..], &sync[..]].concat());
+        let mut stats = self.stats.lock().unwrap();
+        stats.sync_packets += 1;
+        stats.frequency = frequency;
+    }
+
     pub fn send(&mut self, packet: &[u8]) -> u32 {
+        if let Err(e) = self.announce_session() {
+            eprintln!("Error announcing session: {}", e);
+        }
+        self.follow_hopping();
+
+        if let Some(rs_fec) = self.rs_fec.as_mut() {
+            let fragments = rs_fec.process_packet_fec(packet);
+            let sent = self.send_fragments(fragments);
+            self.update_fec_stats();
+            return sent;
+        }
+
         let block = if let Some(fec) = self.fec.as_mut() {
-            if let Some(block) = fec.process_packet_fec(packet) {
-                block
-            } else {
+            let Some(block) = fec.process_packet_fec(packet) else {
                 return 0;
+            };
+            self.update_fec_stats();
+            match self.interleaver.as_mut() {
+                Some(interleaver) => {
+                    let Some(packets) = interleaver.push_block(block) else { return 0 };
+                    packets
+                }
+                None => block,
             }
         } else {
             // if fec is disabled just send the raw block
             vec![packet.to_vec()]
         };
 
+        self.send_block(block)
+    }
+
+    // fec blocks encoded so far
+    fn sent_blocks(&self) -> u64 {
+        match (self.fec.as_ref(), self.rs_fec.as_ref()) {
+            (Some(fec), _) => fec.blocks,
+            (_, Some(rs_fec)) => rs_fec.blocks,
+            _ => 0,
+        }
+    }
+
+    fn update_fec_stats(&self) {
+        let (blocks, repair_packets) = match (self.fec.as_ref(), self.rs_fec.as_ref()) {
+            (Some(fec), _) => (fec.blocks, fec.repair_packets),
+            (_, Some(rs_fec)) => (rs_fec.blocks, rs_fec.repair_packets),
+            _ => return,
+        };
+        let mut stats = self.stats.lock().unwrap();
+        stats.fec_blocks = blocks;
+        stats.repair_packets = repair_packets;
+    }
+
+    // Sends one frame and counts it, returns the sent bytes
+    fn send_frame(&mut self, packet: &[u8]) -> u32 {
+        let result = self.tx.send_packet(packet);
+        let mut stats = self.stats.lock().unwrap();
+        match result {
+            Ok(Some(sent)) => {
+                if sent < packet.len() {
+                    eprintln!("socket dropped some bytes");
+                }
+                stats.packets_out += 1;
+                stats.bytes_out += sent as u64;
+                sent as u32
+            }
+            Ok(None) => {
+                stats.enobufs_drops += 1;
+                0
+            }
+            Err(_) => {
+                // already logged by the hardware interface
+                stats.send_errors += 1;
+                0
+            }
+        }
+    }
+
+    fn send_block(&mut self, block: Vec<Vec<u8>>) -> u32 {
         let mut sent_bytes = 0;
 
+        let magic_header = self.magic_header.to_bytes();
+
         for wfb_packet in block.into_iter() {
+            // encrypt after fec, so the receiver can drop forged packets before decoding
+            let wfb_packet = match self.crypto.as_mut() {
+                Some(crypto) => crypto.encrypt_packet(&magic_header, &wfb_packet),
+                None => wfb_packet,
+            };
             // add magic number
-            let packet = [&self.magic_header.to_bytes(), &wfb_packet[..]].concat();
+            let packet = [&magic_header, &wfb_packet[..]].concat();
             // send via raw socket
-            let sent = self.tx.send_packet(&packet).unwrap() as u32;
-            if sent < packet.len() as u32 {
-                eprintln!("socket dropped some bytes");
-            }
-            sent_bytes += sent as u32;
+            sent_bytes += self.send_frame(&packet);
+        }
+        sent_bytes
+    }
+
+    // wfb-ng fragments are encrypted into complete packets, without a magic header
+    fn send_fragments(&mut self, fragments: Vec<(u64, Vec<u8>)>) -> u32 {
+        let Some(crypto) = self.crypto.as_ref() else { return 0 };
+        let packets: Vec<Vec<u8>> = fragments
+            .iter()
+            .map(|(data_nonce, fragment)| crypto.encrypt_fragment(*data_nonce, fragment))
+            .collect();
+
+        packets.iter().map(|packet| self.send_frame(packet)).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::fec::MAX_BLOCK_SIZE;
+    use crate::common::sim_channel::{ChannelConditions, SimChannel};
+
+    fn transmitter(config: TxConfig) -> Transmitter {
+        let channel = SimChannel::new(ChannelConditions::default(), 1);
+        Transmitter::new(TxDevice::Radio(Arc::new(channel.transmitter())), config).unwrap()
+    }
+
+    // Keeps the sent frames
+    #[derive(Default)]
+    struct Recorder {
+        frames: Mutex<Vec<Vec<u8>>>,
+    }
+
+    impl RadioTx for Recorder {
+        fn send_frame(&self, parts: &[&[u8]]) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+            let frame = parts.concat();
+            let sent = frame.len();
+            self.frames.lock().unwrap().push(frame);
+            Ok(Some(sent))
         }
-        return sent_bytes;
+    }
+
+    #[test]
+    fn radio_settings_reach_every_stream() {
+        let recorder = Arc::new(Recorder::default());
+        let config = TxConfig { fec_disabled: true, ..Default::default() };
+        let mut tx = Transmitter::new(TxDevice::Radio(recorder.clone()), config).unwrap();
+        let mut stream = tx.add_stream(1, SessionFec::Disabled).unwrap();
+
+        tx.apply_setting(Setting::Mcs(3)).unwrap();
+        stream.apply_setting(Setting::Bandwidth(Bandwidth::Bw40)).unwrap();
+
+        let expected = RadioSettings { mcs_index: 3, bandwidth: Bandwidth::Bw40, ..tx.radio() };
+        for transmitter in [&tx, &stream] {
+            assert_eq!((transmitter.radio().mcs_index, transmitter.radio().bandwidth), (3, Bandwidth::Bw40));
+            let stats = transmitter.stats().get();
+            assert_eq!((stats.mcs_index, stats.bandwidth), (3, 40));
+        }
+
+        tx.send(b"main");
+        stream.send(b"stream");
+        let radiotap_header = expected.radiotap_header().unwrap();
+        let frames = recorder.frames.lock().unwrap();
+        assert_eq!(frames.len(), 2);
+        assert!(frames.iter().all(|frame| frame.starts_with(&radiotap_header)));
+    }
+
+    #[test]
+    fn rejects_invalid_radio_settings() {
+        let mut tx = transmitter(TxConfig::default());
+        assert!(tx.apply_setting(Setting::Mcs(32)).is_err());
+        assert!(tx.apply_setting(Setting::Stbc(4)).is_err());
+        assert!(tx.apply_setting(Setting::Bandwidth(Bandwidth::Bw80)).is_err());
+        assert!(tx.apply_setting(Setting::TxPower(20)).is_err());
+
+        // nothing changed
+        assert_eq!((tx.radio().mcs_index, tx.radio().stbc, tx.radio().bandwidth), (1, 1, Bandwidth::Bw20));
+        let stats = tx.stats().get();
+        assert_eq!((stats.mcs_index, stats.bandwidth), (1, 20));
+
+        tx.apply_setting(Setting::Mcs(3)).unwrap();
+        assert_eq!(tx.stats().get().mcs_index, 3);
+    }
+
+    #[test]
+    fn rejects_bandwidth_wider_than_the_channel() {
+        let mut tx = transmitter(TxConfig::default());
+        tx.shared.channel = Some(Channel::new(5745, Bandwidth::Bw20, None).unwrap());
+        assert!(tx.apply_setting(Setting::Bandwidth(Bandwidth::Bw40)).is_err());
+        assert_eq!(tx.radio().bandwidth, Bandwidth::Bw20);
+        assert_eq!(tx.stats().get().bandwidth, 20);
+    }
+
+    #[test]
+    fn rejects_invalid_fec_settings() {
+        let mut tx = transmitter(TxConfig::default());
+        assert!(tx.apply_setting(Setting::Fec { block_size: 0, redundant_pkgs: 4 }).is_err());
+        assert!(tx.apply_setting(Setting::Fec { block_size: MAX_BLOCK_SIZE + 1, redundant_pkgs: 4 }).is_err());
+        assert_eq!(tx.stats().get().redundant_pkgs, 15);
+
+        tx.apply_setting(Setting::Fec { block_size: 20_000, redundant_pkgs: 4 }).unwrap();
+        assert_eq!(tx.stats().get().redundant_pkgs, 4);
+
+        let mut tx = transmitter(TxConfig { fec_disabled: true, ..Default::default() });
+        assert!(tx.apply_setting(Setting::Fec { block_size: 20_000, redundant_pkgs: 4 }).is_err());
+
+        let mut tx = transmitter(TxConfig { legacy_fec_header: true, ..Default::default() });
+        assert!(tx.apply_setting(Setting::Fec { block_size: 70_000, redundant_pkgs: 4 }).is_err());
     }
 }
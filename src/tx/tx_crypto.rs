@@ -0,0 +1,180 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crypto_box::SalsaBox;
+
+use crate::common::crypto::{self, CryptoHeader, Key, KEY_SIZE};
+use crate::common::keypair::Keypair;
+use crate::common::session::{self, SessionData, SessionFec, SessionInfo};
+use crate::common::wfb_ng::BlockHeader;
+
+pub(super) struct TXCrypto {
+    session_box: SalsaBox,
+    session: SessionData,
+    nonce: u64,
+    announce_interval: Duration,
+    rotation_interval: Option<Duration>,
+    last_announce: Option<Instant>,
+    session_start: Instant,
+    wfb_ng: bool,
+}
+
+impl TXCrypto {
+    pub fn new(
+        keypair: &Keypair,
+        epoch: u64,
+        channel_id: u32,
+        fec: SessionFec,
+        announce_interval: Duration,
+        rotation_interval: Option<Duration>,
+        wfb_ng: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        // the epochs count seconds, faster rotations would run ahead of the clock and a restarted
+        // transmitter would be rejected until the clock caught up
+        if rotation_interval.is_some_and(|i| i < Duration::from_secs(1)) {
+            return Err("The key rotation interval must be at least one second".into());
+        }
+
+        let session = SessionData {
+            epoch,
+            channel_id,
+            fec,
+            session_key: Self::new_session_key()?,
+        };
+
+        Ok(Self {
+            session_box: session::session_box(keypair),
+            session,
+            nonce: 0,
+            announce_interval,
+            rotation_interval,
+            last_announce: None,
+            session_start: Instant::now(),
+            wfb_ng,
+        })
+    }
+
+    fn new_session_key() -> Result<Key, Box<dyn std::error::Error>> {
+        let mut session_key = [0u8; KEY_SIZE];
+        getrandom::getrandom(&mut session_key)?;
+        Ok(session_key)
+    }
+
+    pub fn session_info(&self) -> SessionInfo {
+        SessionInfo::from(&self.session)
+    }
+
+    // Returns the payload of a session packet if one is due, rotating the session key first if it expired
+    pub fn session_packet(&mut self) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        let now = Instant::now();
+
+        if self.rotation_interval.is_some_and(|i| now.duration_since(self.session_start) >= i) {
+            // like the initial epoch the unix time, so a restart continues above it
+            let now_secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+            self.session.epoch = (self.session.epoch + 1).max(now_secs);
+            self.session.session_key = Self::new_session_key()?;
+            self.nonce = 0;
+            self.session_start = now;
+            self.last_announce = None;
+        }
+
+        if self.last_announce.is_some_and(|t| now.duration_since(t) < self.announce_interval) {
+            return Ok(None);
+        }
+        self.last_announce = Some(now);
+
+        Ok(Some(session::seal_session(&self.session_box, &self.session, self.wfb_ng)?))
+    }
+
+    // Encrypts a wfb packet, the magic header is authenticated together with the crypto header
+    pub fn encrypt_packet(&mut self, magic_header: &[u8], packet: &[u8]) -> Vec<u8> {
+        let header = CryptoHeader::new(self.session.epoch, self.nonce).to_bytes();
+        let nonce = self.nonce.to_le_bytes();
+        self.nonce += 1;
+
+        let ad = [magic_header, &header[..]].concat();
+        let ciphertext = crypto::encrypt(&self.session.session_key, &nonce, &ad, packet);
+
+        [&header[..], &ciphertext[..]].concat()
+    }
+
+    // Encrypts a fragment into a complete wfb-ng data packet, the nonce is given by block and fragment index
+    pub fn encrypt_fragment(&self, data_nonce: u64, fragment: &[u8]) -> Vec<u8> {
+        let header = BlockHeader::new(data_nonce).to_bytes();
+        let ciphertext = crypto::encrypt(&self.session.session_key, &data_nonce.to_be_bytes(), &header, fragment);
+
+        [&header[..], &ciphertext[..]].concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::from_hex;
+    use crate::common::keypair::generate_keypairs;
+
+    fn tx_crypto() -> TXCrypto {
+        let (drone, _) = generate_keypairs(1).unwrap();
+        let mut crypto = TXCrypto::new(&drone, 5, 0x0700, SessionFec::Disabled, Duration::from_secs(1), None, false).unwrap();
+        crypto.session.session_key = core::array::from_fn(|i| i as u8);
+        crypto
+    }
+
+    #[test]
+    fn rotated_epoch_follows_the_clock() {
+        let (drone, _) = generate_keypairs(1).unwrap();
+        let rotation = Some(Duration::from_secs(1));
+        let mut crypto = TXCrypto::new(&drone, 5, 0x0700, SessionFec::Disabled, Duration::from_secs(1), rotation, false).unwrap();
+        let session_key = crypto.session.session_key;
+
+        crypto.session_start -= Duration::from_secs(1);
+        crypto.session_packet().unwrap();
+        let now_secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert!(crypto.session.epoch >= now_secs - 1);
+        assert_ne!(crypto.session.session_key, session_key);
+
+        // an epoch ahead of the clock still increases
+        crypto.session.epoch = u64::MAX - 1;
+        crypto.session_start -= Duration::from_secs(1);
+        crypto.session_packet().unwrap();
+        assert_eq!(crypto.session.epoch, u64::MAX);
+    }
+
+    #[test]
+    fn rejects_sub_second_rotation() {
+        let (drone, _) = generate_keypairs(1).unwrap();
+        let rotation = Some(Duration::from_millis(999));
+        assert!(TXCrypto::new(&drone, 5, 0x0700, SessionFec::Disabled, Duration::from_secs(1), rotation, false).is_err());
+    }
+
+    // the nonce of a packet is its counter (little endian) and the crypto header is authenticated
+    // together with the magic header, known answers of libsodium's crypto_aead_chacha20poly1305_encrypt
+    #[test]
+    fn packet_nonce_and_layout() {
+        let mut crypto = tx_crypto();
+        let magic_header = [0x73, 0x72, 0x62, 0x57];
+        assert_eq!(
+            crypto.encrypt_packet(&magic_header, b"wfb_rs packet"),
+            from_hex("05000000000000000000000000000000\
+                      6fde206edf9586a172023704dbb7fe9765abb0ac621a0d107fabb2fe2c"),
+        );
+        assert_eq!(
+            crypto.encrypt_packet(&magic_header, b"wfb_rs packet"),
+            from_hex("05000000000000000100000000000000\
+                      e8319100c7598f6848d5c539261cf15ff82ba67ffd5e84cc2ae18fb66b"),
+        );
+    }
+
+    // wfb-ng's data packet: wblock_hdr_t followed by the fragment sealed with the big endian data
+    // nonce and the header as associated data
+    #[test]
+    fn wfb_ng_data_packet() {
+        let mut crypto = tx_crypto();
+        crypto.session.session_key = core::array::from_fn(|i| 0xa0 + i as u8);
+        let fragment = [&[0, 0, 5][..], b"hello"].concat();
+        assert_eq!(
+            crypto.encrypt_fragment((3 << 8) | 5, &fragment),
+            from_hex("010000000000000305\
+                      13f6dac3baf3d3af7da513e52d6702141dca61491d90db64"),
+        );
+    }
+}
@@ -1,67 +1,220 @@
 use std::iter::once;
+use std::mem::size_of;
+use std::time::Instant;
 use raptorq::SourceBlockEncoder;
 
-use crate::common::fec::{self, FecHeader};
+use crate::common::fec::{self, FecHeader, PacketTable, FEC_VERSION_LEGACY};
+use crate::common::feedback::FecFeedback;
 
 pub(super) struct TXFec {
-    block_id: u8,
-    pkg_indices: Vec<u16>,
+    version: u8,
+    block_seq: u64,
+    pkg_indices: Vec<u32>,
     block_buffer: Vec<u8>,
-    min_block_size: u16,
+    block_start: Option<Instant>,
+    min_block_size: u32,
     wifi_packet_size: u16,
     redundant_pkgs: u32,
+    pub blocks: u64,
+    pub repair_packets: u64,
 }
 
 impl TXFec {
-    pub fn new(min_block_size: u16, wifi_packet_size: u16, redundant_pkgs: u32) -> Self {
-        Self {
-            block_id: 0,
+    pub fn new(version: u8, min_block_size: u32, wifi_packet_size: u16, redundant_pkgs: u32) -> Result<Self, Box<dyn std::error::Error>> {
+        if version == FEC_VERSION_LEGACY && min_block_size > u16::MAX as u32 {
+            return Err("The legacy fec header is limited to blocks of 64 KiB".into());
+        }
+        fec::check_block_size(min_block_size, wifi_packet_size)?;
+
+        // start at a random sequence, so a restarted transmitter doesn't hit blocks the receiver already decoded
+        let mut block_seq = [0u8; 8];
+        getrandom::getrandom(&mut block_seq)?;
+
+        Ok(Self {
+            version,
+            block_seq: u64::from_le_bytes(block_seq),
             pkg_indices: Vec::new(),
             block_buffer: Vec::new(),
+            block_start: None,
             min_block_size,
             wifi_packet_size,
-            redundant_pkgs
-        }
+            redundant_pkgs,
+            blocks: 0,
+            repair_packets: 0,
+        })
     }
     pub fn process_packet_fec(&mut self, packet: &[u8]) -> Option<Vec<Vec<u8>>> {
         // wait for block buffer to fill
-        self.pkg_indices.push(self.block_buffer.len() as u16);
+        self.block_start.get_or_insert_with(Instant::now);
+        self.pkg_indices.push(self.block_buffer.len() as u32);
         self.block_buffer.extend_from_slice(packet);
         if self.block_buffer.len() < self.min_block_size as usize {
             return None;
         }
-        
-        // add udp package limiter info header (append it for performance)
-        let udp_pkgs_header: Vec<_> = self.pkg_indices
+
+        Some(self.encode_block())
+    }
+
+    // Adapts the number of repair packets to the loss reported by the receiver: lost blocks double it,
+    // otherwise it follows the worst block with some headroom, rising at once and falling one by one.
+    // Returns the new value if it changed.
+    pub fn adapt_redundancy(&mut self, feedback: &FecFeedback, min: u32, max: u32) -> Option<u32> {
+        let current = self.redundant_pkgs;
+        let target = if feedback.lost_blocks > 0 {
+            current.saturating_mul(2).max(1)
+        } else if feedback.decoded_blocks > 0 {
+            feedback.max_repair_used + feedback.max_repair_used / 2 + 1
+        } else {
+            return None;
+        };
+
+        let adapted = if target > current { target } else { current.saturating_sub(1).max(target) };
+        self.redundant_pkgs = adapted.clamp(min, max);
+        (self.redundant_pkgs != current).then_some(self.redundant_pkgs)
+    }
+
+    pub fn redundant_pkgs(&self) -> u32 {
+        self.redundant_pkgs
+    }
+
+    // Changes the block layout for the following blocks, the receiver reads it from the fec header
+    pub fn set_block_params(&mut self, min_block_size: u32, redundant_pkgs: u32) -> Result<(), String> {
+        if self.version == FEC_VERSION_LEGACY && min_block_size > u16::MAX as u32 {
+            return Err("The legacy fec header is limited to blocks of 64 KiB".to_string());
+        }
+        if min_block_size == 0 {
+            return Err("The block size must not be zero".to_string());
+        }
+        fec::check_block_size(min_block_size, self.wifi_packet_size)?;
+        self.min_block_size = min_block_size;
+        self.redundant_pkgs = redundant_pkgs;
+        Ok(())
+    }
+
+    // Time the oldest buffered packet arrived, None if the buffer is empty
+    pub fn block_start(&self) -> Option<Instant> {
+        self.block_start
+    }
+
+    // Encodes the buffered packets even though the block is not full yet
+    pub fn flush(&mut self) -> Option<Vec<Vec<u8>>> {
+        if self.pkg_indices.is_empty() {
+            return None;
+        }
+        Some(self.encode_block())
+    }
+
+    // udp package limiter info: the offsets of all packets and the end, followed by their count.
+    // legacy blocks use 16 bit offsets and an 8 bit count, otherwise all of them are 32 bit.
+    fn udp_pkgs_header(&self) -> Vec<u8> {
+        let block_end = self.block_buffer.len() as u32;
+        let count = self.pkg_indices.len() + 1;
+        let offsets = self.pkg_indices.iter().chain(once(&block_end));
+
+        if self.version == FEC_VERSION_LEGACY {
+            offsets
+                .flat_map(|&i| (i as u16).to_le_bytes())
+                .chain(once(count as u8))
+                .collect()
+        } else {
+            offsets
+                .flat_map(|&i| i.to_le_bytes())
+                .chain((count as u32).to_le_bytes())
+                .collect()
+        }
+    }
+
+    fn encode_block(&mut self) -> Vec<Vec<u8>> {
+        // offset and length of every udp packet, the end of one is the start of the next
+        let packet_bounds: Vec<(u32, u32)> = self.pkg_indices
             .iter()
-            .map(|i| i.to_le_bytes())
-            .chain(once((self.block_buffer.len() as u16).to_le_bytes()))
-            .flatten()
-            .chain(once(self.pkg_indices.len() as u8 + 1))
+            .zip(self.pkg_indices.iter().skip(1).chain(once(&(self.block_buffer.len() as u32))))
+            .map(|(&start, &end)| (start, end - start))
             .collect();
 
+        // add udp package limiter info header (append it for performance)
+        let udp_pkgs_header = self.udp_pkgs_header();
+
         // if block is full, return it
-        let block_size = self.block_buffer.len() as u16 + udp_pkgs_header.len() as u16;
+        let block_size = self.block_buffer.len() as u32 + udp_pkgs_header.len() as u32;
+        // the last packet may take the block beyond the checked minimum size
+        let too_large = if self.version == FEC_VERSION_LEGACY && block_size > u16::MAX as u32 {
+            Err(format!("Block of {} bytes exceeds the legacy fec header", block_size))
+        } else {
+            fec::check_block_size(block_size, self.wifi_packet_size)
+        };
+        if let Err(e) = too_large {
+            eprintln!("{}, dropped", e);
+            self.block_buffer.clear();
+            self.pkg_indices.clear();
+            self.block_start = None;
+            return vec![];
+        }
         let (config, padding) = fec::get_raptorq_oti(block_size, self.wifi_packet_size);
         self.block_buffer.extend(vec![0; padding as usize]);
         self.block_buffer.extend(udp_pkgs_header);
-        let encoder = SourceBlockEncoder::new(self.block_id, &config, &self.block_buffer);
+        // raptorq only carries the lowest byte of the block sequence
+        let encoder = SourceBlockEncoder::new(self.block_seq as u8, &config, &self.block_buffer);
 
         let block = {
-            let header = FecHeader::new(block_size, self.wifi_packet_size).to_bytes();
+            let header = FecHeader::new(self.version, self.block_seq, block_size, self.wifi_packet_size).to_bytes();
             let mut packets = vec![];
             packets.extend(encoder.source_packets());
             packets.extend(encoder.repair_packets(0, self.redundant_pkgs));
+            let symbol_size = config.symbol_size() as u32;
             packets
                 .iter()
-                .map(|e| [&header, &e.serialize()[..]].concat())
+                .map(|e| {
+                    if self.version == FEC_VERSION_LEGACY {
+                        return [&header[..], &e.serialize()[..]].concat();
+                    }
+                    // source symbols list the packets starting in them, as many as fit, repair symbols list none
+                    let symbol = e.serialize();
+                    let table_space = fec::MAX_FEC_PACKET_SIZE.saturating_sub(header.len() + symbol.len() + size_of::<u16>());
+                    let symbol_start = e.payload_id().encoding_symbol_id() * symbol_size;
+                    let packet_table = PacketTable {
+                        packets: packet_bounds
+                            .iter()
+                            .filter(|(start, _)| (symbol_start..symbol_start + symbol_size).contains(start))
+                            .take(table_space / (2 * size_of::<u32>()))
+                            .copied()
+                            .collect(),
+                    };
+                    [&header[..], &packet_table.to_bytes()[..], &symbol[..]].concat()
+                })
                 .collect()
         };
 
-        self.block_id = self.block_id.wrapping_add(1);
+        self.blocks += 1;
+        self.repair_packets += self.redundant_pkgs as u64;
+        self.block_seq = self.block_seq.wrapping_add(1);
         self.block_buffer.clear();
         self.pkg_indices.clear();
-        Some(block)
+        self.block_start = None;
+        block
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::fec::FEC_VERSION;
+
+    #[test]
+    fn packet_tables_fit_the_capture() {
+        let mut fec = TXFec::new(FEC_VERSION, 8000, 1400, 2).unwrap();
+        let block = (0..8000u32)
+            .find_map(|i| fec.process_packet_fec(&[i as u8]))
+            .unwrap();
+
+        assert!(block.iter().all(|packet| packet.len() <= fec::MAX_FEC_PACKET_SIZE));
+        // the first symbol holds 1400 packets, its table is cut
+        let counts: Vec<u16> = block.iter().map(|packet| u16::from_le_bytes([packet[15], packet[16]])).collect();
+        let max_count = ((fec::MAX_FEC_PACKET_SIZE - 15 - 2 - (4 + 1400)) / 8) as u16;
+        assert_eq!(counts[0], max_count);
+        assert!(counts.iter().all(|&count| count <= max_count));
+        // the repair symbols list no packets
+        assert_eq!(counts[counts.len() - 2..], [0, 0]);
+    }
+}
@@ -2,22 +2,66 @@ use std::ffi::CString;
 use std::mem::{size_of, zeroed};
 use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
 use std::fs;
+use std::sync::{Arc, RwLock};
 
 use crate::common::hw_headers;
+use crate::common::radio::RadioTx;
 
+// Headers of the frames of one stream, the radio and its radiotap header are shared by the streams of one card
 pub(super) struct TXHwInt {
-    wifi_socket: OwnedFd,
-    radiotap_header: Vec<u8>,
+    radio: Arc<dyn RadioTx>,
+    radiotap_header: Arc<RwLock<Vec<u8>>>,
     ieee_sequence: u16,
     channel_id: u32,
 }
 
+// Raw socket on a monitor mode card
+pub(super) struct RawSocket {
+    wifi_socket: OwnedFd,
+}
+
 impl TXHwInt {
-    pub fn new(wifi_device: String, radiotap_header: Vec<u8>, channel_id: u32) -> Result<Self, Box<dyn std::error::Error>> {
-        let wifi_socket = TXHwInt::open_raw_socket(wifi_device)?;
-        Ok(Self { wifi_socket, radiotap_header, ieee_sequence: 0, channel_id })
+    pub fn new(radio: Arc<dyn RadioTx>, radiotap_header: Vec<u8>, channel_id: u32) -> Self {
+        Self { radio, radiotap_header: Arc::new(RwLock::new(radiotap_header)), ieee_sequence: 0, channel_id }
+    }
+
+    // Another stream on the same radio, with its own 802.11 header
+    pub fn stream(&self, channel_id: u32) -> Self {
+        Self { radio: self.radio.clone(), radiotap_header: self.radiotap_header.clone(), ieee_sequence: 0, channel_id }
+    }
+
+    // Takes effect with the next frame of every stream on the radio
+    pub fn set_radiotap_header(&self, radiotap_header: Vec<u8>) {
+        *self.radiotap_header.write().unwrap() = radiotap_header;
+    }
+
+    // Returns the sent payload bytes, None if the driver queue was full (ENOBUFS) and the frame dropped
+    pub fn send_packet(
+        &mut self,
+        data: &[u8],
+    ) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+        // Create IEEE 802.11 and radiotap headers
+        let ieee_header = hw_headers::get_ieee80211_header(0x08, self.channel_id, self.ieee_sequence);
+        self.ieee_sequence = self.ieee_sequence.wrapping_add(16);
+
+        let radiotap_header = self.radiotap_header.read().unwrap();
+        let Some(sent) = self.radio.send_frame(&[&radiotap_header, &ieee_header, data])? else {
+            return Ok(None);
+        };
+
+        let header_len = radiotap_header.len() + ieee_header.len();
+
+        Ok(Some(sent.saturating_sub(header_len)))
     }
-    pub fn open_raw_socket(wifi_device: String) -> Result<OwnedFd, Box<dyn std::error::Error>> {
+}
+
+impl RawSocket {
+    pub fn open(wifi_device: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let wifi_socket = Self::open_raw_socket(wifi_device)?;
+        Ok(Self { wifi_socket })
+    }
+
+    pub fn open_raw_socket(wifi_device: &str) -> Result<OwnedFd, Box<dyn std::error::Error>> {
         let sockfd = unsafe { libc::socket(libc::PF_PACKET, libc::SOCK_RAW, 0) };
 
         if sockfd < 0 {
@@ -37,7 +81,7 @@ impl TXHwInt {
         }
 
         // Get interface index
-        let ifname = CString::new(wifi_device.as_str())?;
+        let ifname = CString::new(wifi_device)?;
         let ifindex = unsafe { libc::if_nametoindex(ifname.as_ptr()) };
 
         if ifindex == 0 {
@@ -85,29 +129,18 @@ impl TXHwInt {
 
         Ok(fd)
     }
-    pub fn send_packet(
-        &mut self,
-        data: &[u8],
-    ) -> Result<usize, Box<dyn std::error::Error>> {
-        // Create IEEE 802.11 and radiotap headers
-        let ieee_header = hw_headers::get_ieee80211_header(0x08, self.channel_id, self.ieee_sequence);
-        self.ieee_sequence = self.ieee_sequence.wrapping_add(16);
+}
 
+impl RadioTx for RawSocket {
+    fn send_frame(&self, parts: &[&[u8]]) -> Result<Option<usize>, Box<dyn std::error::Error>> {
         // Assemble payload from headers and data
-        let iovecs = [
-            libc::iovec {
-                iov_base: self.radiotap_header.as_ptr() as *mut libc::c_void,
-                iov_len: self.radiotap_header.len(),
-            },
-            libc::iovec {
-                iov_base: ieee_header.as_ptr() as *mut libc::c_void,
-                iov_len: ieee_header.len(),
-            },
-            libc::iovec {
-                iov_base: data.as_ptr() as *mut libc::c_void,
-                iov_len: data.len(),
-            },
-        ];
+        let iovecs: Vec<libc::iovec> = parts
+            .iter()
+            .map(|part| libc::iovec {
+                iov_base: part.as_ptr() as *mut libc::c_void,
+                iov_len: part.len(),
+            })
+            .collect();
 
         let msg: libc::msghdr = libc::msghdr {
             msg_name: std::ptr::null_mut(),
@@ -128,11 +161,9 @@ impl TXHwInt {
                 eprintln!("sendmsg failed: errno {}", errno);
                 return Err(format!("Failed to send packet: errno {}", errno).into());
             }
-            return Ok(0); // Treat ENOBUFS as non-fatal
+            return Ok(None); // Treat ENOBUFS as non-fatal
         }
 
-        let header_len = self.radiotap_header.len() + ieee_header.len();
-
-        Ok((sent as usize).saturating_sub(header_len))
+        Ok(Some(sent as usize))
     }
 }
@@ -0,0 +1,57 @@
+use std::time::Instant;
+
+// Spreads the packets of `depth` consecutive fec blocks across the air, so a burst of lost frames
+// takes a few packets of every block instead of a whole block
+pub(super) struct TXInterleaver {
+    depth: usize,
+    blocks: Vec<Vec<Vec<u8>>>,
+    first_block: Option<Instant>,
+}
+
+impl TXInterleaver {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            blocks: Vec::new(),
+            first_block: None,
+        }
+    }
+
+    // Returns the interleaved packets once `depth` blocks were collected
+    pub fn push_block(&mut self, block: Vec<Vec<u8>>) -> Option<Vec<Vec<u8>>> {
+        if block.is_empty() {
+            return None;
+        }
+        self.first_block.get_or_insert_with(Instant::now);
+        self.blocks.push(block);
+        if self.blocks.len() < self.depth {
+            return None;
+        }
+        Some(self.interleave())
+    }
+
+    // Time the oldest buffered block was encoded, None if there is none
+    pub fn block_start(&self) -> Option<Instant> {
+        self.first_block
+    }
+
+    // Interleaves the buffered blocks even though there are less than `depth`
+    pub fn flush(&mut self) -> Option<Vec<Vec<u8>>> {
+        if self.blocks.is_empty() {
+            return None;
+        }
+        Some(self.interleave())
+    }
+
+    // one packet of every block in turn, blocks that run out of packets are skipped
+    fn interleave(&mut self) -> Vec<Vec<u8>> {
+        let total = self.blocks.iter().map(Vec::len).sum();
+        let mut blocks: Vec<_> = self.blocks.drain(..).map(Vec::into_iter).collect();
+        let mut packets = Vec::with_capacity(total);
+        while packets.len() < total {
+            packets.extend(blocks.iter_mut().filter_map(Iterator::next));
+        }
+        self.first_block = None;
+        packets
+    }
+}
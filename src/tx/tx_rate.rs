@@ -0,0 +1,303 @@
+use std::time::{Duration, Instant};
+
+use crate::common::bandwidth::Bandwidth;
+use crate::common::feedback::{FecFeedback, FEEDBACK_INTERVAL};
+use crate::common::hw_headers;
+
+// Clean reports in a row before the next faster rate is tried
+const STEP_UP_REPORTS: u32 = 8;
+// A rate that had to be left is tried again once the signal is this much stronger than back then,
+const RSSI_HYSTERESIS: i8 = 3;
+// or after this many clean reports
+const RETRY_REPORTS: u32 = 80;
+// Sent blocks go without a report for this long before the link counts as broken, a link that
+// delivers nothing leaves the receiver nothing to report
+const REPORT_TIMEOUT: Duration = FEEDBACK_INTERVAL.saturating_mul(4);
+// Neighbouring rates that differ by less than this (in percent) only cost signal, the slower one is skipped
+const MIN_RATE_STEP: u32 = 10;
+
+// 20 MHz single stream data rates of the MCS indices 0 to 9 in 100 kbit/s, long guard interval
+const MCS_RATES: [u32; 10] = [65, 130, 195, 260, 390, 520, 585, 650, 780, 867];
+
+// Picks the MCS index and bandwidth from the link reports of the receiver: lost blocks, an exhausted
+// repair margin or missing reports step down at once, a clean link steps up after a while
+pub(super) struct RateController {
+    ladder: Vec<(Bandwidth, u8)>,   // from the slowest to the fastest rate
+    index: usize,
+    clean_reports: u32,
+    failed_rssi: Vec<Option<i8>>,   // signal when the rate was left, i8::MAX if unknown
+    reported_blocks: u64,           // blocks sent up to the last report about any of them
+    unreported_since: Option<Instant>,  // first check that found blocks sent after that
+}
+
+impl RateController {
+    // Rates with MCS indices between min_mcs and max_mcs, up to the given bandwidth
+    pub fn new(
+        min_mcs: u8,
+        max_mcs: u8,
+        max_bandwidth: Bandwidth,
+        stbc: u8,
+        vht_mode: bool,
+        vht_nss: u8,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut rates: Vec<(u32, Bandwidth, u8)> = [Bandwidth::Bw20, Bandwidth::Bw40, Bandwidth::Bw80, Bandwidth::Bw160]
+            .into_iter()
+            .filter(|bandwidth| bandwidth.mhz() <= max_bandwidth.mhz().max(20))
+            .flat_map(|bandwidth| (min_mcs..=max_mcs).map(move |mcs| (bandwidth, mcs)))
+            .filter(|&(bandwidth, mcs)| hw_headers::check_radio_settings(stbc, bandwidth, mcs, vht_mode, vht_nss).is_ok())
+            .map(|(bandwidth, mcs)| (Self::rate(bandwidth, mcs, vht_mode, vht_nss), bandwidth, mcs))
+            .collect();
+        rates.sort_by_key(|&(rate, bandwidth, _)| (rate, bandwidth.mhz()));
+
+        let mut ladder: Vec<(Bandwidth, u8)> = Vec::new();
+        let mut last_rate = 0;
+        for (rate, bandwidth, mcs) in rates {
+            if rate * 100 >= last_rate * (100 + MIN_RATE_STEP) {
+                ladder.push((bandwidth, mcs));
+                last_rate = rate;
+            }
+        }
+        if ladder.is_empty() {
+            return Err(format!("No valid rate between MCS {} and {}", min_mcs, max_mcs).into());
+        }
+
+        Ok(Self {
+            failed_rssi: vec![None; ladder.len()],
+            ladder,
+            index: 0,
+            clean_reports: 0,
+            reported_blocks: 0,
+            unreported_since: None,
+        })
+    }
+
+    fn rate(bandwidth: Bandwidth, mcs: u8, vht_mode: bool, vht_nss: u8) -> u32 {
+        // HT counts the spatial streams in the MCS index, 8 per stream
+        let (index, streams) = if vht_mode { (mcs, vht_nss) } else { (mcs % 8, mcs / 8 + 1) };
+        let percent = match bandwidth {
+            Bandwidth::Bw10 => 50,
+            Bandwidth::Bw20 => 100,
+            Bandwidth::Bw40 => 208,
+            Bandwidth::Bw80 => 450,
+            Bandwidth::Bw160 => 900,
+        };
+        MCS_RATES[index as usize] * streams as u32 * percent / 100
+    }
+
+    // Continues from the fastest rate that is not faster than the given one
+    pub fn sync(&mut self, bandwidth: Bandwidth, mcs: u8, vht_mode: bool, vht_nss: u8) -> (Bandwidth, u8) {
+        let rate = Self::rate(bandwidth, mcs, vht_mode, vht_nss);
+        self.index = self.ladder
+            .iter()
+            .rposition(|&(bandwidth, mcs)| Self::rate(bandwidth, mcs, vht_mode, vht_nss) <= rate)
+            .unwrap_or(0);
+        self.clean_reports = 0;
+        self.ladder[self.index]
+    }
+
+    // Returns the new bandwidth and MCS index if the rate changes. The repair margin is the share of
+    // the repair packets per block that the worst block left unused, sent_blocks counts the blocks so far.
+    pub fn update(&mut self, feedback: &FecFeedback, redundant_pkgs: u32, sent_blocks: u64) -> Option<(Bandwidth, u8)> {
+        if feedback.decoded_blocks == 0 && feedback.lost_blocks == 0 {
+            // nothing arrived, check_reports finds out whether something was sent
+            return None;
+        }
+        self.reported_blocks = sent_blocks;
+        self.unreported_since = None;
+
+        let exhausted = feedback.max_repair_used * 4 > redundant_pkgs * 3;
+        if feedback.lost_blocks > 0 || exhausted {
+            return self.step_down(feedback.rssi);
+        }
+
+        if feedback.max_repair_used * 2 > redundant_pkgs {
+            self.clean_reports = 0;
+            return None;
+        }
+        self.clean_reports += 1;
+
+        let next = self.index + 1;
+        if next == self.ladder.len() || self.clean_reports < STEP_UP_REPORTS {
+            return None;
+        }
+        let stronger = match (self.failed_rssi[next], feedback.rssi) {
+            (None, _) => true,
+            (Some(failed), Some(rssi)) => rssi >= failed.saturating_add(RSSI_HYSTERESIS),
+            (Some(_), None) => false,
+        };
+        if !stronger && self.clean_reports < RETRY_REPORTS {
+            return None;
+        }
+
+        self.clean_reports = 0;
+        self.index = next;
+        Some(self.ladder[next])
+    }
+
+    // Steps down if blocks were sent but no report about them arrived for a while, to be called
+    // regularly while the reports are expected. Returns the new bandwidth and MCS index like update.
+    pub fn check_reports(&mut self, sent_blocks: u64) -> Option<(Bandwidth, u8)> {
+        if sent_blocks == self.reported_blocks {
+            return None;
+        }
+        let unreported_since = *self.unreported_since.get_or_insert_with(Instant::now);
+        if unreported_since.elapsed() < REPORT_TIMEOUT {
+            return None;
+        }
+
+        // the next step down needs new blocks going unreported for as long
+        self.reported_blocks = sent_blocks;
+        self.unreported_since = None;
+        self.step_down(None)
+    }
+
+    // When check_reports wants to be called again, if it waits for a report
+    pub fn report_deadline(&self) -> Option<Instant> {
+        self.unreported_since.map(|since| since + REPORT_TIMEOUT)
+    }
+
+    fn step_down(&mut self, rssi: Option<i8>) -> Option<(Bandwidth, u8)> {
+        self.clean_reports = 0;
+        if self.index == 0 {
+            return None;
+        }
+        self.failed_rssi[self.index] = Some(rssi.unwrap_or(i8::MAX));
+        self.index -= 1;
+        Some(self.ladder[self.index])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REDUNDANT_PKGS: u32 = 8;
+
+    // HT rates from MCS 0 to 7, up to 40 MHz
+    fn controller() -> RateController {
+        RateController::new(0, 7, Bandwidth::Bw40, 1, false, 1).unwrap()
+    }
+
+    fn report(lost_blocks: u32, max_repair_used: u32, rssi: Option<i8>) -> FecFeedback {
+        FecFeedback { channel_id: 0, decoded_blocks: 10, lost_blocks, max_repair_used, rssi }
+    }
+
+    fn clean(rssi: i8) -> FecFeedback {
+        report(0, 0, Some(rssi))
+    }
+
+    // Sends clean reports until the rate changes, returns their number and the new rate
+    fn step_up(rate_control: &mut RateController, rssi: i8) -> (u32, Option<(Bandwidth, u8)>) {
+        for reports in 1..=RETRY_REPORTS + 1 {
+            if let Some(rate) = rate_control.update(&clean(rssi), REDUNDANT_PKGS, 0) {
+                return (reports, Some(rate));
+            }
+        }
+        (RETRY_REPORTS + 1, None)
+    }
+
+    #[test]
+    fn ladder_skips_close_rates() {
+        // 40 MHz MCS 0 to 3 are within 10 % of a 20 MHz rate
+        let expected: Vec<(Bandwidth, u8)> = (0..8)
+            .map(|mcs| (Bandwidth::Bw20, mcs))
+            .chain((4..8).map(|mcs| (Bandwidth::Bw40, mcs)))
+            .collect();
+        assert_eq!(controller().ladder, expected);
+
+        let rate_control = RateController::new(0, 7, Bandwidth::Bw20, 1, false, 1).unwrap();
+        assert_eq!(rate_control.ladder.len(), 8);
+        assert!(RateController::new(32, 40, Bandwidth::Bw20, 1, false, 1).is_err());
+    }
+
+    #[test]
+    fn sync_picks_the_fastest_rate_not_above() {
+        let mut rate_control = controller();
+        assert_eq!(rate_control.sync(Bandwidth::Bw40, 2, false, 1), (Bandwidth::Bw20, 4));
+        assert_eq!(rate_control.sync(Bandwidth::Bw40, 7, false, 1), (Bandwidth::Bw40, 7));
+        assert_eq!(rate_control.sync(Bandwidth::Bw20, 0, false, 1), (Bandwidth::Bw20, 0));
+    }
+
+    #[test]
+    fn steps_down_on_loss_or_exhausted_repair() {
+        let mut rate_control = controller();
+        rate_control.sync(Bandwidth::Bw20, 3, false, 1);
+
+        assert_eq!(rate_control.update(&report(1, 0, Some(-60)), REDUNDANT_PKGS, 1), Some((Bandwidth::Bw20, 2)));
+        // more than 3/4 of the repair packets used
+        assert_eq!(rate_control.update(&report(0, 6, Some(-60)), REDUNDANT_PKGS, 2), None);
+        assert_eq!(rate_control.update(&report(0, 7, Some(-60)), REDUNDANT_PKGS, 3), Some((Bandwidth::Bw20, 1)));
+        assert_eq!(rate_control.update(&report(2, 8, Some(-60)), REDUNDANT_PKGS, 4), Some((Bandwidth::Bw20, 0)));
+        // there is no slower rate
+        assert_eq!(rate_control.update(&report(1, 0, Some(-60)), REDUNDANT_PKGS, 5), None);
+        // reports without blocks say nothing about the rate
+        let empty = FecFeedback { decoded_blocks: 0, ..report(0, 0, None) };
+        assert_eq!(rate_control.update(&empty, REDUNDANT_PKGS, 6), None);
+    }
+
+    #[test]
+    fn steps_up_after_clean_reports() {
+        let mut rate_control = controller();
+        assert_eq!(step_up(&mut rate_control, -60), (STEP_UP_REPORTS, Some((Bandwidth::Bw20, 1))));
+        assert_eq!(step_up(&mut rate_control, -60), (STEP_UP_REPORTS, Some((Bandwidth::Bw20, 2))));
+
+        // a block that used more than half of the repair packets starts the count again
+        for _ in 0..STEP_UP_REPORTS - 1 {
+            assert_eq!(rate_control.update(&clean(-60), REDUNDANT_PKGS, 0), None);
+        }
+        assert_eq!(rate_control.update(&report(0, 5, Some(-60)), REDUNDANT_PKGS, 0), None);
+        assert_eq!(step_up(&mut rate_control, -60), (STEP_UP_REPORTS, Some((Bandwidth::Bw20, 3))));
+
+        // the fastest rate stays
+        rate_control.sync(Bandwidth::Bw40, 7, false, 1);
+        assert_eq!(step_up(&mut rate_control, -60), (RETRY_REPORTS + 1, None));
+    }
+
+    #[test]
+    fn retries_a_failed_rate_with_a_stronger_signal() {
+        let mut rate_control = controller();
+        rate_control.sync(Bandwidth::Bw20, 2, false, 1);
+        assert_eq!(rate_control.update(&report(1, 0, Some(-60)), REDUNDANT_PKGS, 0), Some((Bandwidth::Bw20, 1)));
+
+        // MCS 2 failed at -60 dBm, it is tried again at -57 dBm
+        for _ in 0..STEP_UP_REPORTS {
+            assert_eq!(rate_control.update(&clean(-58), REDUNDANT_PKGS, 0), None);
+        }
+        assert_eq!(rate_control.update(&clean(-57), REDUNDANT_PKGS, 0), Some((Bandwidth::Bw20, 2)));
+
+        // or after enough clean reports without a stronger signal
+        assert_eq!(rate_control.update(&report(1, 0, Some(-60)), REDUNDANT_PKGS, 0), Some((Bandwidth::Bw20, 1)));
+        assert_eq!(step_up(&mut rate_control, -60), (RETRY_REPORTS, Some((Bandwidth::Bw20, 2))));
+
+        // a rate that failed with an unknown signal needs the clean reports
+        assert_eq!(rate_control.update(&report(1, 0, None), REDUNDANT_PKGS, 0), Some((Bandwidth::Bw20, 1)));
+        assert_eq!(step_up(&mut rate_control, 20), (RETRY_REPORTS, Some((Bandwidth::Bw20, 2))));
+    }
+
+    #[test]
+    fn steps_down_when_sent_blocks_go_unreported() {
+        let mut rate_control = controller();
+        rate_control.sync(Bandwidth::Bw20, 3, false, 1);
+
+        // nothing sent, nothing to report
+        assert_eq!(rate_control.check_reports(0), None);
+        assert_eq!(rate_control.report_deadline(), None);
+
+        assert_eq!(rate_control.check_reports(5), None);
+        let deadline = rate_control.report_deadline().unwrap();
+        assert!(deadline > Instant::now() && deadline <= Instant::now() + REPORT_TIMEOUT);
+
+        // a report about the blocks ends the wait
+        assert_eq!(rate_control.update(&clean(-60), REDUNDANT_PKGS, 5), None);
+        assert_eq!(rate_control.report_deadline(), None);
+
+        assert_eq!(rate_control.check_reports(8), None);
+        rate_control.unreported_since = Some(Instant::now() - REPORT_TIMEOUT);
+        assert_eq!(rate_control.check_reports(8), Some((Bandwidth::Bw20, 2)));
+        assert_eq!(rate_control.report_deadline(), None);
+        // the next step down waits for new blocks and another timeout
+        assert_eq!(rate_control.check_reports(8), None);
+        assert_eq!(rate_control.check_reports(9), None);
+        assert!(rate_control.report_deadline().is_some());
+    }
+}
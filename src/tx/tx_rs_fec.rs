@@ -0,0 +1,150 @@
+use std::time::Instant;
+
+use crate::common::fec::reed_solomon::ReedSolomon;
+use crate::common::wfb_ng::{self, PacketHeader};
+
+// Reed-Solomon fec in the wfb-ng layout: every udp packet is one data fragment, sent right away,
+// the n-k parity fragments follow once k fragments were collected
+pub(super) struct TXRsFec {
+    rs: ReedSolomon,
+    block_idx: u64,
+    fragments: Vec<Vec<u8>>,
+    max_fragment_size: usize,
+    block_start: Option<Instant>,
+    pub blocks: u64,
+    pub repair_packets: u64,
+}
+
+impl TXRsFec {
+    pub fn new(k: u8, n: u8) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            rs: ReedSolomon::new(k, n)?,
+            block_idx: 0,
+            fragments: Vec::new(),
+            max_fragment_size: 0,
+            block_start: None,
+            blocks: 0,
+            repair_packets: 0,
+        })
+    }
+
+    // Returns the fragments to send together with their data nonce
+    pub fn process_packet_fec(&mut self, packet: &[u8]) -> Vec<(u64, Vec<u8>)> {
+        if packet.len() > wfb_ng::MAX_PAYLOAD_SIZE {
+            eprintln!("Packet of {} bytes exceeds the wfb-ng payload size, dropped", packet.len());
+            return vec![];
+        }
+
+        self.block_start.get_or_insert_with(Instant::now);
+        let mut out = vec![self.add_fragment(PacketHeader::new(0, packet.len() as u16), packet)];
+        if self.fragments.len() < self.rs.k() {
+            return out;
+        }
+
+        out.extend(self.encode_block());
+        out
+    }
+
+    // Parity fragments per block
+    pub fn redundant_pkgs(&self) -> u32 {
+        (self.rs.n() - self.rs.k()) as u32
+    }
+
+    // Time the first fragment of the current block was sent, None if no block is open
+    pub fn block_start(&self) -> Option<Instant> {
+        self.block_start
+    }
+
+    // Closes the current block like wfb-ng does on its fec timeout: the missing data fragments are
+    // sent as empty fec only packets, so the parity can go out
+    pub fn flush(&mut self) -> Vec<(u64, Vec<u8>)> {
+        if self.fragments.is_empty() {
+            return vec![];
+        }
+
+        let mut out = Vec::new();
+        while self.fragments.len() < self.rs.k() {
+            out.push(self.add_fragment(PacketHeader::new(wfb_ng::PACKET_FLAG_FEC_ONLY, 0), &[]));
+        }
+        out.extend(self.encode_block());
+        out
+    }
+
+    fn add_fragment(&mut self, packet_header: PacketHeader, packet: &[u8]) -> (u64, Vec<u8>) {
+        let fragment = [&packet_header.to_bytes()[..], packet].concat();
+        let data_nonce = wfb_ng::data_nonce(self.block_idx, self.fragments.len() as u8);
+        self.max_fragment_size = self.max_fragment_size.max(fragment.len());
+        self.fragments.push(fragment.clone());
+        (data_nonce, fragment)
+    }
+
+    // Returns the parity fragments of the full block and starts the next one
+    fn encode_block(&mut self) -> Vec<(u64, Vec<u8>)> {
+        let mut out = Vec::new();
+        let data: Vec<&[u8]> = self.fragments.iter().map(|f| &f[..]).collect();
+        let parity = self.rs.encode(&data, self.max_fragment_size);
+        for (i, fragment) in parity.into_iter().enumerate() {
+            let fragment_idx = (self.rs.k() + i) as u8;
+            out.push((wfb_ng::data_nonce(self.block_idx, fragment_idx), fragment));
+        }
+
+        self.blocks += 1;
+        self.repair_packets += out.len() as u64;
+        self.block_idx = (self.block_idx + 1) & wfb_ng::MAX_BLOCK_IDX;
+        self.fragments.clear();
+        self.max_fragment_size = 0;
+        self.block_start = None;
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::from_hex;
+
+    // The fragments of wfb-ng for these packets: wpacket_hdr_t and packet, then the zfec parity
+    #[test]
+    fn fragments_match_wfb_ng() {
+        let mut fec = TXRsFec::new(8, 12).unwrap();
+        let packets: Vec<Vec<u8>> = (0..8u8).map(|i| (0..3 + 2 * i).map(|j| i * 7 + j).collect()).collect();
+
+        let mut fragments = Vec::new();
+        for packet in &packets[..7] {
+            fragments.extend(fec.process_packet_fec(packet));
+        }
+        assert_eq!(fragments.len(), 7);
+        fragments.extend(fec.process_packet_fec(&packets[7]));
+
+        let expected = [
+            "000003000102",
+            "0000050708090a0b",
+            "0000070e0f1011121314",
+            "00000915161718191a1b1c1d",
+            "00000b1c1d1e1f20212223242526",
+            "00000d232425262728292a2b2c2d2e2f",
+            "00000f2a2b2c2d2e2f303132333435363738",
+            "0000113132333435363738393a3b3c3d3e3f4041",
+            "0000e11461c7c7c81d7a07fb00003860a155718f",
+            "00004b6b28baeadea45bcae0d863dbc485c3e9ff",
+            "0000de57e887b4bc35dd536f805ea27350d554ca",
+            "0000ddfa8596da4f5723e2e73bb8368d658dbe6c",
+        ];
+        let expected: Vec<(u64, Vec<u8>)> = expected.iter().enumerate().map(|(i, f)| (i as u64, from_hex(f))).collect();
+        assert_eq!(fragments, expected);
+
+        // the next block continues with the block index in the upper bits of the nonce
+        assert_eq!(fec.process_packet_fec(b"x")[0].0, 1 << 8);
+    }
+
+    #[test]
+    fn flush_fills_the_block_with_fec_only_fragments() {
+        let mut fec = TXRsFec::new(2, 3).unwrap();
+        fec.process_packet_fec(b"abc");
+        let fragments = fec.flush();
+        assert_eq!(fragments.len(), 2);
+        assert_eq!(fragments[0], (1, vec![wfb_ng::PACKET_FLAG_FEC_ONLY, 0, 0]));
+        assert_eq!(fragments[1].0, 2);
+        assert!(fec.flush().is_empty());
+    }
+}
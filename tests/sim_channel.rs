@@ -0,0 +1,387 @@
+// End to end tests of transmitter and receiver over the simulated channel, they need neither root nor
+// wifi cards. The receiver is only built with the receiver feature: cargo test --features=receiver
+#![cfg(feature = "receiver")]
+
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use wfb_rs::common::fec::FecMode;
+use wfb_rs::common::keypair;
+use wfb_rs::common::sim_channel::{ChannelConditions, SimChannel};
+use wfb_rs::{Receiver, RxConfig, RxDevice, RxStats, Transmitter, TxConfig, TxDevice};
+
+const LINK_ID: u32 = 7;
+const RADIO_PORT: u8 = 0;
+// How long the receiver may stay silent before a test gives up on the missing packets
+const QUIET_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Settings both ends have to agree on
+#[derive(Clone)]
+struct Link {
+    link_id: u32,
+    fec_mode: FecMode,
+    fec_disabled: bool,
+    interleave_depth: usize,
+    forward_partial: bool,  // receiver only
+    keys: Option<Arc<KeyFiles>>,
+}
+
+// Drone and ground station key file, removed once no end needs them anymore
+struct KeyFiles {
+    drone: String,
+    ground_station: String,
+}
+
+impl Drop for KeyFiles {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.drone);
+        let _ = std::fs::remove_file(&self.ground_station);
+    }
+}
+
+impl Default for Link {
+    fn default() -> Self {
+        Self { link_id: LINK_ID, fec_mode: FecMode::Raptorq, fec_disabled: false, interleave_depth: 1, forward_partial: false, keys: None }
+    }
+}
+
+impl Link {
+    fn encrypted(mut self, name: &str) -> Self {
+        let (drone, ground_station) = keypair::generate_keypairs(1).unwrap();
+        let dir = std::env::temp_dir();
+        let path = |role| {
+            let file = format!("wfb_rs_test_{}_{}_{}.key", name, std::process::id(), role);
+            dir.join(file).to_string_lossy().into_owned()
+        };
+        let keys = KeyFiles { drone: path("drone"), ground_station: path("gs") };
+        drone.save(&keys.drone, false).unwrap();
+        ground_station.save(&keys.ground_station, false).unwrap();
+        self.keys = Some(Arc::new(keys));
+        self
+    }
+
+    fn transmitter(&self, channel: &SimChannel) -> Transmitter {
+        Transmitter::new(TxDevice::Radio(Arc::new(channel.transmitter())), TxConfig {
+            radio_port: RADIO_PORT,
+            link_id: self.link_id,
+            short_gi: false,
+            stbc: 0,
+            ldpc: false,
+            fec_disabled: self.fec_disabled,
+            fec_mode: self.fec_mode,
+            min_block_size: 8000,
+            wifi_packet_size: 1000,
+            redundant_pkgs: 6,
+            interleave_depth: self.interleave_depth,
+            max_block_age: Some(Duration::ZERO),   // flush_expired_block sends the last block at once
+            key_file: self.keys.as_ref().map(|keys| keys.drone.clone()),
+            epoch: 1,
+            session_interval: Duration::from_secs(10),
+            ..Default::default()
+        }).unwrap()
+    }
+
+    fn receiver(&self, cards: &[&SimChannel]) -> Receiver {
+        let devices = cards.iter().map(|channel| RxDevice::Radio(Box::new(channel.receiver()))).collect();
+        Receiver::new(devices, RxConfig {
+            radio_port: RADIO_PORT,
+            link_id: self.link_id,
+            fec_mode: self.fec_mode,
+            forward_partial: self.forward_partial,
+            interleave_depth: self.interleave_depth,
+            key_file: self.keys.as_ref().map(|keys| keys.ground_station.clone()),
+            ..Default::default()
+        }).unwrap()
+    }
+}
+
+// Packets of different sizes that tell their number
+fn test_packets(count: u32) -> Vec<Vec<u8>> {
+    (0..count)
+        .map(|i| {
+            let mut packet = i.to_le_bytes().to_vec();
+            packet.resize(100 + (i as usize * 337) % 1300, i as u8);
+            packet
+        })
+        .collect()
+}
+
+// Sends the packets and the last, incomplete fec block
+fn send_all(tx: &mut Transmitter, packets: &[Vec<u8>]) {
+    for packet in packets {
+        tx.send(packet);
+    }
+    tx.flush_expired_block();
+}
+
+// Forwards the decoded packets of the receiver from its own thread
+fn spawn_receiver(mut rx: Receiver) -> mpsc::Receiver<Vec<u8>> {
+    let (packets_s, packets) = mpsc::channel();
+    thread::spawn(move || {
+        while let Ok(decoded) = rx.recv() {
+            for packet in decoded {
+                if packets_s.send(packet).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    packets
+}
+
+// Waits for the expected number of packets, or until the receiver went quiet
+fn collect(packets: &mpsc::Receiver<Vec<u8>>, expected: usize) -> Vec<Vec<u8>> {
+    let mut received = Vec::new();
+    while received.len() < expected {
+        match packets.recv_timeout(QUIET_TIMEOUT) {
+            Ok(packet) => received.push(packet),
+            Err(_) => break,
+        }
+    }
+    received
+}
+
+// Waits until the capture threads handed every frame to the decoder, the statistics trail the packets
+fn settled_stats(rx: &wfb_rs::common::stats::StatsHandle<RxStats>, frames: u64) -> RxStats {
+    let deadline = Instant::now() + QUIET_TIMEOUT;
+    loop {
+        let stats = rx.get();
+        if stats.frames_in >= frames || Instant::now() > deadline {
+            return stats;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn perfect_channel_without_fec() {
+    let channel = SimChannel::new(ChannelConditions::default(), 1);
+    let link = Link { fec_disabled: true, ..Default::default() };
+    let packets = spawn_receiver(link.receiver(&[&channel]));
+    let mut tx = link.transmitter(&channel);
+
+    let sent = test_packets(200);
+    send_all(&mut tx, &sent);
+
+    assert_eq!(collect(&packets, sent.len()), sent);
+    assert_eq!(channel.stats().delivered, 200);
+}
+
+#[test]
+fn raptorq_recovers_random_loss() {
+    let conditions = ChannelConditions { loss: 0.1, ..Default::default() };
+    let channel = SimChannel::new(conditions, 2);
+    let link = Link::default();
+    let rx = link.receiver(&[&channel]);
+    let stats = rx.stats();
+    let packets = spawn_receiver(rx);
+    let mut tx = link.transmitter(&channel);
+
+    let sent = test_packets(300);
+    send_all(&mut tx, &sent);
+
+    assert_eq!(collect(&packets, sent.len()), sent);
+    assert!(channel.stats().lost > 0);
+    let stats = settled_stats(&stats, channel.stats().delivered);
+    assert!(stats.fec.recovered > 0);
+    assert_eq!(stats.fec.lost, 0);
+}
+
+#[test]
+fn loss_without_fec_loses_packets() {
+    let conditions = ChannelConditions { loss: 0.2, ..Default::default() };
+    let channel = SimChannel::new(conditions, 3);
+    let link = Link { fec_disabled: true, ..Default::default() };
+    let packets = spawn_receiver(link.receiver(&[&channel]));
+    let mut tx = link.transmitter(&channel);
+
+    let sent = test_packets(200);
+    send_all(&mut tx, &sent);
+
+    let received = collect(&packets, sent.len());
+    assert_eq!(received.len() as u64, channel.stats().delivered);
+    assert!(received.len() < sent.len());
+    // what arrives arrives intact and in order
+    let mut remaining = sent.iter();
+    assert!(received.iter().all(|packet| remaining.any(|sent| sent == packet)));
+}
+
+#[test]
+fn interleaving_spreads_burst_loss() {
+    // the same bursts hit both links, the one without interleaving loses whole blocks
+    let send_through_bursts = |interleave_depth| {
+        let conditions = ChannelConditions { burst_loss: 0.01, burst_length: 8, ..Default::default() };
+        let channel = SimChannel::new(conditions, 4);
+        let link = Link { interleave_depth, ..Default::default() };
+        let packets = spawn_receiver(link.receiver(&[&channel]));
+        let mut tx = link.transmitter(&channel);
+
+        let sent = test_packets(400);
+        send_all(&mut tx, &sent);
+        assert!(channel.stats().lost >= 8);
+        (collect(&packets, sent.len()), sent)
+    };
+
+    let (received, sent) = send_through_bursts(1);
+    assert!(received.len() < sent.len());
+    let (received, sent) = send_through_bursts(4);
+    assert_eq!(received, sent);
+}
+
+#[test]
+fn partial_blocks_are_forwarded_without_any_decoded_block() {
+    // too few frames for any block, only the packets that arrived whole get through
+    let conditions = ChannelConditions { loss: 0.8, ..Default::default() };
+    let channel = SimChannel::new(conditions, 9);
+    let link = Link { forward_partial: true, ..Default::default() };
+    let rx = link.receiver(&[&channel]);
+    let stats = rx.stats();
+    let packets = spawn_receiver(rx);
+    let mut tx = link.transmitter(&channel);
+
+    let sent = test_packets(1500);
+    send_all(&mut tx, &sent);
+
+    let received = collect(&packets, sent.len());
+    assert!(!received.is_empty());
+    let mut remaining = sent.iter();
+    assert!(received.iter().all(|packet| remaining.any(|sent| sent == packet)));
+    let stats = settled_stats(&stats, channel.stats().delivered);
+    assert_eq!(stats.fec.completed, 0);
+    assert!(stats.fec.lost > 0);
+}
+
+#[test]
+fn raptorq_survives_reordering() {
+    let conditions = ChannelConditions { reorder: 0.1, reorder_distance: 3, ..Default::default() };
+    let channel = SimChannel::new(conditions, 5);
+    let link = Link::default();
+    let packets = spawn_receiver(link.receiver(&[&channel]));
+    let mut tx = link.transmitter(&channel);
+
+    let sent = test_packets(300);
+    send_all(&mut tx, &sent);
+    // held back frames only go out once later frames overtook them
+    tx.send(&[0; 10]);
+    tx.flush_expired_block();
+
+    assert_eq!(collect(&packets, sent.len()), sent);
+    assert!(channel.stats().reordered > 0);
+}
+
+#[test]
+fn encryption_rejects_corrupted_and_duplicated_frames() {
+    let channel = SimChannel::new(ChannelConditions::default(), 6);
+    let link = Link::default().encrypted("corruption");
+    let rx = link.receiver(&[&channel]);
+    let stats = rx.stats();
+    let packets = spawn_receiver(rx);
+    let mut tx = link.transmitter(&channel);
+
+    // the session key gets through before the channel turns bad
+    tx.announce_session().unwrap();
+    channel.set_conditions(ChannelConditions { corrupt: 0.05, duplicate: 0.05, ..Default::default() });
+
+    let sent = test_packets(300);
+    send_all(&mut tx, &sent);
+
+    assert_eq!(collect(&packets, sent.len()), sent);
+    let channel_stats = channel.stats();
+    assert!(channel_stats.corrupted > 0 && channel_stats.duplicated > 0);
+    let stats = settled_stats(&stats, channel_stats.delivered);
+    assert!(stats.rejected.unauthenticated >= channel_stats.corrupted);
+    assert!(stats.rejected.replayed > 0);
+}
+
+#[test]
+fn reed_solomon_recovers_loss_and_reordering() {
+    let channel = SimChannel::new(ChannelConditions::default(), 7);
+    let link = Link { fec_mode: FecMode::Rs, ..Default::default() }.encrypted("rs");
+    let packets = spawn_receiver(link.receiver(&[&channel]));
+    let mut tx = link.transmitter(&channel);
+
+    tx.announce_session().unwrap();
+    channel.set_conditions(ChannelConditions { loss: 0.05, reorder: 0.05, ..Default::default() });
+
+    let sent = test_packets(200);
+    send_all(&mut tx, &sent);
+
+    let mut received = collect(&packets, sent.len());
+    // reordered fragments of a block that was already forwarded come late, the order within the
+    // blocks doesn't matter here
+    received.sort_by_key(|packet| u32::from_le_bytes(packet[..4].try_into().unwrap()));
+    assert_eq!(received, sent);
+}
+
+#[test]
+fn diversity_combines_cards() {
+    let conditions = ChannelConditions { loss: 0.3, ..Default::default() };
+    let channel = SimChannel::new(conditions, 8);
+    let link = Link { fec_disabled: true, ..Default::default() };
+    let rx = link.receiver(&[&channel, &channel]);
+    let stats = rx.stats();
+    let packets = spawn_receiver(rx);
+    let mut tx = link.transmitter(&channel);
+
+    let sent = test_packets(300);
+    send_all(&mut tx, &sent);
+
+    // a packet is only lost if both cards lost it, and no packet is forwarded twice. The cards
+    // are read by their own threads, so without fec the order may change.
+    let mut received = collect(&packets, sent.len());
+    assert!(received.len() > 250 && received.len() < sent.len());
+    received.sort_by_key(|packet| u32::from_le_bytes(packet[..4].try_into().unwrap()));
+    let forwarded = received.len();
+    received.dedup();
+    assert_eq!(received.len(), forwarded);
+    let mut remaining = sent.iter();
+    assert!(received.iter().all(|packet| remaining.any(|sent| sent == packet)));
+
+    let stats = settled_stats(&stats, channel.stats().delivered);
+    assert!(stats.duplicates > 0);
+    assert!(stats.cards.iter().all(|card| card.packets > 150 && card.unique > 0));
+    assert!(stats.cards[0].antennas.values().all(|antenna| antenna.rssi_last == -50));
+}
+
+#[test]
+fn receiver_ignores_other_links() {
+    let channel = SimChannel::new(ChannelConditions::default(), 9);
+    let link = Link { fec_disabled: true, ..Default::default() };
+    let other_link = Link { link_id: LINK_ID + 1, ..link.clone() };
+    let packets = spawn_receiver(link.receiver(&[&channel]));
+    let mut tx = link.transmitter(&channel);
+    let mut other_tx = other_link.transmitter(&channel);
+
+    let sent = test_packets(50);
+    for packet in &sent {
+        other_tx.send(&[0xff; 100]);
+        tx.send(packet);
+    }
+
+    assert_eq!(collect(&packets, sent.len() + 1), sent);
+}
+
+#[test]
+fn streams_share_the_radio() {
+    let channel = SimChannel::new(ChannelConditions::default(), 10);
+    let link = Link { fec_disabled: true, ..Default::default() };
+    let rx = link.receiver(&[&channel]);
+    let telemetry_rx = rx.add_stream(1, FecMode::Raptorq).unwrap();
+    let packets = spawn_receiver(rx);
+    let telemetry = spawn_receiver(telemetry_rx);
+    let mut tx = link.transmitter(&channel);
+    let mut telemetry_tx = tx.add_stream(1, wfb_rs::common::session::SessionFec::Disabled).unwrap();
+
+    let sent = test_packets(100);
+    for (i, packet) in sent.iter().enumerate() {
+        tx.send(packet);
+        if i % 10 == 0 {
+            telemetry_tx.send(&[i as u8; 20]);
+        }
+    }
+
+    assert_eq!(collect(&packets, sent.len()), sent);
+    let telemetry = collect(&telemetry, 10);
+    assert_eq!(telemetry, (0..10).map(|i| vec![i * 10; 20]).collect::<Vec<_>>());
+}